@@ -0,0 +1,929 @@
+use crate::files::error_response;
+use crate::http::*;
+use crate::proxy::Proxy;
+use crate::server::ServerError;
+use crate::upstream::hash;
+use chrono::{DateTime, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use toml::Value;
+
+/// Statuses that can be stored without explicit freshness, and given a
+/// heuristic lifetime from `Last-Modified` (RFC 9110 section 15.1).
+const HEURISTIC: &[usize] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+/// The longest heuristic lifetime, in seconds.
+const MAX_HEURISTIC: i64 = 24 * 60 * 60;
+/// Response headers that describe one message rather than the stored response.
+const UNSTORED: &[&str] = &[
+    "Connection",
+    "Content-Length",
+    "Transfer-Encoding",
+    "X-Cache",
+];
+
+/// Where and how much to cache, from the `[cache]` table. Proxies with
+/// `cache = true` use it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CacheConfig {
+    pub directory: PathBuf,
+    /// Bytes on disk, after which the least recently used responses are removed.
+    pub max_size: u64,
+    /// Larger responses are passed on without being stored.
+    pub max_entry_size: u64,
+    /// Client addresses that may send `PURGE` requests.
+    pub purge_from: Vec<IpAddr>,
+}
+impl CacheConfig {
+    pub fn from_toml(cfg: &Value) -> Result<CacheConfig, ServerError> {
+        let mut config = CacheConfig {
+            directory: PathBuf::from("./cache/"),
+            max_size: 1024 * 1024 * 1024,
+            max_entry_size: 16 * 1024 * 1024,
+            purge_from: vec![
+                IpAddr::from([127, 0, 0, 1]),
+                IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+            ],
+        };
+        if let Some(Value::String(directory)) = cfg.get("directory") {
+            config.directory = PathBuf::from(directory);
+        }
+        if let Some(Value::Integer(max_size)) = cfg.get("max_size") {
+            config.max_size = *max_size as u64;
+        }
+        if let Some(Value::Integer(max_entry_size)) = cfg.get("max_entry_size") {
+            config.max_entry_size = *max_entry_size as u64;
+        }
+        if let Some(Value::Array(purge_from)) = cfg.get("purge_from") {
+            config.purge_from = purge_from
+                .iter()
+                .map(|address| match address.as_str().map(str::parse) {
+                    Some(Ok(address)) => Ok(address),
+                    _ => Err(ServerError::ConfigError),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(config)
+    }
+}
+
+/// `Cache-Control` directives, by lower-case name, with their arguments.
+struct Directives(HashMap<String, Option<String>>);
+impl Directives {
+    fn new(value: Option<&str>) -> Directives {
+        Directives(
+            value
+                .unwrap_or("")
+                .split(',')
+                .filter(|directive| !directive.trim().is_empty())
+                .map(|directive| match directive.split_once('=') {
+                    Some((name, argument)) => (
+                        name.trim().to_ascii_lowercase(),
+                        Some(argument.trim().trim_matches('"').to_owned()),
+                    ),
+                    None => (directive.trim().to_ascii_lowercase(), None),
+                })
+                .collect(),
+        )
+    }
+    fn has(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+    fn seconds(&self, name: &str) -> Option<i64> {
+        self.0.get(name)?.as_ref()?.parse().ok()
+    }
+}
+
+fn http_date(value: Option<&str>) -> Option<i64> {
+    DateTime::parse_from_rfc2822(value?.trim())
+        .ok()
+        .map(|date| date.timestamp())
+}
+
+/// A stored response, kept as a line of JSON before its body.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// The request host, path and query.
+    key: String,
+    /// The request headers named by `Vary`, and their values.
+    vary: Vec<(String, String)>,
+    status: HttpStatus,
+    headers: HashMap<String, String>,
+    /// When it was received, in seconds since the epoch.
+    stored: i64,
+    /// How old it already was then.
+    age: i64,
+}
+impl Entry {
+    fn new(request: &HttpRequest, response: &HttpResponse, now: i64) -> Entry {
+        let mut entry = Entry {
+            key: key(request),
+            vary: vec![],
+            status: response.status.clone(),
+            headers: HashMap::new(),
+            stored: now,
+            age: 0,
+        };
+        entry.update(response, now);
+        entry.vary = vary_names(response.header("Vary"))
+            .into_iter()
+            .map(|name| {
+                let value = request.header(&name).unwrap_or("").trim().to_owned();
+                (name, value)
+            })
+            .collect();
+        entry
+    }
+    /// Takes the headers of a newer response for the same thing, like a 304.
+    fn update(&mut self, response: &HttpResponse, now: i64) {
+        for (name, value) in &response.headers {
+            if !UNSTORED
+                .iter()
+                .any(|unstored| unstored.eq_ignore_ascii_case(name))
+            {
+                self.headers
+                    .retain(|header, _| !header.eq_ignore_ascii_case(name));
+                self.headers.insert(name.clone(), value.clone());
+            }
+        }
+        let apparent = http_date(response.header("Date")).map_or(0, |date| now - date);
+        let age = response
+            .header("Age")
+            .and_then(|age| age.trim().parse().ok())
+            .unwrap_or(0);
+        self.stored = now;
+        self.age = apparent.max(age).max(0);
+    }
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    fn cache_control(&self) -> Directives {
+        Directives::new(self.header("Cache-Control"))
+    }
+    /// Seconds it is fresh for after it was generated (RFC 9111 section 4.2.1).
+    fn lifetime(&self) -> i64 {
+        let cache_control = self.cache_control();
+        if let Some(lifetime) = cache_control
+            .seconds("s-maxage")
+            .or_else(|| cache_control.seconds("max-age"))
+        {
+            return lifetime;
+        }
+        let date = http_date(self.header("Date")).unwrap_or(self.stored);
+        if let Some(expires) = self.header("Expires") {
+            // An invalid date means it has already expired.
+            return http_date(Some(expires)).map_or(0, |expires| expires - date);
+        }
+        match http_date(self.header("Last-Modified")) {
+            Some(modified) if HEURISTIC.contains(&self.status.value) => {
+                ((date - modified) / 10).clamp(0, MAX_HEURISTIC)
+            }
+            _ => 0,
+        }
+    }
+    fn current_age(&self, now: i64) -> i64 {
+        self.age + (now - self.stored).max(0)
+    }
+    /// Whether it may be used after it is stale: not when the origin says it
+    /// must be checked again, which `s-maxage` also says to shared caches.
+    fn may_serve_stale(&self) -> bool {
+        let cache_control = self.cache_control();
+        ![
+            "must-revalidate",
+            "proxy-revalidate",
+            "s-maxage",
+            "no-cache",
+        ]
+        .iter()
+        .any(|directive| cache_control.has(directive))
+    }
+    fn response(&self, body: Vec<u8>, now: i64, status: &str) -> HttpResponse {
+        let mut builder = HttpResponse::new().body(body);
+        builder.status = self.status.clone();
+        builder.headers = self.headers.clone();
+        let mut response = builder.build();
+        response.set_header("Age", &self.current_age(now).to_string());
+        response.set_header("X-Cache", status);
+        response
+    }
+}
+
+/// Where the cache keeps a response: the request's host, path and query. The
+/// method isn't part of it, since only GET responses are stored, HEAD is answered
+/// from them and other methods have to find them to invalidate them.
+fn key(request: &HttpRequest) -> String {
+    let host = request.header("Host").unwrap_or("").trim();
+    format!(
+        "{}{}",
+        host.to_ascii_lowercase(),
+        &request.path[url::Position::BeforePath..url::Position::AfterQuery]
+    )
+}
+
+fn vary_names(vary: Option<&str>) -> Vec<String> {
+    let mut names: Vec<String> = vary
+        .unwrap_or("")
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn file_name(key: &str, vary: &[(String, String)]) -> String {
+    let mut id = key.to_owned();
+    for (name, value) in vary {
+        id.push_str(&format!("\n{}: {}", name, value));
+    }
+    format!("{:016x}.cache", hash(&id))
+}
+
+#[derive(Debug)]
+struct Indexed {
+    key: String,
+    size: u64,
+    /// When it was last used, for evicting the least recently used.
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    files: HashMap<String, Indexed>,
+    /// The headers each key's responses vary on.
+    vary: HashMap<String, Vec<String>>,
+    size: u64,
+    clock: u64,
+}
+
+/// An HTTP cache for proxied responses (RFC 9111), stored on disk.
+#[derive(Debug)]
+pub struct Cache {
+    config: CacheConfig,
+    index: Mutex<Index>,
+    /// Keys being fetched, so requests for the same one wait for a single fetch.
+    fetches: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+impl Cache {
+    /// Opens the cache directory, keeping what was stored before.
+    pub async fn open(config: CacheConfig) -> Result<Cache, ServerError> {
+        tokio::fs::create_dir_all(&config.directory).await?;
+        let mut index = Index::default();
+        let mut files = tokio::fs::read_dir(&config.directory).await?;
+        while let Some(file) = files.next_entry().await? {
+            let name = file.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".tmp") {
+                tokio::fs::remove_file(file.path()).await?;
+                continue;
+            } else if !name.ends_with(".cache") {
+                continue;
+            }
+            let mut meta = String::new();
+            BufReader::new(tokio::fs::File::open(file.path()).await?)
+                .read_line(&mut meta)
+                .await?;
+            let entry: Entry = match serde_json::from_str(&meta) {
+                Ok(entry) => entry,
+                Err(_) => {
+                    tokio::fs::remove_file(file.path()).await?;
+                    continue;
+                }
+            };
+            let size = file.metadata().await?.len();
+            index.size += size;
+            index.vary.insert(
+                entry.key.clone(),
+                entry.vary.iter().map(|(name, _)| name.clone()).collect(),
+            );
+            index.files.insert(
+                name,
+                Indexed {
+                    key: entry.key,
+                    size,
+                    used: 0,
+                },
+            );
+        }
+        let cache = Cache {
+            config,
+            index: Mutex::new(index),
+            fetches: Mutex::new(HashMap::new()),
+        };
+        cache.evict().await;
+        Ok(cache)
+    }
+    /// Responses stored.
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().files.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Bytes stored on disk.
+    pub fn size(&self) -> u64 {
+        self.index.lock().unwrap().size
+    }
+    async fn lookup(&self, request: &HttpRequest) -> Option<(Entry, Vec<u8>)> {
+        let key = key(request);
+        let (name, vary) = {
+            let mut index = self.index.lock().unwrap();
+            let vary: Vec<(String, String)> = index
+                .vary
+                .get(&key)?
+                .iter()
+                .map(|name| {
+                    let value = request.header(name).unwrap_or("").trim().to_owned();
+                    (name.clone(), value)
+                })
+                .collect();
+            let name = file_name(&key, &vary);
+            index.clock += 1;
+            let clock = index.clock;
+            index.files.get_mut(&name)?.used = clock;
+            (name, vary)
+        };
+        let data = tokio::fs::read(self.config.directory.join(&name))
+            .await
+            .ok()?;
+        let split = data.iter().position(|byte| *byte == b'\n')?;
+        let entry: Entry = serde_json::from_slice(&data[..split]).ok()?;
+        match entry.key == key && entry.vary == vary {
+            true => Some((entry, data[split + 1..].to_vec())),
+            false => None,
+        }
+    }
+    async fn store(&self, entry: &Entry, body: &[u8]) {
+        let mut data = match serde_json::to_vec(entry) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+        data.push(b'\n');
+        data.extend_from_slice(body);
+        if data.len() as u64 > self.config.max_entry_size {
+            return;
+        }
+        let name = file_name(&entry.key, &entry.vary);
+        let temporary =
+            self.config
+                .directory
+                .join(format!("{}.{}.tmp", name, uuid::Uuid::new_v4()));
+        let written = async {
+            tokio::fs::write(&temporary, &data).await?;
+            tokio::fs::rename(&temporary, self.config.directory.join(&name)).await
+        };
+        if let Err(e) = written.await {
+            warn!("Could not cache {}: {}", entry.key, e);
+            let _ = tokio::fs::remove_file(&temporary).await;
+            return;
+        }
+        let names: Vec<String> = entry.vary.iter().map(|(name, _)| name.clone()).collect();
+        let outdated = {
+            let mut index = self.index.lock().unwrap();
+            // Responses that varied on other headers can't be found any more.
+            let outdated = match index.vary.insert(entry.key.clone(), names.clone()) {
+                Some(previous) if previous != names => index.remove(|key| key == entry.key),
+                _ => vec![],
+            };
+            index.clock += 1;
+            let indexed = Indexed {
+                key: entry.key.clone(),
+                size: data.len() as u64,
+                used: index.clock,
+            };
+            index.size += indexed.size;
+            if let Some(replaced) = index.files.insert(name, indexed) {
+                index.size -= replaced.size;
+            }
+            outdated
+        };
+        self.delete(outdated).await;
+        self.evict().await;
+    }
+    /// Removes the least recently used responses while over `max_size`.
+    async fn evict(&self) {
+        let evicted = {
+            let mut index = self.index.lock().unwrap();
+            let mut by_use: Vec<(u64, String)> = index
+                .files
+                .iter()
+                .map(|(name, indexed)| (indexed.used, name.clone()))
+                .collect();
+            by_use.sort();
+            let mut evicted = vec![];
+            for (_, name) in by_use {
+                if index.size <= self.config.max_size {
+                    break;
+                }
+                if let Some(indexed) = index.files.remove(&name) {
+                    index.size -= indexed.size;
+                    evicted.push(name);
+                }
+            }
+            evicted
+        };
+        self.delete(evicted).await;
+    }
+    async fn delete(&self, names: Vec<String>) {
+        for name in names {
+            let _ = tokio::fs::remove_file(self.config.directory.join(name)).await;
+        }
+    }
+    /// Removes the responses for a key, or for every one starting with a prefix
+    /// ending in `*`. Returns how many there were.
+    pub async fn purge(&self, pattern: &str) -> usize {
+        let removed = self
+            .index
+            .lock()
+            .unwrap()
+            .remove(|key| match pattern.strip_suffix('*') {
+                Some(prefix) => key.starts_with(prefix),
+                None => key == pattern,
+            });
+        let count = removed.len();
+        self.delete(removed).await;
+        count
+    }
+    fn fetch_lock(&self, key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.fetches
+            .lock()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .clone()
+    }
+    fn fetched(&self) {
+        self.fetches
+            .lock()
+            .unwrap()
+            .retain(|_, lock| Arc::strong_count(lock) > 1);
+    }
+}
+impl Index {
+    /// Takes the responses whose keys match out of the index, returning their files.
+    fn remove<F: Fn(&str) -> bool>(&mut self, matches: F) -> Vec<String> {
+        let names: Vec<String> = self
+            .files
+            .iter()
+            .filter(|(_, indexed)| matches(&indexed.key))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &names {
+            if let Some(indexed) = self.files.remove(name) {
+                self.size -= indexed.size;
+            }
+        }
+        self.vary.retain(|key, _| !matches(key));
+        names
+    }
+}
+
+/// Whether a shared cache may store a response (RFC 9111 section 3).
+fn storable(response: &HttpResponse) -> bool {
+    let cache_control = Directives::new(response.header("Cache-Control"));
+    // Without any of these it would never be fresh, and couldn't be revalidated.
+    let useful = ["max-age", "s-maxage", "public"]
+        .iter()
+        .any(|directive| cache_control.has(directive))
+        || ["Expires", "ETag", "Last-Modified"]
+            .iter()
+            .any(|header| response.header(header).is_some());
+    useful
+        && HEURISTIC.contains(&response.status.value)
+        && !cache_control.has("no-store")
+        && !cache_control.has("private")
+        && response.cookies.is_empty()
+        && response.set_cookies.is_empty()
+        && !vary_names(response.header("Vary"))
+            .iter()
+            .any(|name| name == "*")
+}
+
+/// Reads a streamed body into memory, if it is no bigger than `limit`.
+/// Otherwise, the response is left to stream as it was.
+async fn buffer(
+    mut response: HttpResponse,
+    limit: u64,
+) -> std::io::Result<(HttpResponse, Option<Vec<u8>>)> {
+    let mut stream = match response.stream.take() {
+        Some(stream) => stream,
+        None => {
+            let body = response.body.clone();
+            return Ok((response, Some(body)));
+        }
+    };
+    let mut data = vec![];
+    (&mut stream.reader)
+        .take(limit + 1)
+        .read_to_end(&mut data)
+        .await?;
+    let chunked = response.header("Transfer-Encoding").is_some();
+    let body = match data.len() as u64 > limit {
+        true => None,
+        false if chunked => dechunk(&data),
+        false => Some(data.clone()),
+    };
+    match body {
+        Some(body) => {
+            response.remove_header("Transfer-Encoding");
+            response.remove_header("Connection");
+            response.set_body(body.clone());
+            Ok((response, Some(body)))
+        }
+        None => {
+            stream.reader = Box::new(std::io::Cursor::new(data).chain(stream.reader));
+            response.stream = Some(stream);
+            Ok((response, None))
+        }
+    }
+}
+
+/// Decodes a chunked body, leaving out any trailers.
+fn dechunk(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line_end = data.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&data[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        } else if data.len() < size + 2 {
+            return None;
+        }
+        body.extend_from_slice(&data[..size]);
+        data = &data[size + 2..];
+    }
+}
+
+/// A 304 for a client that already has the response it is about to be sent.
+fn not_modified(request: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    if response.status.value != 200 {
+        return response;
+    }
+    let unchanged = match request.header("If-None-Match") {
+        Some(tags) => response.header("ETag").is_some_and(|etag| {
+            let etag = etag.trim_start_matches("W/");
+            tags.split(',')
+                .any(|tag| tag.trim() == "*" || tag.trim().trim_start_matches("W/") == etag)
+        }),
+        None => match (
+            http_date(request.header("If-Modified-Since")),
+            http_date(response.header("Last-Modified")),
+        ) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        },
+    };
+    if !unchanged {
+        return response;
+    }
+    let mut builder = HttpResponse::new().status(304);
+    for name in [
+        "Age",
+        "Cache-Control",
+        "Date",
+        "ETag",
+        "Expires",
+        "Last-Modified",
+        "Vary",
+        "X-Cache",
+    ] {
+        if let Some(value) = response.header(name) {
+            builder = builder.header(name, value);
+        }
+    }
+    let mut response = builder.build();
+    response.remove_header("Content-Length");
+    response
+}
+
+/// The request to send upstream to fill or refresh the cache: a plain GET,
+/// made conditional on what is stored.
+fn upstream_request(request: &HttpRequest, stored: Option<&Entry>) -> HttpRequest {
+    let mut headers: Vec<HttpHeader> = request
+        .headers
+        .iter()
+        .filter(|header| {
+            ![
+                "If-None-Match",
+                "If-Modified-Since",
+                "If-Match",
+                "If-Unmodified-Since",
+            ]
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(&header.name))
+        })
+        .map(|header| HttpHeader::new(&header.name, &header.value))
+        .collect();
+    if let Some(stored) = stored {
+        if let Some(etag) = stored.header("ETag") {
+            headers.push(HttpHeader::new("If-None-Match", etag));
+        }
+        if let Some(modified) = stored.header("Last-Modified") {
+            headers.push(HttpHeader::new("If-Modified-Since", modified));
+        }
+    }
+    HttpRequest {
+        method: HttpMethod::Get,
+        path: request.path.clone(),
+        version: request.version.clone(),
+        headers,
+        params: HashMap::new(),
+        remote_addr: request.remote_addr,
+        body: RequestBody::default(),
+    }
+}
+
+/// Fetches a response for the cache and stores it, or refreshes the stored one.
+/// Falls back on the stored one if the upstream fails and `stale-if-error` allows.
+async fn fetch(
+    cache: &Cache,
+    proxy: &Proxy,
+    request: &HttpRequest,
+    stored: Option<(Entry, Vec<u8>)>,
+) -> HttpResponse {
+    let mut upstream = upstream_request(request, stored.as_ref().map(|(entry, _)| entry));
+    let replaced = stored.is_some();
+    let response = proxy.fetch(&mut upstream).await;
+    let now = Utc::now().timestamp();
+    if let Some((mut entry, body)) = stored {
+        if response.status.value == 304 {
+            entry.update(&response, now);
+            cache.store(&entry, &body).await;
+            return entry.response(body, now, "REVALIDATED");
+        }
+        let staleness = entry.current_age(now) - entry.lifetime();
+        let stale_if_error = Directives::new(request.header("Cache-Control"))
+            .seconds("stale-if-error")
+            .or_else(|| entry.cache_control().seconds("stale-if-error"));
+        if response.status.value >= 500
+            && entry.may_serve_stale()
+            && stale_if_error.is_some_and(|seconds| staleness <= seconds)
+        {
+            return entry.response(body, now, "STALE");
+        }
+    }
+    if !storable(&response) {
+        // The upstream no longer lets this be stored; an error doesn't say so.
+        if replaced && response.status.value < 500 {
+            cache.purge(&key(request)).await;
+        }
+        let mut response = response;
+        response.set_header("X-Cache", "MISS");
+        return response;
+    }
+    let (mut response, body) = match buffer(response, cache.config.max_entry_size).await {
+        Ok(buffered) => buffered,
+        Err(e) => {
+            error!("Could not read {} from upstream: {}", key(request), e);
+            return error_response(502);
+        }
+    };
+    if let Some(body) = body {
+        cache
+            .store(&Entry::new(request, &response, now), &body)
+            .await;
+    }
+    response.set_header("X-Cache", "MISS");
+    response
+}
+
+/// Answers a request to a caching proxy: from the cache when it can, from the
+/// upstream otherwise.
+pub(crate) async fn respond(
+    cache: &Arc<Cache>,
+    proxy: &Proxy,
+    mut request: HttpRequest,
+) -> HttpResponse {
+    match &request.method {
+        HttpMethod::Extension(method) if method == "PURGE" => {
+            let allowed = request
+                .remote_addr
+                .is_some_and(|address| cache.config.purge_from.contains(&address.ip()));
+            if !allowed {
+                return error_response(403);
+            }
+            let purged = cache.purge(&key(&request)).await;
+            return HttpResponse::new()
+                .header("Content-Type", "application/json")
+                .body(format!("{{\"purged\":{}}}", purged).into_bytes())
+                .build();
+        }
+        HttpMethod::Get | HttpMethod::Head => {}
+        _ => {
+            // Changing something makes what is stored for it out of date (section 4.4).
+            let response = proxy.fetch(&mut request).await;
+            if response.status.value < 400 {
+                cache.purge(&key(&request)).await;
+            }
+            return response;
+        }
+    }
+    let cache_control = Directives::new(request.header("Cache-Control"));
+    if cache_control.has("no-store")
+        || request.header("Authorization").is_some()
+        || request.header("Range").is_some()
+    {
+        let mut response = proxy.fetch(&mut request).await;
+        response.set_header("X-Cache", "BYPASS");
+        return response;
+    }
+    let no_cache = cache_control.has("no-cache")
+        || (request.header("Cache-Control").is_none()
+            && request
+                .header("Pragma")
+                .is_some_and(|pragma| pragma.contains("no-cache")));
+    let mut stored = cache.lookup(&request).await;
+    let mut fetching = None;
+    loop {
+        if let Some((entry, body)) = &stored {
+            let now = Utc::now().timestamp();
+            if let Some((response, revalidate)) =
+                from_cache(&cache_control, no_cache, entry, body, now)
+            {
+                drop(fetching);
+                if revalidate {
+                    revalidate_later(cache.clone(), proxy.clone(), &request);
+                }
+                cache.fetched();
+                return not_modified(&request, response);
+            }
+        }
+        if cache_control.has("only-if-cached") {
+            return error_response(504);
+        }
+        if fetching.is_some() {
+            break;
+        }
+        // Wait for any fetch of the same thing, then see if it was stored.
+        fetching = Some(cache.fetch_lock(&key(&request)).lock_owned().await);
+        stored = cache.lookup(&request).await;
+    }
+    let response = fetch(cache, proxy, &request, stored).await;
+    drop(fetching);
+    cache.fetched();
+    not_modified(&request, response)
+}
+
+/// The stored response, if the request can have it, and whether it is stale
+/// but should be refreshed in the background (RFC 5861).
+fn from_cache(
+    cache_control: &Directives,
+    no_cache: bool,
+    entry: &Entry,
+    body: &[u8],
+    now: i64,
+) -> Option<(HttpResponse, bool)> {
+    if no_cache || entry.cache_control().has("no-cache") {
+        return None;
+    }
+    let age = entry.current_age(now);
+    let lifetime = entry.lifetime();
+    let fresh = age < lifetime - cache_control.seconds("min-fresh").unwrap_or(0)
+        && cache_control
+            .seconds("max-age")
+            .is_none_or(|max_age| age <= max_age);
+    if fresh {
+        return Some((entry.response(body.to_vec(), now, "HIT"), false));
+    } else if !entry.may_serve_stale() {
+        return None;
+    }
+    let staleness = age - lifetime;
+    let max_stale = match cache_control.0.get("max-stale") {
+        Some(Some(seconds)) => seconds
+            .parse::<i64>()
+            .is_ok_and(|seconds| staleness <= seconds),
+        Some(None) => true,
+        None => false,
+    };
+    let while_revalidate = entry
+        .cache_control()
+        .seconds("stale-while-revalidate")
+        .is_some_and(|seconds| staleness <= seconds);
+    if max_stale || while_revalidate || cache_control.has("only-if-cached") {
+        let response = entry.response(body.to_vec(), now, "STALE");
+        return Some((response, while_revalidate));
+    }
+    None
+}
+
+/// Refreshes a stale response in the background, unless that is already happening.
+fn revalidate_later(cache: Arc<Cache>, proxy: Proxy, request: &HttpRequest) {
+    let request = upstream_request(request, None);
+    tokio::spawn(async move {
+        let fetching = cache.fetch_lock(&key(&request)).try_lock_owned();
+        if fetching.is_ok() {
+            if let Some(stored) = cache.lookup(&request).await {
+                fetch(&cache, &proxy, &request, Some(stored)).await;
+            }
+        }
+        drop(fetching);
+        cache.fetched();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn response(head: &str) -> HttpResponse {
+        HttpResponse::parse(head).unwrap().1
+    }
+
+    #[test]
+    fn cache_freshness() {
+        let (_, request) = HttpRequest::parse(
+            "GET /a?b=1 HTTP/1.1\r\nHost: Example.com\r\nAccept-Encoding: gzip\r\n\r\n",
+        )
+        .unwrap();
+        let now = 1_700_000_000;
+        let date = |offset: i64| {
+            chrono::TimeZone::timestamp_opt(&Utc, now + offset, 0)
+                .unwrap()
+                .to_rfc2822()
+        };
+        let upstream = response(&format!(
+            "HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: public, max-age=60, stale-while-revalidate=30\r\nVary: Accept-Encoding\r\nContent-Length: 5\r\n\r\n",
+            date(-10)
+        ));
+        assert!(storable(&upstream));
+        let entry = Entry::new(&request, &upstream, now);
+        assert_eq!(entry.key, "example.com/a?b=1");
+        assert_eq!(
+            entry.vary,
+            vec![("accept-encoding".to_owned(), "gzip".to_owned())]
+        );
+        assert_eq!(entry.header("Content-Length"), None);
+        assert_eq!((entry.current_age(now + 5), entry.lifetime()), (15, 60));
+        assert!(entry.may_serve_stale());
+        let heuristic = Entry::new(
+            &request,
+            &response(&format!(
+                "HTTP/1.1 200 OK\r\nDate: {}\r\nLast-Modified: {}\r\n\r\n",
+                date(0),
+                date(-1000)
+            )),
+            now,
+        );
+        assert_eq!(heuristic.lifetime(), 100);
+        let expired =
+            response("HTTP/1.1 200 OK\r\nExpires: 0\r\nCache-Control: s-maxage=0\r\n\r\n");
+        assert_eq!(Entry::new(&request, &expired, now).lifetime(), 0);
+        assert!(!Entry::new(&request, &expired, now).may_serve_stale());
+        assert!(!storable(&response(
+            "HTTP/1.1 200 OK\r\nCache-Control: private, max-age=60\r\n\r\n"
+        )));
+        assert!(!storable(&response("HTTP/1.1 200 OK\r\n\r\n")));
+        assert_eq!(
+            dechunk(b"5\r\nhello\r\n6;x=y\r\n world\r\n0\r\nTrailer: 1\r\n\r\n"),
+            Some(b"hello world".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn cache_store_and_evict() {
+        let directory = TempDir::new("cache");
+        let config = CacheConfig {
+            directory: directory.to_path_buf(),
+            max_size: 250,
+            max_entry_size: 1000,
+            purge_from: vec![],
+        };
+        let cache = Cache::open(config.clone()).await.unwrap();
+        let now = Utc::now().timestamp();
+        let upstream =
+            response("HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nVary: Accept\r\n\r\n");
+        let (_, json) =
+            HttpRequest::parse("GET /data HTTP/1.1\r\nAccept: application/json\r\n\r\n").unwrap();
+        let (_, html) =
+            HttpRequest::parse("GET /data HTTP/1.1\r\nAccept: text/html\r\n\r\n").unwrap();
+        cache.store(&Entry::new(&json, &upstream, now), b"{}").await;
+        assert_eq!(cache.lookup(&json).await.unwrap().1, b"{}");
+        // Name-based virtual hosts don't share responses.
+        let (_, vhost) = HttpRequest::parse(
+            "GET /data HTTP/1.1\r\nHost: other.example\r\nAccept: application/json\r\n\r\n",
+        )
+        .unwrap();
+        assert!(cache.lookup(&vhost).await.is_none());
+        assert!(cache.lookup(&html).await.is_none());
+        cache
+            .store(&Entry::new(&html, &upstream, now), b"<p>")
+            .await;
+        // Both variants don't fit, so the older one goes.
+        assert_eq!(cache.len(), 1);
+        assert!(cache.lookup(&json).await.is_none());
+        let reopened = Cache::open(config).await.unwrap();
+        assert_eq!(reopened.lookup(&html).await.unwrap().1, b"<p>");
+        assert_eq!(reopened.purge("/d*").await, 1);
+        assert!(reopened.is_empty());
+    }
+}
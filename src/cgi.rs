@@ -0,0 +1,463 @@
+use crate::files::error_response;
+use crate::http::*;
+use crate::router::Handler;
+use crate::server::{Config, ServerError};
+use async_trait::async_trait;
+use log::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use toml::Value;
+
+/// A directory of CGI/1.1 scripts (RFC 3875) mounted at a URL path, from a
+/// `[[cgi]]` table.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CgiConfig {
+    /// The URL path the scripts are served under, like `/cgi-bin/`.
+    pub path: String,
+    /// Where the scripts are, outside the resources root so they can't be downloaded.
+    pub directory: PathBuf,
+    /// Programs to run scripts with by extension, for scripts that aren't executable.
+    pub interpreters: HashMap<String, String>,
+    /// Seconds a script may run before it is killed.
+    pub timeout: u64,
+    /// Scripts that may run at once; requests past this get 503.
+    pub max_processes: usize,
+}
+impl CgiConfig {
+    pub fn from_toml(cfg: &Value) -> Result<CgiConfig, ServerError> {
+        let mut config = CgiConfig {
+            path: match cfg.get("path") {
+                Some(Value::String(path)) if path.starts_with('/') => path.clone(),
+                _ => return Err(ServerError::ConfigError),
+            },
+            directory: match cfg.get("directory") {
+                Some(Value::String(directory)) => PathBuf::from(directory),
+                _ => return Err(ServerError::ConfigError),
+            },
+            interpreters: [("pl".to_owned(), "perl".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            timeout: 30,
+            max_processes: 16,
+        };
+        if let Some(Value::Table(interpreters)) = cfg.get("interpreters") {
+            for (extension, program) in interpreters {
+                match program {
+                    Value::String(program) => config.interpreters.insert(
+                        extension.trim_start_matches('.').to_owned(),
+                        program.clone(),
+                    ),
+                    _ => return Err(ServerError::ConfigError),
+                };
+            }
+        }
+        if let Some(Value::Integer(timeout)) = cfg.get("timeout") {
+            config.timeout = *timeout as u64;
+        }
+        if let Some(Value::Integer(max_processes)) = cfg.get("max_processes") {
+            config.max_processes = (*max_processes).max(1) as usize;
+        }
+        Ok(config)
+    }
+}
+
+/// Runs the scripts in a CGI directory, passing the request in environment
+/// variables and on stdin, and answering with what they print.
+#[derive(Clone)]
+pub struct Cgi {
+    config: Arc<Config>,
+    cgi: CgiConfig,
+    processes: Arc<Semaphore>,
+}
+impl Cgi {
+    pub fn new(config: Arc<Config>, cgi: CgiConfig) -> Cgi {
+        Cgi {
+            config,
+            processes: Arc::new(Semaphore::new(cgi.max_processes)),
+            cgi,
+        }
+    }
+    /// Finds the script a request names, walking its path until a file is
+    /// found so the rest becomes `PATH_INFO`. Returns the script's file,
+    /// `SCRIPT_NAME` and `PATH_INFO`.
+    async fn script(&self, request: &HttpRequest) -> Option<(PathBuf, String, String)> {
+        let prefix = self.cgi.path.trim_end_matches('/');
+        let rest = request.path.path().strip_prefix(prefix)?;
+        let segments: Vec<&str> = rest.split('/').filter(|s| !s.is_empty()).collect();
+        let mut file_path = self.cgi.directory.clone();
+        for (index, segment) in segments.iter().enumerate() {
+            let name = percent_encoding::percent_decode_str(segment)
+                .decode_utf8()
+                .ok()?;
+            if name.starts_with('.') || name.contains('/') || name.contains('\\') {
+                return None;
+            }
+            file_path.push(name.as_ref());
+            let metadata = tokio::fs::metadata(&file_path).await.ok()?;
+            if metadata.is_file() {
+                let script_name = format!("{}/{}", prefix, segments[..=index].join("/"));
+                let path_info = segments[index + 1..]
+                    .iter()
+                    .map(|segment| {
+                        format!(
+                            "/{}",
+                            percent_encoding::percent_decode_str(segment).decode_utf8_lossy()
+                        )
+                    })
+                    .collect();
+                // Symlinks may not lead out of the script directory.
+                let file_path = tokio::fs::canonicalize(&file_path).await.ok()?;
+                let directory = tokio::fs::canonicalize(&self.cgi.directory).await.ok()?;
+                return match file_path.starts_with(directory) {
+                    true => Some((file_path, script_name, path_info)),
+                    false => None,
+                };
+            }
+        }
+        None
+    }
+    async fn run(&self, request: &mut HttpRequest) -> Result<HttpResponse, ServerError> {
+        let (script, script_name, path_info) = match self.script(request).await {
+            Some(script) => script,
+            None => return Ok(error_response(404)),
+        };
+        let _permit = match self.processes.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("Too many CGI scripts running for {}", script_name);
+                let mut response = error_response(503);
+                response.set_header("Retry-After", "1");
+                return Ok(response);
+            }
+        };
+        let interpreter = script
+            .extension()
+            .and_then(|ext| self.cgi.interpreters.get(ext.to_str()?));
+        let mut command = match interpreter {
+            Some(interpreter) => {
+                let mut command = Command::new(interpreter);
+                command.arg(&script);
+                command
+            }
+            None => Command::new(&script),
+        };
+        command
+            .env_clear()
+            .envs(environment(
+                &self.config,
+                request,
+                &script,
+                &script_name,
+                &path_info,
+            ))
+            .env(
+                "PATH",
+                std::env::var("PATH").unwrap_or_else(|_| "/usr/local/bin:/usr/bin:/bin".to_owned()),
+            )
+            .current_dir(script.parent().unwrap_or_else(|| Path::new("/")))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Could not run CGI script {}: {}", script.display(), e);
+                return Ok(error_response(500));
+            }
+        };
+        let mut stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().ok_or(ServerError::IoError)?;
+        let mut stderr = child.stderr.take().ok_or(ServerError::IoError)?;
+        let body = &mut request.body;
+        let exchange = async {
+            // Writing and reading at once, so a script that answers before
+            // reading all of its input can't block on a full pipe.
+            let feed = async {
+                if let Some(stdin) = stdin.as_mut() {
+                    // Scripts that don't read the body close stdin early.
+                    let _ = tokio::io::copy(body, stdin).await;
+                }
+                drop(stdin.take());
+            };
+            let mut output = vec![];
+            let mut errors = vec![];
+            let (_, out, err) = tokio::join!(
+                feed,
+                stdout.read_to_end(&mut output),
+                stderr.read_to_end(&mut errors)
+            );
+            out?;
+            err?;
+            let status = child.wait().await?;
+            Ok::<_, std::io::Error>((output, errors, status))
+        };
+        let timeout = Duration::from_secs(self.cgi.timeout);
+        let (output, errors, status) = match tokio::time::timeout(timeout, exchange).await {
+            Ok(result) => result?,
+            Err(_) => {
+                error!("CGI script {} timed out", script_name);
+                return Ok(error_response(504));
+            }
+        };
+        for line in String::from_utf8_lossy(&errors).lines() {
+            if !line.trim().is_empty() {
+                warn!("{}: {}", script_name, line);
+            }
+        }
+        match parse_output(&output) {
+            Ok(response) => Ok(response),
+            Err(reason) => {
+                error!(
+                    "CGI script {} {} ({})",
+                    script_name,
+                    reason,
+                    match status.code() {
+                        Some(code) => format!("exit status {}", code),
+                        None => "killed by a signal".to_owned(),
+                    }
+                );
+                Ok(error_response(502))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for Cgi {
+    async fn handle(&self, request: HttpRequest) -> HttpResponse {
+        let mut request = request;
+        match self.run(&mut request).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!(
+                    "Could not run CGI script for {}: {}",
+                    request.path.path(),
+                    e.message()
+                );
+                error_response(500)
+            }
+        }
+    }
+}
+
+/// The meta-variables from RFC 3875 section 4.1, plus `SCRIPT_FILENAME`,
+/// `REQUEST_URI` and `DOCUMENT_ROOT` that most scripts also expect.
+pub(crate) fn environment(
+    config: &Config,
+    request: &HttpRequest,
+    script: &Path,
+    script_name: &str,
+    path_info: &str,
+) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_owned()),
+        (
+            "SERVER_SOFTWARE",
+            format!("GlassCannon/{}", env!("CARGO_PKG_VERSION")),
+        ),
+        ("SERVER_PROTOCOL", format!("HTTP/{}", request.version)),
+        (
+            "SERVER_NAME",
+            request
+                .header("Host")
+                .map(|host| host.rsplit_once(':').map_or(host, |(name, _)| name))
+                .unwrap_or("localhost")
+                .to_owned(),
+        ),
+        ("SERVER_PORT", config.port.to_string()),
+        ("REQUEST_METHOD", request.method.to_string()),
+        (
+            "QUERY_STRING",
+            request.path.query().unwrap_or("").to_owned(),
+        ),
+        (
+            "REQUEST_URI",
+            match request.path.query() {
+                Some(query) => format!("{}?{}", request.path.path(), query),
+                None => request.path.path().to_owned(),
+            },
+        ),
+        ("SCRIPT_NAME", script_name.to_owned()),
+        ("SCRIPT_FILENAME", script.to_string_lossy().to_string()),
+        (
+            "DOCUMENT_ROOT",
+            config.resources.to_string_lossy().to_string(),
+        ),
+    ];
+    if !path_info.is_empty() {
+        env.push(("PATH_INFO", path_info.to_owned()));
+        if let Some(translated) = config.file_path(path_info) {
+            env.push(("PATH_TRANSLATED", translated.to_string_lossy().to_string()));
+        }
+    }
+    if let Some(remote_addr) = request.remote_addr {
+        env.push(("REMOTE_ADDR", remote_addr.ip().to_string()));
+        env.push(("REMOTE_PORT", remote_addr.port().to_string()));
+    }
+    if !request.body.is_empty() {
+        env.push(("CONTENT_LENGTH", request.body.len().to_string()));
+    }
+    if let Some(content_type) = request.header("Content-Type") {
+        env.push(("CONTENT_TYPE", content_type.to_owned()));
+    }
+    let mut env: Vec<(String, String)> = env
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value))
+        .collect();
+    for header in &request.headers {
+        let name = header.name.to_ascii_uppercase().replace('-', "_");
+        // Credentials stay with the server, and HTTP_PROXY would be taken
+        // for proxy settings by many HTTP libraries ("httpoxy").
+        if ["CONTENT_LENGTH", "CONTENT_TYPE", "AUTHORIZATION", "PROXY"].contains(&&name[..])
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            continue;
+        }
+        let name = format!("HTTP_{}", name);
+        match env.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, value)) => {
+                value.push_str(", ");
+                value.push_str(&header.value);
+            }
+            None => env.push((name, header.value.clone())),
+        }
+    }
+    env
+}
+/// Turns a script's output, CGI header fields then a blank line and the body,
+/// into a response. `Status:` sets the status, and a `Location:` without one
+/// redirects with 302.
+pub(crate) fn parse_output(output: &[u8]) -> Result<HttpResponse, &'static str> {
+    let mut response = HttpResponse::new();
+    let mut status = None;
+    let mut position = 0;
+    loop {
+        let end = match output[position..].iter().position(|byte| *byte == b'\n') {
+            Some(end) => position + end,
+            None => return Err("printed no blank line after its headers"),
+        };
+        let line = String::from_utf8_lossy(&output[position..end]);
+        let line = line.trim_end_matches('\r');
+        position = end + 1;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) if !name.trim().is_empty() => (name.trim(), value.trim()),
+            _ => return Err("printed a malformed header"),
+        };
+        if name.eq_ignore_ascii_case("Status") {
+            // Any code a server could send, not just those `status()` knows.
+            let code = value.split(' ').next().unwrap_or_default();
+            status = match HttpStatus::parse(code) {
+                Ok(("", status)) => Some(status),
+                _ => return Err("printed an unsupported status"),
+            };
+        } else if name.eq_ignore_ascii_case("Set-Cookie") {
+            response.set_cookies.push(value.to_owned());
+        } else if name.eq_ignore_ascii_case("Content-Length") {
+            // The body is sent as it was printed, with its real length.
+        } else {
+            // PHP prints `Content-type`, which would be missed by lookups elsewhere.
+            let name = canonical_name(name);
+            let value = match response.headers.get(&name) {
+                Some(existing) => format!("{}, {}", existing, value),
+                None => value.to_owned(),
+            };
+            response = response.header(&name, &value);
+        }
+    }
+    let redirect = response.headers.contains_key("Location");
+    response.status = match status {
+        Some(status) => status,
+        None => HttpStatus::new(if redirect { 302 } else { 200 }).unwrap(),
+    };
+    Ok(response.body(output[position..].to_vec()).build())
+}
+
+/// Headers the server itself reads, which it looks up with this spelling.
+const KNOWN_HEADERS: &[&str] = &[
+    "Cache-Control",
+    "Content-Encoding",
+    "Content-Type",
+    "ETag",
+    "Last-Modified",
+    "Location",
+    "Vary",
+];
+
+/// Spells a header the server reads the way it expects, keeping others as given.
+fn canonical_name(name: &str) -> String {
+    KNOWN_HEADERS
+        .iter()
+        .find(|known| known.eq_ignore_ascii_case(name))
+        .map_or(name, |known| *known)
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn cgi_output() {
+        let response = parse_output(
+            b"content-type: text/plain\r\nStatus: 404 Not Found\r\nSet-Cookie: id=1; Path=/; HttpOnly; Priority=High\r\nX-A: 1\r\nX-A: 2\r\n\r\ngone",
+        )
+        .unwrap();
+        assert_eq!(response.status.value, 404);
+        assert_eq!(response.headers["Content-Type"], "text/plain");
+        assert_eq!(response.headers["X-A"], "1, 2");
+        assert_eq!(
+            response.set_cookies,
+            vec!["id=1; Path=/; HttpOnly; Priority=High"]
+        );
+        assert_eq!(response.body, b"gone");
+        let redirect = parse_output(b"Location: /elsewhere\n\n").unwrap();
+        assert_eq!(redirect.status.value, 302);
+        let gone = parse_output(b"Status: 410 Gone\n\n").unwrap();
+        assert_eq!(gone.status.value, 410);
+        assert!(parse_output(b"Status: 600\n\n").is_err());
+        assert!(parse_output(b"Status: 4100\n\n").is_err());
+        assert!(parse_output(b"no headers").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cgi_script() {
+        let dir = TempDir::new("cgi");
+        dir.write(
+            "echo.sh",
+            "printf 'Content-Type: text/plain\\n\\n'\nprintf '%s %s %s ' \"$REQUEST_METHOD\" \"$PATH_INFO\" \"$HTTP_X_NAME\"\ncat\n",
+        );
+        let mut cgi = CgiConfig::from_toml(
+            &toml::from_str(&format!(
+                "path = \"/cgi-bin/\"\ndirectory = {:?}\ninterpreters = {{ sh = \"sh\" }}",
+                dir.to_string_lossy()
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        cgi.timeout = 5;
+        let cgi = Cgi::new(Arc::new(dir.config()), cgi);
+        let (_, mut request) = HttpRequest::parse(
+            "POST /cgi-bin/echo.sh/extra/path HTTP/1.1\r\nX-Name: glass\r\nContent-Length: 4\r\n\r\n",
+        )
+        .unwrap();
+        request.body = RequestBody::from_bytes(b"body".to_vec());
+        let response = cgi.handle(request).await;
+        assert_eq!(response.status.value, 200);
+        assert_eq!(response.body, b"POST /extra/path glass body");
+        let (_, missing) = HttpRequest::parse("GET /cgi-bin/nope.sh HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(cgi.handle(missing).await.status.value, 404);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::server::{Config, ServerError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// A content coding that a response body can be sent with.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+impl Encoding {
+    /// Encodings in order of preference when the client ranks them equally.
+    pub const PREFERRED: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+    /// The file extension used for precompressed copies of a resource.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Identity => "",
+            Encoding::Gzip => "gz",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub precompressed: bool,
+    pub min_size: usize,
+    pub gzip_level: u32,
+    pub brotli_level: u32,
+    pub zstd_level: i32,
+    pub types: Vec<String>,
+}
+impl Default for CompressionConfig {
+    fn default() -> CompressionConfig {
+        CompressionConfig {
+            enabled: true,
+            precompressed: true,
+            min_size: 1024,
+            gzip_level: 6,
+            brotli_level: 5,
+            zstd_level: 3,
+            types: vec![
+                "text/*".to_owned(),
+                "application/json".to_owned(),
+                "application/javascript".to_owned(),
+                "application/xml".to_owned(),
+                "application/xhtml+xml".to_owned(),
+                "image/svg+xml".to_owned(),
+            ],
+        }
+    }
+}
+impl CompressionConfig {
+    pub fn from_toml(cfg: &Value) -> CompressionConfig {
+        let mut config = CompressionConfig::default();
+        if let Some(Value::Boolean(enabled)) = cfg.get("enabled") {
+            config.enabled = *enabled;
+        }
+        if let Some(Value::Boolean(precompressed)) = cfg.get("precompressed") {
+            config.precompressed = *precompressed;
+        }
+        if let Some(Value::Integer(min_size)) = cfg.get("min_size") {
+            config.min_size = *min_size as usize;
+        }
+        if let Some(Value::Integer(level)) = cfg.get("gzip_level") {
+            config.gzip_level = (*level as u32).min(9);
+        }
+        if let Some(Value::Integer(level)) = cfg.get("brotli_level") {
+            config.brotli_level = (*level as u32).min(11);
+        }
+        if let Some(Value::Integer(level)) = cfg.get("zstd_level") {
+            config.zstd_level = (*level as i32).clamp(1, 22);
+        }
+        if let Some(Value::Array(types)) = cfg.get("types") {
+            config.types = types
+                .iter()
+                .filter_map(|t| t.as_str().map(|t| t.to_owned()))
+                .collect();
+        }
+        config
+    }
+    /// Whether a body of the given MIME type should be compressed.
+    pub fn is_compressible(&self, mimetype: &str) -> bool {
+        let mimetype = mimetype.split(';').next().unwrap_or("").trim();
+        self.types.iter().any(|t| match t.strip_suffix('*') {
+            Some(prefix) => mimetype.starts_with(prefix),
+            None => t == mimetype,
+        })
+    }
+}
+
+/// Parses an `Accept-Encoding` header into `(coding, qvalue)` pairs.
+pub fn parse_accept_encoding(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let mut q = 1.0;
+            for param in params {
+                if let Some(value) = param.trim().strip_prefix("q=") {
+                    q = value.trim().parse::<f32>().unwrap_or(0.0).clamp(0.0, 1.0);
+                }
+            }
+            Some((coding, q))
+        })
+        .collect()
+}
+
+/// Picks the best encoding from `available` for the given `Accept-Encoding` header,
+/// or `None` when the client refuses them all, `identity` included.
+///
+/// `identity` is acceptable unless the header gives it, or `*`, a q of 0.
+pub fn negotiate(header: Option<&str>, available: &[Encoding]) -> Option<Encoding> {
+    let header = match header {
+        Some(header) => header,
+        None => return Some(Encoding::Identity),
+    };
+    let accepted = parse_accept_encoding(header);
+    let qvalue = |encoding: &Encoding| -> Option<f32> {
+        let mut wildcard = None;
+        for (coding, q) in &accepted {
+            if coding == encoding.name() || (*encoding == Encoding::Gzip && coding == "x-gzip") {
+                return Some(*q);
+            } else if coding == "*" {
+                wildcard = Some(*q);
+            }
+        }
+        wildcard
+    };
+    let mut best = None;
+    let mut best_q = 0.0;
+    for encoding in Encoding::PREFERRED.iter() {
+        if !available.contains(encoding) {
+            continue;
+        }
+        let q = qvalue(encoding).unwrap_or(0.0);
+        if q > best_q {
+            best = Some(*encoding);
+            best_q = q;
+        }
+    }
+    // Ties go to the compressed encoding.
+    let identity_q = qvalue(&Encoding::Identity).unwrap_or(1.0);
+    match best {
+        Some(encoding) if best_q >= identity_q => Some(encoding),
+        _ if identity_q > 0.0 => Some(Encoding::Identity),
+        _ => None,
+    }
+}
+
+pub fn compress(
+    data: &[u8],
+    encoding: Encoding,
+    config: &CompressionConfig,
+) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity => Ok(data.to_vec()),
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(config.gzip_level),
+            );
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            {
+                let mut encoder =
+                    brotli::CompressorWriter::new(&mut out, 4096, config.brotli_level, 22);
+                encoder.write_all(data)?;
+            }
+            Ok(out)
+        }
+        Encoding::Zstd => zstd::encode_all(data, config.zstd_level),
+    }
+}
+
+/// The path of a precompressed copy of `file_path`, e.g. `app.js.br`.
+pub fn sidecar_path(file_path: &Path, encoding: Encoding) -> PathBuf {
+    let mut path = file_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(encoding.extension());
+    PathBuf::from(path)
+}
+
+/// Writes `.br`, `.zst` and `.gz` sidecars next to every compressible file under `dir`.
+/// Sidecars that are newer than their source are left alone.
+/// Returns the number of sidecars written.
+#[async_recursion::async_recursion]
+pub async fn compress_directory(dir: PathBuf, config: &Config) -> Result<usize, ServerError> {
+    let mut written = 0;
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let metadata = entry.metadata().await?;
+        if metadata.is_dir() {
+            written += compress_directory(path, config).await?;
+            continue;
+        }
+        let name = path.to_string_lossy();
+        let is_sidecar = Encoding::PREFERRED
+            .iter()
+            .any(|encoding| name.ends_with(&format!(".{}", encoding.extension())));
+        if is_sidecar
+            || (metadata.len() as usize) < config.compression.min_size
+            || !config.compression.is_compressible(config.mimetype(&name))
+        {
+            continue;
+        }
+        let contents = tokio::fs::read(&path).await?;
+        for encoding in Encoding::PREFERRED.iter() {
+            let sidecar = sidecar_path(&path, *encoding);
+            if let Ok(sidecar_metadata) = tokio::fs::metadata(&sidecar).await {
+                if sidecar_metadata.modified()? >= metadata.modified()? {
+                    continue;
+                }
+            }
+            let compressed = compress(&contents, *encoding, &config.compression)?;
+            tokio::fs::write(&sidecar, compressed).await?;
+            written += 1;
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_encoding_parse() {
+        assert_eq!(
+            parse_accept_encoding("gzip, br;q=0.8, *;q=0"),
+            vec![
+                ("gzip".to_owned(), 1.0),
+                ("br".to_owned(), 0.8),
+                ("*".to_owned(), 0.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn accept_encoding_negotiate() {
+        let all = [Encoding::Gzip, Encoding::Brotli, Encoding::Zstd];
+        assert_eq!(negotiate(None, &all), Some(Encoding::Identity));
+        assert_eq!(
+            negotiate(Some("gzip, br, zstd"), &all),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            negotiate(Some("gzip, br;q=0.5"), &all),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(Some("br;q=0, *"), &all), Some(Encoding::Zstd));
+        assert_eq!(
+            negotiate(Some("br"), &[Encoding::Gzip]),
+            Some(Encoding::Identity)
+        );
+        assert_eq!(negotiate(Some("identity"), &all), Some(Encoding::Identity));
+        assert_eq!(
+            negotiate(Some("gzip;q=0.5, identity"), &all),
+            Some(Encoding::Identity)
+        );
+        assert_eq!(negotiate(Some("gzip, identity;q=0"), &[]), None);
+        assert_eq!(negotiate(Some("*;q=0"), &all), None);
+    }
+
+    #[test]
+    fn compressible_types() {
+        let config = CompressionConfig::default();
+        assert!(config.is_compressible("text/html"));
+        assert!(config.is_compressible("application/json; charset=utf-8"));
+        assert!(!config.is_compressible("image/png"));
+    }
+}
@@ -0,0 +1,193 @@
+use crate::files::StaticFiles;
+use crate::http::*;
+use crate::server::ServerError;
+use std::ops::RangeInclusive;
+use toml::Value;
+
+/// Pages served in place of the built-in error pages, from `[error_pages]`.
+///
+/// Keys are a status code like `"404"`, or a range like `"500-599"` or
+/// `"5xx"`; the narrowest one containing a status wins. Values are URL paths
+/// of pages under the resources root, in which `{{status}}`, `{{reason}}`,
+/// `{{path}}` and `{{request_id}}` are replaced.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ErrorPages {
+    pages: Vec<(RangeInclusive<usize>, String)>,
+}
+impl ErrorPages {
+    pub fn from_toml(cfg: &Value) -> Result<ErrorPages, ServerError> {
+        let cfg_pages = match cfg.as_table() {
+            Some(cfg_pages) => cfg_pages,
+            None => return Err(ServerError::ConfigError),
+        };
+        let mut error_pages = ErrorPages::default();
+        for (key, page) in cfg_pages {
+            match (parse_statuses(key), page.as_str()) {
+                (Some(statuses), Some(page)) if page.starts_with('/') => {
+                    error_pages.pages.push((statuses, page.to_owned()))
+                }
+                _ => return Err(ServerError::ConfigError),
+            }
+        }
+        Ok(error_pages)
+    }
+    /// The URL path of the page for a status. Without a configured one,
+    /// `/400.html` and `/404.html` are used, as they always have been.
+    pub fn page(&self, status: usize) -> Option<&str> {
+        let configured = self
+            .pages
+            .iter()
+            .filter(|(range, _)| range.contains(&status))
+            .min_by_key(|(range, _)| range.end() - range.start())
+            .map(|(_, page)| page.as_str());
+        configured.or(match status {
+            400 => Some("/400.html"),
+            404 => Some("/404.html"),
+            _ => None,
+        })
+    }
+}
+
+/// What error pages can say about the request that failed.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ErrorContext {
+    pub path: String,
+    pub request_id: String,
+    /// Whether the client prefers `application/json` to HTML.
+    pub json: bool,
+}
+impl ErrorContext {
+    pub fn new(request: &HttpRequest) -> ErrorContext {
+        let json = request.header("Accept").is_some_and(|accept| {
+            accept_quality(accept, "application/json") > accept_quality(accept, "text/html")
+        });
+        ErrorContext {
+            path: request.path.path().to_owned(),
+            request_id: request_id(request.header("X-Request-Id")),
+            json,
+        }
+    }
+    /// For requests that could not be parsed.
+    pub fn unknown() -> ErrorContext {
+        ErrorContext {
+            request_id: request_id(None),
+            ..ErrorContext::default()
+        }
+    }
+}
+
+/// The request ID a proxy in front assigned, or a new one.
+fn request_id(header: Option<&str>) -> String {
+    match header {
+        Some(id)
+            if !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_graphic()) =>
+        {
+            id.to_owned()
+        }
+        _ => uuid::Uuid::new_v4().simple().to_string(),
+    }
+}
+
+/// Replaces a built-in error page with the configured page for its status, or
+/// with JSON for clients that prefer it. Other responses are left alone, so
+/// handlers' own error bodies are never touched.
+pub(crate) async fn apply(
+    files: &StaticFiles,
+    context: &ErrorContext,
+    response: &mut HttpResponse,
+) {
+    let status = response.status.value;
+    if status < 400 || response.body != crate::error_page(status).as_bytes() {
+        return;
+    }
+    response.set_header("X-Request-Id", &context.request_id);
+    if context.json {
+        let body = serde_json::json!({
+            "status": status,
+            "reason": response.status.reason(),
+            "path": context.path,
+            "request_id": context.request_id,
+        });
+        response.set_header("Content-Type", "application/json");
+        response.set_body(body.to_string().into_bytes());
+        return;
+    }
+    if let Some(page) = files.config.error_pages.page(status) {
+        if let Some(template) = files.error_page(page).await {
+            let body = render(&template, &response.status, context);
+            response.set_header("Content-Type", files.config.mimetype(page));
+            response.set_body(body.into_bytes());
+        }
+    }
+    files.inject_live_reload(response);
+}
+
+fn render(template: &str, status: &HttpStatus, context: &ErrorContext) -> String {
+    template
+        .replace("{{status}}", &status.value.to_string())
+        .replace("{{reason}}", status.reason())
+        .replace("{{path}}", &escape_html(&context.path))
+        .replace("{{request_id}}", &escape_html(&context.request_id))
+}
+
+/// Parses `404`, `500-599` or `5xx`.
+fn parse_statuses(key: &str) -> Option<RangeInclusive<usize>> {
+    let range = if let Some((start, end)) = key.split_once('-') {
+        start.trim().parse().ok()?..=end.trim().parse().ok()?
+    } else if let Some(class) = key.strip_suffix("xx") {
+        let class: usize = class.parse().ok()?;
+        class * 100..=class * 100 + 99
+    } else {
+        let status = key.parse().ok()?;
+        status..=status
+    };
+    match range.start() >= &400 && range.end() <= &599 && !range.is_empty() {
+        true => Some(range),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_pages_choose() {
+        let cfg = "404 = '/errors/missing.html'\n'4xx' = '/errors/client.html'\n'500-599' = '/errors/server.html'";
+        let pages = ErrorPages::from_toml(&cfg.parse::<Value>().unwrap()).unwrap();
+        assert_eq!(pages.page(404), Some("/errors/missing.html"));
+        assert_eq!(pages.page(400), Some("/errors/client.html"));
+        assert_eq!(ErrorPages::default().page(400), Some("/400.html"));
+        assert_eq!(pages.page(403), Some("/errors/client.html"));
+        assert_eq!(pages.page(502), Some("/errors/server.html"));
+        assert!(
+            ErrorPages::from_toml(&"'3xx' = '/redirect.html'".parse::<Value>().unwrap()).is_err()
+        );
+        let context = ErrorContext {
+            path: "/<script>".to_owned(),
+            request_id: "abc".to_owned(),
+            json: false,
+        };
+        assert_eq!(
+            render(
+                "{{status}} {{reason}}: {{path}} ({{request_id}})",
+                &HttpStatus::new(404).unwrap(),
+                &context
+            ),
+            "404 Not Found: /&lt;script&gt; (abc)"
+        );
+    }
+
+    #[test]
+    fn error_context_accept() {
+        let context = |accept: &str| {
+            let head = format!("GET /missing HTTP/1.1\r\nAccept: {}\r\n\r\n", accept);
+            ErrorContext::new(&HttpRequest::parse(&head).unwrap().1)
+        };
+        assert!(context("text/*;q=0.5, application/json").json);
+        assert!(!context("text/*, application/json;q=0.5").json);
+        assert!(context("application/json, text/html;level=1").json);
+        assert!(!context("text/html, application/xhtml+xml, */*;q=0.8").json);
+        assert!(!context("*/*").json);
+    }
+}
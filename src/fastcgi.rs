@@ -0,0 +1,478 @@
+use crate::cgi;
+use crate::files::{error_response, StaticFiles};
+use crate::http::*;
+use crate::server::ServerError;
+use log::*;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use toml::Value;
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u8 = 1;
+const KEEP_CONN: u8 = 1;
+/// Each connection carries one request at a time, so they can all use the same id.
+const REQUEST_ID: u16 = 1;
+/// Idle connections older than this are closed rather than reused, since the
+/// backend may have given up on them.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a FastCGI backend listens.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FastCgiAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// A FastCGI backend such as php-fpm that files with some extensions are
+/// passed to, from a `[[fastcgi]]` table.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FastCgiConfig {
+    /// `host:port`, or `unix:/path/to.sock`.
+    pub address: FastCgiAddress,
+    pub extensions: Vec<String>,
+    /// Only files under this URL path are passed on.
+    pub path: String,
+    /// Seconds to wait for a response before answering with 504.
+    pub timeout: u64,
+    /// Connections kept open to the backend, which is also how many requests
+    /// it gets at once.
+    pub max_connections: usize,
+}
+impl FastCgiConfig {
+    pub fn from_toml(cfg: &Value) -> Result<FastCgiConfig, ServerError> {
+        let mut config = FastCgiConfig {
+            address: match cfg.get("address") {
+                Some(Value::String(address)) => match address.strip_prefix("unix:") {
+                    Some(path) => FastCgiAddress::Unix(PathBuf::from(path)),
+                    None => FastCgiAddress::Tcp(address.clone()),
+                },
+                _ => return Err(ServerError::ConfigError),
+            },
+            extensions: vec!["php".to_owned()],
+            path: "/".to_owned(),
+            timeout: 30,
+            max_connections: 8,
+        };
+        if let Some(Value::Array(extensions)) = cfg.get("extensions") {
+            config.extensions = extensions
+                .iter()
+                .filter_map(|ext| {
+                    ext.as_str()
+                        .map(|ext| ext.trim_start_matches('.').to_owned())
+                })
+                .collect();
+        }
+        if let Some(Value::String(path)) = cfg.get("path") {
+            config.path = path.clone();
+        }
+        if let Some(Value::Integer(timeout)) = cfg.get("timeout") {
+            config.timeout = *timeout as u64;
+        }
+        if let Some(Value::Integer(max_connections)) = cfg.get("max_connections") {
+            config.max_connections = (*max_connections).max(1) as usize;
+        }
+        Ok(config)
+    }
+    /// Splits a URL path into the script it names and the `PATH_INFO` after
+    /// it, as in `/index.php/users/1`.
+    pub fn split<'a>(&self, url_path: &'a str) -> Option<(&'a str, &'a str)> {
+        if !url_path.starts_with(&self.path) {
+            return None;
+        }
+        let mut end = 0;
+        for segment in url_path.split('/') {
+            end += segment.len();
+            let matches = segment.rsplit_once('.').is_some_and(|(name, ext)| {
+                !name.is_empty() && self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext))
+            });
+            if matches {
+                return Some((&url_path[..end], &url_path[end..]));
+            }
+            end += 1;
+        }
+        None
+    }
+}
+
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+/// Why an exchange with the backend failed, and whether it had started answering.
+struct Failure {
+    error: std::io::Error,
+    answered: bool,
+}
+impl From<std::io::Error> for Failure {
+    fn from(error: std::io::Error) -> Failure {
+        Failure {
+            error,
+            answered: false,
+        }
+    }
+}
+
+/// What the backend printed for a request.
+struct Output {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// A client for one backend, keeping connections open between requests.
+pub struct FastCgi {
+    pub config: FastCgiConfig,
+    idle: Mutex<Vec<(Box<dyn Connection>, Instant)>>,
+    connections: Semaphore,
+}
+impl FastCgi {
+    pub fn new(config: FastCgiConfig) -> FastCgi {
+        FastCgi {
+            connections: Semaphore::new(config.max_connections),
+            idle: Mutex::new(vec![]),
+            config,
+        }
+    }
+    /// An idle connection, or a new one if there are none.
+    async fn connect(&self) -> std::io::Result<(Box<dyn Connection>, bool)> {
+        let reused = {
+            let mut idle = self.idle.lock().unwrap();
+            idle.retain(|(_, since)| since.elapsed() < IDLE_TIMEOUT);
+            idle.pop()
+        };
+        if let Some((connection, _)) = reused {
+            return Ok((connection, true));
+        }
+        let connection: Box<dyn Connection> = match &self.config.address {
+            FastCgiAddress::Tcp(address) => Box::new(TcpStream::connect(address).await?),
+            #[cfg(unix)]
+            FastCgiAddress::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+            #[cfg(not(unix))]
+            FastCgiAddress::Unix(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "unix sockets aren't supported here",
+                ))
+            }
+        };
+        Ok((connection, false))
+    }
+    /// Runs a request on the backend, retrying once on a fresh connection if
+    /// a kept-open one turns out to have been closed.
+    async fn respond(
+        &self,
+        params: &[(String, String)],
+        body: &mut RequestBody,
+    ) -> std::io::Result<Output> {
+        let _permit = self
+            .connections
+            .acquire()
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        let length = body.len();
+        let (connection, reused) = self.connect().await?;
+        match self.exchange(connection, params, body).await {
+            Ok(output) => Ok(output),
+            Err(failure) if reused && !failure.answered && body.len() == length => {
+                debug!("Reconnecting to FastCGI backend: {}", failure.error);
+                // The others were likely closed at the same time.
+                self.idle.lock().unwrap().clear();
+                let (connection, _) = self.connect().await?;
+                self.exchange(connection, params, body)
+                    .await
+                    .map_err(|failure| failure.error)
+            }
+            Err(failure) => Err(failure.error),
+        }
+    }
+    /// Sends the params, then streams the body as stdin records while reading
+    /// stdout and stderr records, until the backend ends the request.
+    async fn exchange(
+        &self,
+        mut connection: Box<dyn Connection>,
+        params: &[(String, String)],
+        body: &mut RequestBody,
+    ) -> Result<Output, Failure> {
+        let mut head = record(BEGIN_REQUEST, &[0, RESPONDER, KEEP_CONN, 0, 0, 0, 0, 0]);
+        for chunk in encode_params(params).chunks(u16::MAX as usize) {
+            head.extend(record(PARAMS, chunk));
+        }
+        head.extend(record(PARAMS, &[]));
+        connection.write_all(&head).await?;
+        let (mut reader, mut writer) = tokio::io::split(connection);
+        let (result, sent) = {
+            let send = async {
+                let mut buffer = vec![0; 32 * 1024];
+                loop {
+                    let count = body.read(&mut buffer).await?;
+                    if count == 0 {
+                        break;
+                    }
+                    writer.write_all(&record(STDIN, &buffer[..count])).await?;
+                }
+                writer.write_all(&record(STDIN, &[])).await?;
+                writer.flush().await
+            };
+            let receive = receive(&mut reader);
+            tokio::pin!(send);
+            tokio::pin!(receive);
+            let mut sent = false;
+            // The backend may answer before reading all of the body.
+            let result = loop {
+                tokio::select! {
+                    result = &mut send, if !sent => {
+                        result?;
+                        sent = true;
+                    }
+                    result = &mut receive => break result,
+                }
+            };
+            (result, sent)
+        };
+        let (output, complete) = result?;
+        if sent && complete {
+            let connection = reader.unsplit(writer);
+            self.idle.lock().unwrap().push((connection, Instant::now()));
+        }
+        Ok(output)
+    }
+}
+
+/// Reads records until the end of the request, returning the output and
+/// whether the backend finished it normally.
+async fn receive<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(Output, bool), Failure> {
+    let mut output = Output {
+        stdout: vec![],
+        stderr: vec![],
+    };
+    let mut answered = false;
+    loop {
+        let mut header = [0; 8];
+        if let Err(error) = reader.read_exact(&mut header).await {
+            return Err(Failure { error, answered });
+        }
+        answered = true;
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0; length + header[6] as usize];
+        if let Err(error) = reader.read_exact(&mut content).await {
+            return Err(Failure { error, answered });
+        }
+        content.truncate(length);
+        match header[1] {
+            STDOUT => output.stdout.extend(content),
+            STDERR => output.stderr.extend(content),
+            // The protocol status is 0 when the request completed.
+            END_REQUEST => return Ok((output, content.get(4) == Some(&0))),
+            _ => {}
+        }
+    }
+}
+
+/// Frames content as a record, padded to a multiple of eight bytes.
+fn record(kind: u8, content: &[u8]) -> Vec<u8> {
+    let padding = (8 - content.len() % 8) % 8;
+    let mut out = vec![VERSION, kind];
+    out.extend(REQUEST_ID.to_be_bytes());
+    out.extend((content.len() as u16).to_be_bytes());
+    out.extend([padding as u8, 0]);
+    out.extend(content);
+    out.extend(vec![0; padding]);
+    out
+}
+
+/// Encodes params as name-value pairs, with lengths under 128 in one byte
+/// and longer ones in four.
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    let mut out = vec![];
+    for (name, value) in params {
+        for length in [name.len(), value.len()].iter() {
+            match *length < 128 {
+                true => out.push(*length as u8),
+                false => out.extend((*length as u32 | 0x8000_0000).to_be_bytes()),
+            }
+        }
+        out.extend(name.as_bytes());
+        out.extend(value.as_bytes());
+    }
+    out
+}
+
+/// Passes a request to the backend configured for its script, if there is
+/// one: either the file it names, like `/index.php/users`, or where its
+/// location's `try_files` leads, like a front controller.
+pub(crate) async fn dispatch(
+    files: &StaticFiles,
+    request: &mut HttpRequest,
+) -> Result<Option<HttpResponse>, ServerError> {
+    if files.fastcgi.is_empty() {
+        return Ok(None);
+    }
+    let path = request.path.path().to_owned();
+    let mut found = None;
+    for backend in files.fastcgi.iter() {
+        if let Some((script, path_info)) = backend.config.split(&path) {
+            if files.safe_path(script).is_some_and(|file| file.is_file()) {
+                found = Some((backend, script.to_owned(), path_info.to_owned()));
+                break;
+            }
+        }
+    }
+    if found.is_none() {
+        if let Ok(target) = files.try_files(&path) {
+            found = files.fastcgi.iter().find_map(|backend| {
+                let (script, path_info) = backend.config.split(&target)?;
+                match path_info.is_empty() && files.safe_path(script)?.is_file() {
+                    true => Some((backend, script.to_owned(), String::new())),
+                    false => None,
+                }
+            });
+        }
+    }
+    let (backend, script_name, path_info) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let file_path = match files.safe_path(&script_name) {
+        Some(file_path) => tokio::fs::canonicalize(file_path).await?,
+        None => return Ok(Some(error_response(404))),
+    };
+    let path_info = percent_encoding::percent_decode_str(&path_info)
+        .decode_utf8_lossy()
+        .to_string();
+    let mut params = cgi::environment(&files.config, request, &file_path, &script_name, &path_info);
+    // PHP refuses to run without this when built with force-cgi-redirect.
+    params.push(("REDIRECT_STATUS".to_owned(), "200".to_owned()));
+    let timeout = Duration::from_secs(backend.config.timeout);
+    let output =
+        match tokio::time::timeout(timeout, backend.respond(&params, &mut request.body)).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => {
+                error!("FastCGI backend failed for {}: {}", script_name, e);
+                return Ok(Some(error_response(502)));
+            }
+            Err(_) => {
+                error!("FastCGI backend timed out for {}", script_name);
+                return Ok(Some(error_response(504)));
+            }
+        };
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        if !line.trim().is_empty() {
+            warn!("{}: {}", script_name, line);
+        }
+    }
+    let mut response = match cgi::parse_output(&output.stdout) {
+        Ok(response) => response,
+        Err(reason) => {
+            error!("FastCGI script {} {}", script_name, reason);
+            return Ok(Some(error_response(502)));
+        }
+    };
+    files.inject_live_reload(&mut response);
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn fastcgi_split() {
+        let config = FastCgiConfig::from_toml(
+            &toml::from_str("address = \"unix:/run/php/php-fpm.sock\"").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.address,
+            FastCgiAddress::Unix(PathBuf::from("/run/php/php-fpm.sock"))
+        );
+        assert_eq!(config.split("/index.php"), Some(("/index.php", "")));
+        assert_eq!(
+            config.split("/app/index.php/users/1"),
+            Some(("/app/index.php", "/users/1"))
+        );
+        assert_eq!(config.split("/app/.php"), None);
+        assert_eq!(config.split("/style.css"), None);
+        let long = "x".repeat(200);
+        let encoded = encode_params(&[("A".to_owned(), long.clone())]);
+        assert_eq!(&encoded[..5], &[1, 0x80, 0, 0, 200]);
+        assert_eq!(record(STDIN, b"abc").len(), 16);
+    }
+
+    #[tokio::test]
+    async fn fastcgi_keeps_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // A backend that echoes stdin, and only ever accepts one connection.
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            loop {
+                let mut stdin = vec![];
+                loop {
+                    let mut header = [0; 8];
+                    if socket.read_exact(&mut header).await.is_err() {
+                        return;
+                    }
+                    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+                    let mut content = vec![0; length + header[6] as usize];
+                    socket.read_exact(&mut content).await.unwrap();
+                    content.truncate(length);
+                    if header[1] == STDIN {
+                        if length == 0 {
+                            break;
+                        }
+                        stdin.extend(content);
+                    }
+                }
+                let mut out =
+                    b"Status: 422 Unprocessable Entity\r\nContent-type: text/plain\r\n\r\n"
+                        .to_vec();
+                out.extend(stdin);
+                let mut reply = record(STDOUT, &out);
+                reply.extend(record(STDERR, b"notice"));
+                reply.extend(record(END_REQUEST, &[0; 8]));
+                socket.write_all(&reply).await.unwrap();
+            }
+        });
+        let backend = FastCgi::new(FastCgiConfig {
+            address: FastCgiAddress::Tcp(address),
+            extensions: vec!["php".to_owned()],
+            path: "/".to_owned(),
+            timeout: 5,
+            max_connections: 2,
+        });
+        // The second request's body arrives chunked, as PHP clients often send it.
+        let mut chunked =
+            HttpRequest::parse("POST /index.php HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .unwrap()
+                .1;
+        chunked.body = RequestBody::new(
+            b"6\r\nsecond\r\n0\r\n\r\n".to_vec(),
+            Box::new(tokio::io::empty()),
+            u64::MAX,
+            1024,
+        );
+        chunked.decode_body().await.unwrap();
+        let bodies = vec![
+            ("first", RequestBody::from_bytes(b"first".to_vec())),
+            ("second", chunked.body),
+        ];
+        for (text, mut body) in bodies {
+            let output = backend.respond(&[], &mut body).await.unwrap();
+            let response = cgi::parse_output(&output.stdout).unwrap();
+            assert_eq!(response.status.value, 422);
+            assert_eq!(response.headers["Content-Type"], "text/plain");
+            assert_eq!(response.body, text.as_bytes());
+            assert_eq!(output.stderr, b"notice");
+        }
+        assert_eq!(backend.idle.lock().unwrap().len(), 1);
+    }
+}
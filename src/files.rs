@@ -0,0 +1,858 @@
+use crate::compression::{self, Encoding};
+use crate::fastcgi::{self, FastCgi};
+use crate::http::*;
+use crate::livereload;
+use crate::markdown;
+use crate::multipart::Multipart;
+use crate::router::{allow_header, Handler};
+use crate::server::{Config, ServerError};
+use crate::sourceview;
+use crate::ssi;
+use crate::template;
+use crate::webdav::{self, DavState};
+use async_trait::async_trait;
+use log::*;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Tells apart temporary files written at the same time.
+static PENDING_FILES: AtomicUsize = AtomicUsize::new(0);
+
+#[async_recursion::async_recursion]
+async fn get_files(dir: PathBuf, config: &Config) -> Result<Vec<String>, ServerError> {
+    if !dir.as_path().exists() {
+        return Err(ServerError::FileLoadError);
+    }
+    let mut file_paths = vec![];
+    let mut entries = tokio::fs::read_dir(dir.as_path()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        file_paths.push(entry.path());
+    }
+    let mut recursive_paths = vec![];
+    for path in &file_paths {
+        if path.as_path().is_file() {
+            if let Some(url_path) = config.url_path(path) {
+                recursive_paths.push(url_path);
+            }
+        } else if path.as_path().is_dir() {
+            recursive_paths.append(&mut get_files(path.clone(), config).await?);
+        }
+    }
+    let mut set = HashSet::new();
+    recursive_paths.retain(|x| set.insert(x.clone()));
+    recursive_paths.retain(|x| config.preload.contains(x));
+    Ok(recursive_paths)
+}
+
+/// In-memory copies of the preloaded resources and their compressed variants.
+#[derive(Default)]
+struct ResourceCache {
+    resources: HashMap<String, Vec<u8>>,
+    compressed: HashMap<(String, Encoding), Vec<u8>>,
+}
+
+/// The files a page was rendered from, with their modification times then.
+/// A file that didn't exist has no time, so creating it invalidates the page.
+pub(crate) type Dependencies = Vec<(PathBuf, Option<SystemTime>)>;
+
+/// Rendered pages that don't depend on the request or the time, kept until
+/// any file they were rendered from changes.
+#[derive(Default)]
+pub(crate) struct RenderCache {
+    pages: Mutex<HashMap<String, (Vec<u8>, Dependencies)>>,
+}
+impl RenderCache {
+    pub(crate) async fn get(&self, url_path: &str) -> Option<Vec<u8>> {
+        let (body, dependencies) = self.pages.lock().unwrap().get(url_path).cloned()?;
+        for (file_path, modified) in dependencies {
+            if modified_time(&file_path).await != modified {
+                self.pages.lock().unwrap().remove(url_path);
+                return None;
+            }
+        }
+        Some(body)
+    }
+    pub(crate) fn insert(&self, url_path: &str, body: Vec<u8>, dependencies: Dependencies) {
+        self.pages
+            .lock()
+            .unwrap()
+            .insert(url_path.to_owned(), (body, dependencies));
+    }
+}
+
+pub(crate) async fn modified_time(file_path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(file_path).await.ok()?.modified().ok()
+}
+
+/// Serves files from the resources root, keeping preloaded ones in memory.
+///
+/// Clones share the same cache, so one copy can be mounted on a `Router`
+/// while another is refreshed when files change.
+#[derive(Clone)]
+pub struct StaticFiles {
+    pub(crate) config: Arc<Config>,
+    cache: Arc<RwLock<ResourceCache>>,
+    pub(crate) dav: Arc<DavState>,
+    pub(crate) rendered: Arc<RenderCache>,
+    pub(crate) fastcgi: Arc<Vec<FastCgi>>,
+}
+impl StaticFiles {
+    pub async fn load(config: Arc<Config>) -> Result<StaticFiles, ServerError> {
+        let preloaded = get_files(config.resources.clone(), &config).await?;
+        let files = StaticFiles {
+            fastcgi: Arc::new(config.fastcgi.iter().cloned().map(FastCgi::new).collect()),
+            config,
+            cache: Arc::new(RwLock::new(ResourceCache::default())),
+            dav: Arc::new(DavState::default()),
+            rendered: Arc::new(RenderCache::default()),
+        };
+        for path in preloaded {
+            files.load_resource(&path).await?;
+        }
+        Ok(files)
+    }
+    /// The preloaded contents of a resource, if it is in the cache.
+    pub fn cached(&self, url_path: &str) -> Option<Vec<u8>> {
+        self.cache.read().unwrap().resources.get(url_path).cloned()
+    }
+    /// Reads a preloaded resource into the cache, along with its compressed variants
+    /// so they aren't recompressed per request.
+    async fn load_resource(&self, url_path: &str) -> Result<(), ServerError> {
+        let mut file_path = self.config.resources.clone();
+        file_path.push(&url_path[1..]);
+        let mut contents = vec![];
+        File::open(&file_path)
+            .await?
+            .read_to_end(&mut contents)
+            .await?;
+        let config = &self.config.compression;
+        let mut compressed = vec![];
+        // Rendered pages are compressed per response instead.
+        if config.enabled
+            && !self.is_rendered(url_path)
+            && contents.len() >= config.min_size
+            && config.is_compressible(self.config.mimetype(url_path))
+        {
+            for encoding in Encoding::PREFERRED.iter() {
+                compressed.push((
+                    *encoding,
+                    compression::compress(&contents, *encoding, config)?,
+                ));
+            }
+        }
+        self.evict_resource(url_path);
+        let mut cache = self.cache.write().unwrap();
+        for (encoding, body) in compressed {
+            cache
+                .compressed
+                .insert((url_path.to_owned(), encoding), body);
+        }
+        cache.resources.insert(url_path.to_owned(), contents);
+        Ok(())
+    }
+    fn evict_resource(&self, url_path: &str) {
+        let mut cache = self.cache.write().unwrap();
+        cache.resources.remove(url_path);
+        cache.compressed.retain(|(path, _), _| path != url_path);
+    }
+    /// Brings the cache up to date with files that changed on disk.
+    pub async fn refresh(&self, changed: &[String]) {
+        for url_path in changed {
+            let mut file_path = self.config.resources.clone();
+            file_path.push(&url_path[1..]);
+            if file_path.is_file() && self.config.preload.contains(url_path) {
+                info!("Reloading {}", url_path);
+                if let Err(e) = self.load_resource(url_path).await {
+                    warn!("Could not reload {}: {}", url_path, e.message());
+                    self.evict_resource(url_path);
+                }
+            } else if !file_path.exists() {
+                // A removed directory takes everything cached beneath it with it.
+                let prefix = format!("{}/", url_path);
+                let removed: Vec<String> = self
+                    .cache
+                    .read()
+                    .unwrap()
+                    .resources
+                    .keys()
+                    .filter(|path| *path == url_path || path.starts_with(&prefix))
+                    .cloned()
+                    .collect();
+                for path in removed {
+                    info!("Evicting {}", path);
+                    self.evict_resource(&path);
+                }
+            }
+        }
+    }
+    /// Checks the request method against the location's allowlist, then serves the file.
+    async fn serve(&self, request: &mut HttpRequest) -> Result<HttpResponse, ServerError> {
+        // Scripts are never served as files, and decide for themselves which
+        // methods they accept.
+        if let Some(response) = fastcgi::dispatch(self, request).await? {
+            return Ok(response);
+        }
+        let allowed = self.config.allowed_methods(request.path.path());
+        if !allowed.contains(&request.method) {
+            Ok(HttpResponse::new()
+                .status(405)
+                .header("Content-Type", "text/html")
+                .header("Allow", &allow_header(&allowed))
+                .body(crate::ERROR405.as_bytes().to_vec())
+                .build())
+        } else if request.method == HttpMethod::Options {
+            let mut response = HttpResponse::new()
+                .status(204)
+                .header("Allow", &allow_header(&allowed))
+                .build();
+            if let Some(location) = self.config.location(request.path.path()) {
+                if location.webdav {
+                    response.set_header("DAV", "1, 2");
+                    response.set_header("MS-Author-Via", "DAV");
+                }
+            }
+            Ok(response)
+        } else if let HttpMethod::Get | HttpMethod::Head = request.method {
+            self.serve_file(request).await
+        } else if webdav::is_propfind(&request.method) {
+            webdav::propfind(self, request).await
+        } else {
+            self.write(request).await
+        }
+    }
+    /// Handles PUT, POST, DELETE and WebDAV writes in a writable location,
+    /// for the users it lists.
+    async fn write(&self, request: &mut HttpRequest) -> Result<HttpResponse, ServerError> {
+        let url_path = request.path.path().to_owned();
+        let user = match self
+            .config
+            .location(&url_path)
+            .filter(|location| location.writable)
+            .and_then(|location| request.basic_auth_user(&location.users))
+        {
+            Some(user) => user,
+            None => {
+                return Ok(HttpResponse::new()
+                    .status(401)
+                    .header("Content-Type", "text/html")
+                    .header(
+                        "WWW-Authenticate",
+                        "Basic realm=\"GlassCannon\", charset=\"UTF-8\"",
+                    )
+                    .body(crate::ERROR401.as_bytes().to_vec())
+                    .build())
+            }
+        };
+        let file_path = match self.safe_path(&url_path) {
+            Some(file_path) => file_path,
+            None => return Ok(error_response(403)),
+        };
+        if let HttpMethod::Put | HttpMethod::Post | HttpMethod::Delete = request.method {
+            if self.dav.locked(request, &url_path) {
+                return Ok(error_response(423));
+            }
+        }
+        let response = match request.method {
+            HttpMethod::Put => self.put(request, &url_path, &file_path).await?,
+            HttpMethod::Delete => self.delete(&url_path, &file_path).await?,
+            HttpMethod::Post => self.upload(request, &url_path, &file_path).await?,
+            _ => webdav::handle(self, request, &user, &url_path, &file_path).await?,
+        };
+        info!(
+            "{} {} {} by {}",
+            response.status.value, request.method, url_path, user
+        );
+        Ok(response)
+    }
+    /// Creates or replaces a file with the request body.
+    async fn put(
+        &self,
+        request: &mut HttpRequest,
+        url_path: &str,
+        file_path: &Path,
+    ) -> Result<HttpResponse, ServerError> {
+        if url_path.ends_with('/') || file_path.is_dir() {
+            return Ok(error_response(409));
+        }
+        let dir = file_path.parent().unwrap_or(&self.config.resources);
+        if let Some(status) = self.prepare_dir(dir).await? {
+            return Ok(error_response(status));
+        }
+        // The body's length is known, even for a chunked one once it's decoded.
+        if request.body.len() > request.body.limit() as u64 {
+            return Ok(error_response(413));
+        }
+        let existed = file_path.is_file();
+        let mut pending = PendingFile::create(file_path).await?;
+        if let Err(e) = tokio::io::copy(&mut request.body, &mut pending.file).await {
+            pending.discard().await;
+            return Ok(error_response(BodyError::from(e).status()));
+        }
+        pending.commit(file_path).await?;
+        self.invalidate(url_path, file_path).await;
+        Ok(match existed {
+            true => HttpResponse::new().status(204).build(),
+            false => HttpResponse::new()
+                .status(201)
+                .header("Location", url_path)
+                .build(),
+        })
+    }
+    /// Removes a file. Directories are only removed, with everything in them,
+    /// in WebDAV locations.
+    async fn delete(&self, url_path: &str, file_path: &Path) -> Result<HttpResponse, ServerError> {
+        let webdav = self
+            .config
+            .location(url_path)
+            .is_some_and(|location| location.webdav);
+        if file_path.is_dir() && webdav {
+            tokio::fs::remove_dir_all(file_path).await?;
+        } else if file_path.is_dir() {
+            return Ok(error_response(409));
+        } else if file_path.is_file() {
+            tokio::fs::remove_file(file_path).await?;
+        } else {
+            return Ok(error_response(404));
+        }
+        self.dav.forget(url_path);
+        self.invalidate(url_path, file_path).await;
+        Ok(HttpResponse::new().status(204).build())
+    }
+    /// Saves the file parts of a `multipart/form-data` body into a directory,
+    /// under their own file names. Other form fields are ignored.
+    async fn upload(
+        &self,
+        request: &mut HttpRequest,
+        url_path: &str,
+        dir: &Path,
+    ) -> Result<HttpResponse, ServerError> {
+        if dir.is_file() {
+            return Ok(error_response(409));
+        }
+        if let Some(status) = self.prepare_dir(dir).await? {
+            return Ok(error_response(status));
+        }
+        let mut multipart = match Multipart::from_request(request, self.config.multipart) {
+            Ok(multipart) => multipart,
+            Err(e) => return Ok(error_response(e.status())),
+        };
+        let mut uploaded = vec![];
+        loop {
+            let mut part = match multipart.next_part().await {
+                Ok(Some(part)) => part,
+                Ok(None) => break,
+                Err(e) => return Ok(error_response(e.status())),
+            };
+            let name = match part.filename.as_deref().and_then(upload_name) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            let file_path = dir.join(&name);
+            if file_path.is_dir() {
+                return Ok(error_response(409));
+            }
+            let mut pending = PendingFile::create(&file_path).await?;
+            if let Err(e) = part.copy_to(&mut pending.file).await {
+                pending.discard().await;
+                return Ok(error_response(e.status()));
+            }
+            pending.commit(&file_path).await?;
+            let file_url_path = format!(
+                "{}/{}",
+                url_path.trim_end_matches('/'),
+                encode_path_segment(&name)
+            );
+            self.invalidate(&file_url_path, &file_path).await;
+            uploaded.push(file_url_path);
+        }
+        if uploaded.is_empty() {
+            return Ok(error_response(400));
+        }
+        let mut body = uploaded.join("\n");
+        body.push('\n');
+        Ok(HttpResponse::new()
+            .status(201)
+            .header("Content-Type", "text/plain")
+            .body(body.into_bytes())
+            .build())
+    }
+    /// Maps a URL path to a file under the resources root, refusing hidden names
+    /// and anything that could step outside it.
+    pub(crate) fn safe_path(&self, url_path: &str) -> Option<PathBuf> {
+        let file_path = self.config.file_path(url_path)?;
+        let relative = file_path.strip_prefix(&self.config.resources).ok()?;
+        let hidden = relative
+            .components()
+            .any(|component| component.as_os_str().to_string_lossy().starts_with('.'));
+        match hidden {
+            true => None,
+            false => Some(file_path),
+        }
+    }
+    /// Creates a directory to write into. Returns the status to refuse the write
+    /// with if a file is in the way or a symlink leads outside the resources root.
+    pub(crate) async fn prepare_dir(&self, dir: &Path) -> Result<Option<usize>, ServerError> {
+        if dir.ancestors().any(|ancestor| ancestor.is_file()) {
+            return Ok(Some(409));
+        }
+        tokio::fs::create_dir_all(dir).await?;
+        let root = tokio::fs::canonicalize(&self.config.resources).await?;
+        if tokio::fs::canonicalize(dir).await?.starts_with(root) {
+            Ok(None)
+        } else {
+            Ok(Some(403))
+        }
+    }
+    /// Drops stale precompressed sidecars and cached copies of a written file.
+    pub(crate) async fn invalidate(&self, url_path: &str, file_path: &Path) {
+        for encoding in Encoding::PREFERRED.iter() {
+            let sidecar = compression::sidecar_path(file_path, *encoding);
+            if sidecar.is_file() {
+                if let Err(e) = tokio::fs::remove_file(&sidecar).await {
+                    warn!("Could not remove {}: {}", sidecar.display(), e);
+                }
+            }
+        }
+        // Cached resources are keyed by their names on disk, without escapes.
+        let url_path = percent_encoding::percent_decode_str(url_path).decode_utf8_lossy();
+        self.refresh(&[url_path.to_string()]).await;
+    }
+    /// Whether to show a file as highlighted source: when asked with
+    /// `?view=source`, or by default for code in `view_source` locations.
+    fn view_source(&self, request: &HttpRequest, url_path: &str) -> bool {
+        if !self.config.source_view.enabled {
+            return false;
+        }
+        match request.query("view").as_deref() {
+            Some("source") => true,
+            Some(_) => false,
+            None => {
+                self.config
+                    .location(url_path)
+                    .is_some_and(|location| location.view_source)
+                    && !self.is_rendered(url_path)
+                    && !self.config.mimetype(url_path).starts_with("text/html")
+                    && sourceview::has_syntax(url_path)
+            }
+        }
+    }
+    /// Whether a file is processed per request rather than served as it is.
+    fn is_rendered(&self, url_path: &str) -> bool {
+        self.config.template.applies(url_path)
+            || self.config.markdown.applies(url_path)
+            || self.config.ssi.applies(url_path)
+    }
+    /// Resolves a request against the preload cache and the resources root.
+    async fn serve_file(&self, request: &HttpRequest) -> Result<HttpResponse, ServerError> {
+        let mut response;
+        let url_path = match self.try_files(request.path.path()) {
+            Ok(url_path) => url_path,
+            Err(status) => return Ok(error_response(status)),
+        };
+        let url_path = url_path.as_str();
+        let mut file_path = match self.config.file_path(url_path) {
+            Some(file_path) => file_path,
+            None => return Ok(error_response(404)),
+        };
+        if self.view_source(request, url_path) && file_path.is_file() {
+            if let Some(body) = sourceview::render(self, request, url_path, &file_path).await? {
+                let mut response = HttpResponse::new()
+                    .status(200)
+                    .header("Content-Type", "text/html")
+                    .body(body)
+                    .build();
+                self.compress_response(request, None, &mut response)?;
+                return Ok(response);
+            }
+        }
+        let raw = self.config.markdown.applies(url_path) && markdown::wants_raw(request);
+        let mimetype = match raw {
+            true => "text/markdown; charset=utf-8",
+            false => self.config.mimetype(url_path),
+        };
+        if self.is_rendered(url_path) && !raw && file_path.is_file() {
+            let rendered = if self.config.template.applies(url_path) {
+                template::render(self, request, url_path).await
+            } else if self.config.markdown.applies(url_path) {
+                markdown::render(self, request, url_path, &file_path).await
+            } else {
+                Ok(ssi::render(self, request, url_path, &file_path).await?)
+            };
+            let body = match rendered {
+                Ok(body) => body,
+                Err(e) => {
+                    error!("Could not render {}: {}", url_path, e);
+                    return Ok(error_response(500));
+                }
+            };
+            response = HttpResponse::new().status(200).body(body).build();
+            match self.config.markdown.applies(url_path) {
+                true => response.set_header("Content-Type", "text/html"),
+                false => response.set_header("Content-Type", mimetype),
+            }
+            self.inject_live_reload(&mut response);
+            self.compress_response(request, None, &mut response)?;
+        } else if let Some(contents) = self.cached(url_path) {
+            response = HttpResponse::new().status(200).body(contents).build();
+            response.set_header("Content-Type", mimetype);
+            self.inject_live_reload(&mut response);
+            self.compress_response(request, Some(url_path), &mut response)?;
+        } else if file_path.as_path().exists() {
+            let (encoding, variants) = self.find_precompressed(request, &file_path).await;
+            if encoding != Encoding::Identity {
+                file_path = compression::sidecar_path(&file_path, encoding);
+            }
+            let mut contents = vec![];
+            File::open(file_path)
+                .await?
+                .read_to_end(&mut contents)
+                .await?;
+            response = HttpResponse::new().status(200).body(contents).build();
+            response.set_header("Content-Type", mimetype);
+            if encoding != Encoding::Identity {
+                response.set_header("Content-Encoding", encoding.name());
+            }
+            if variants {
+                response.set_header("Vary", "Accept-Encoding");
+            } else {
+                self.inject_live_reload(&mut response);
+                self.compress_response(request, Some(url_path), &mut response)?;
+            }
+        } else {
+            response = error_response(404);
+        }
+        Ok(response)
+    }
+    /// The path to serve for a request: the first of its location's `try_files`
+    /// that exists, or the request path itself. Fails with the status to answer
+    /// with when nothing matches.
+    pub(crate) fn try_files(&self, url_path: &str) -> Result<String, usize> {
+        let location = match self.config.location(url_path) {
+            Some(location) if !location.try_files.is_empty() => location,
+            _ if url_path == "/" => return Ok("/index.html".to_owned()),
+            _ => return Ok(url_path.to_owned()),
+        };
+        for entry in &location.try_files {
+            if let Some(status) = entry.strip_prefix('=') {
+                return Err(status.parse().unwrap_or(404));
+            }
+            let candidate = entry.replace("$uri", url_path).replace("//", "/");
+            if self.cached(&candidate).is_some()
+                || self
+                    .config
+                    .file_path(&candidate)
+                    .is_some_and(|file_path| file_path.is_file())
+            {
+                return Ok(candidate);
+            }
+        }
+        Err(404)
+    }
+    /// The contents of a configured error page, from the cache or the resources root.
+    pub(crate) async fn error_page(&self, page: &str) -> Option<String> {
+        let contents = match self.cached(page) {
+            Some(contents) => contents,
+            None => tokio::fs::read(self.config.file_path(page)?).await.ok()?,
+        };
+        Some(String::from_utf8_lossy(&contents).to_string())
+    }
+    /// Finds the best precompressed sidecar (`.br`, `.zst`, `.gz`) of a file that the
+    /// client accepts, and whether any sidecars exist at all.
+    async fn find_precompressed(
+        &self,
+        request: &HttpRequest,
+        file_path: &Path,
+    ) -> (Encoding, bool) {
+        // Sidecars would bypass the live-reload script in dev mode.
+        if !self.config.compression.precompressed || self.config.dev {
+            return (Encoding::Identity, false);
+        }
+        let mut available = vec![];
+        for encoding in Encoding::PREFERRED.iter() {
+            if let Ok(metadata) =
+                tokio::fs::metadata(compression::sidecar_path(file_path, *encoding)).await
+            {
+                if metadata.is_file() {
+                    available.push(*encoding);
+                }
+            }
+        }
+        // Refusing every sidecar leaves the choice to `compress_response`.
+        let encoding = compression::negotiate(request.header("Accept-Encoding"), &available)
+            .unwrap_or(Encoding::Identity);
+        (encoding, !available.is_empty())
+    }
+    /// In dev mode, adds the live-reload script to HTML responses.
+    pub(crate) fn inject_live_reload(&self, response: &mut HttpResponse) {
+        if !self.config.dev {
+            return;
+        }
+        if let Some(mime) = response.headers.get("Content-Type") {
+            if mime.starts_with("text/html") {
+                let body = livereload::inject(&response.body);
+                response.set_body(body);
+            }
+        }
+    }
+    /// Compresses a response body according to the request's `Accept-Encoding`.
+    /// `path` is the preloaded file the body is a copy of, to reuse its cached
+    /// variants; generated bodies have none.
+    fn compress_response(
+        &self,
+        request: &HttpRequest,
+        path: Option<&str>,
+        response: &mut HttpResponse,
+    ) -> Result<(), ServerError> {
+        let config = &self.config.compression;
+        let compressible = match response.headers.get("Content-Type") {
+            Some(mime) => config.is_compressible(mime),
+            None => false,
+        };
+        if !config.enabled || !compressible {
+            return Ok(());
+        }
+        response.set_header("Vary", "Accept-Encoding");
+        let accept_encoding = request.header("Accept-Encoding");
+        // Small bodies are sent as they are, unless the client refuses that.
+        if response.body.len() < config.min_size
+            && compression::negotiate(accept_encoding, &[]).is_some()
+        {
+            return Ok(());
+        }
+        let encoding = match compression::negotiate(accept_encoding, &Encoding::PREFERRED) {
+            Some(Encoding::Identity) => return Ok(()),
+            Some(encoding) => encoding,
+            None => {
+                *response = error_response(406);
+                response.set_header("Vary", "Accept-Encoding");
+                return Ok(());
+            }
+        };
+        // The cached variants don't include the live-reload script.
+        let cached = match (self.config.dev, path) {
+            (false, Some(path)) => self
+                .cache
+                .read()
+                .unwrap()
+                .compressed
+                .get(&(path.to_owned(), encoding))
+                .cloned(),
+            _ => None,
+        };
+        let body = match cached {
+            Some(body) => body,
+            None => compression::compress(&response.body, encoding, config)?,
+        };
+        response.set_header("Content-Encoding", encoding.name());
+        response.set_body(body);
+        Ok(())
+    }
+}
+
+/// A file being written next to its final path. It is renamed into place once
+/// complete, so nobody ever reads a partial upload.
+struct PendingFile {
+    temp_path: PathBuf,
+    file: File,
+}
+impl PendingFile {
+    async fn create(file_path: &Path) -> Result<PendingFile, ServerError> {
+        let name = file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let temp_path = file_path.with_file_name(format!(
+            ".{}.{}-{}.tmp",
+            name,
+            std::process::id(),
+            PENDING_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::create(&temp_path).await?;
+        Ok(PendingFile { temp_path, file })
+    }
+    async fn commit(self, file_path: &Path) -> Result<(), ServerError> {
+        self.file.sync_all().await?;
+        drop(self.file);
+        if let Err(e) = tokio::fs::rename(&self.temp_path, file_path).await {
+            let _ = tokio::fs::remove_file(&self.temp_path).await;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    async fn discard(self) {
+        drop(self.file);
+        let _ = tokio::fs::remove_file(&self.temp_path).await;
+    }
+}
+
+/// The last component of an uploaded file's name, unless it is empty or hidden.
+pub(crate) fn upload_name(filename: &str) -> Option<&str> {
+    let name = filename.rsplit(['/', '\\']).next()?;
+    if name.is_empty() || name.starts_with('.') || name.contains('\0') {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+pub(crate) fn error_response(status: usize) -> HttpResponse {
+    HttpResponse::new()
+        .status(status)
+        .header("Content-Type", "text/html")
+        .body(crate::error_page(status).as_bytes().to_vec())
+        .build()
+}
+
+#[async_trait]
+impl Handler for StaticFiles {
+    async fn handle(&self, request: HttpRequest) -> HttpResponse {
+        let mut request = request;
+        match self.serve(&mut request).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Could not serve {}: {}", request.path.path(), e.message());
+                HttpResponse::new()
+                    .status(500)
+                    .header("Content-Type", "text/html")
+                    .body(crate::ERROR500.as_bytes().to_vec())
+                    .build()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Location;
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn try_files_fallback() {
+        let resources = TempDir::new("try-files");
+        for file in ["index.html", "about.html", "docs/index.html"].iter() {
+            resources.write(file, "");
+        }
+        let mut config = resources.config();
+        let mut app = Location::new("/");
+        app.try_files = ["$uri", "$uri.html", "$uri/index.html", "/index.html"]
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+        let mut api = Location::new("/api/");
+        api.try_files = vec!["$uri".to_owned(), "=405".to_owned()];
+        config.locations = vec![app, api];
+        let files = StaticFiles::load(Arc::new(config)).await.unwrap();
+        assert_eq!(files.try_files("/about"), Ok("/about.html".to_owned()));
+        assert_eq!(files.try_files("/docs/"), Ok("/docs/index.html".to_owned()));
+        assert_eq!(
+            files.try_files("/dashboard/settings"),
+            Ok("/index.html".to_owned())
+        );
+        assert_eq!(files.try_files("/api/users"), Err(405));
+    }
+
+    #[tokio::test]
+    async fn source_view_compressed() {
+        let resources = TempDir::new("source-gz");
+        resources.write("app.js", "let x = 1;\n".repeat(200));
+        let mut config = resources.config();
+        config.preload = vec!["/app.js".to_owned()];
+        config
+            .mimetypes
+            .insert("js".to_owned(), "text/javascript".to_owned());
+        let files = StaticFiles::load(Arc::new(config)).await.unwrap();
+        let (_rest, request) =
+            HttpRequest::parse("GET /app.js?view=source HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n")
+                .unwrap();
+        let response = files.serve_file(&request).await.unwrap();
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        let mut html = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(&response.body[..]),
+            &mut html,
+        )
+        .unwrap();
+        assert!(html.contains("<tr id=\"L200\">"));
+    }
+
+    #[tokio::test]
+    async fn method_allowlist() {
+        let resources = TempDir::new("methods");
+        resources.write("feed/atom.xml", "<feed/>");
+        let mut config = resources.config();
+        config.locations =
+            vec![
+                Location::from_toml(&"path = '/feed/'\nmethods = ['GET']".parse().unwrap())
+                    .unwrap(),
+            ];
+        let files = StaticFiles::load(Arc::new(config)).await.unwrap();
+        let request = |head: &str| HttpRequest::parse(head).unwrap().1;
+        let options = files.handle(request("OPTIONS / HTTP/1.1\r\n\r\n")).await;
+        assert_eq!(options.status.value, 204);
+        assert_eq!(options.header("Allow"), Some("GET, HEAD, OPTIONS"));
+        let get = files
+            .handle(request("GET /feed/atom.xml HTTP/1.1\r\n\r\n"))
+            .await;
+        assert_eq!((get.status.value, &get.body[..]), (200, &b"<feed/>"[..]));
+        let head = files
+            .handle(request("HEAD /feed/atom.xml HTTP/1.1\r\n\r\n"))
+            .await;
+        assert_eq!(head.status.value, 405);
+        assert_eq!(head.header("Allow"), Some("GET"));
+        let delete = files.handle(request("DELETE / HTTP/1.1\r\n\r\n")).await;
+        assert_eq!(delete.status.value, 405);
+    }
+
+    #[tokio::test]
+    async fn put_limits() {
+        let resources = TempDir::new("put");
+        let mut config = resources.config();
+        config.locations = vec![Location::from_toml(
+            &"path = '/'\nwritable = true\nusers = { ops = 'secret' }"
+                .parse()
+                .unwrap(),
+        )
+        .unwrap()];
+        let files = StaticFiles::load(Arc::new(config)).await.unwrap();
+        let put = |authorization: &str, body: &[u8], limit| {
+            let mut request = HttpRequest::parse(&format!(
+                "PUT /notes.txt HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\n",
+                authorization
+            ))
+            .unwrap()
+            .1;
+            let length = body.len() as u64;
+            request.body =
+                RequestBody::new(body.to_vec(), Box::new(tokio::io::empty()), length, limit);
+            files.handle(request)
+        };
+        // ops:secre, a prefix of the real password.
+        let wrong = put("b3BzOnNlY3Jl", b"hi", 1024).await;
+        assert_eq!(wrong.status.value, 401);
+        let too_large = put("b3BzOnNlY3JldA==", b"hello world", 8).await;
+        assert_eq!(too_large.status.value, 413);
+        assert!(!resources.join("notes.txt").exists());
+        let created = put("b3BzOnNlY3JldA==", b"hello", 8).await;
+        assert_eq!(created.status.value, 201);
+        assert_eq!(
+            std::fs::read(resources.join("notes.txt")).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn upload_names() {
+        assert_eq!(upload_name("report.pdf"), Some("report.pdf"));
+        assert_eq!(upload_name("C:\\Users\\ci\\build.zip"), Some("build.zip"));
+        assert_eq!(upload_name("../../etc/passwd"), Some("passwd"));
+        assert_eq!(upload_name("dist/"), None);
+        assert_eq!(upload_name(".."), None);
+        assert_eq!(upload_name(".env"), None);
+    }
+}
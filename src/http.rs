@@ -1,8 +1,87 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, ReadBuf, Take};
+use tokio::net::TcpStream;
 use url::Url;
 
+/// Characters that can't appear as-is in a URL path segment.
+const PATH_SEGMENT: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Escapes text for HTML and XML content and attribute values.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Percent-encodes a file name for use in a URL path.
+pub fn encode_path_segment(segment: &str) -> String {
+    percent_encoding::utf8_percent_encode(segment, PATH_SEGMENT).to_string()
+}
+
+/// The quality an `Accept` header gives a media type like `application/json`:
+/// that of the most specific range matching it, as in RFC 9110 section 12.5.1,
+/// or 0 if none does. Ranges with parameters other than `q` only match types
+/// with those parameters, so never a bare type.
+pub fn accept_quality(header: &str, mimetype: &str) -> f32 {
+    let (kind, subtype) = mimetype.split_once('/').unwrap_or((mimetype, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for range in header.split(',') {
+        let mut params = range.split(';');
+        let (range_kind, range_subtype) = match params.next().unwrap_or("").trim().split_once('/') {
+            Some(range) => range,
+            None => continue,
+        };
+        let mut q = 1.0;
+        let mut extra = false;
+        for param in params {
+            match param.split_once('=') {
+                // Anything after q is an extension, not a media type parameter.
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("q") => {
+                    q = value.trim().parse::<f32>().unwrap_or(0.0).clamp(0.0, 1.0);
+                    break;
+                }
+                _ => extra = true,
+            }
+        }
+        let specificity = match (range_kind.trim(), range_subtype.trim()) {
+            ("*", "*") => 1,
+            (range_kind, "*") if range_kind.eq_ignore_ascii_case(kind) => 2,
+            (range_kind, range_subtype)
+                if range_kind.eq_ignore_ascii_case(kind)
+                    && range_subtype.eq_ignore_ascii_case(subtype)
+                    && !extra =>
+            {
+                3
+            }
+            _ => continue,
+        };
+        if best.is_none_or(|(best, _)| specificity > best) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct HttpHeader {
     pub name: String,
@@ -15,7 +94,7 @@ impl HttpHeader {
             value: value.into(),
         }
     }
-    pub fn parse<'a>(src: &'a str) -> nom::IResult<&'a str, HttpHeader> {
+    pub fn parse(src: &str) -> nom::IResult<&str, HttpHeader> {
         match nom::sequence::tuple((
             nom::bytes::complete::take_while1(|c: char| {
                 c.is_alphanumeric() || c == '-' || c == '_'
@@ -60,23 +139,65 @@ pub struct HttpStatus {
 impl HttpStatus {
     pub fn new(value: usize) -> Result<HttpStatus, ()> {
         match value {
-            200 | 301 | 400 | 404 => Ok(HttpStatus { value }),
+            101 | 200 | 201 | 204 | 207 | 301 | 302 | 303 | 304 | 307 | 308 | 400 | 401 | 403
+            | 404 | 405 | 406 | 409 | 412 | 413 | 415 | 423 | 426 | 429 | 500 | 502 | 503 | 504 => {
+                Ok(HttpStatus { value })
+            }
             _ => Err(()),
         }
     }
-    pub fn parse<'a>(_src: &'a str) -> nom::IResult<&'a str, HttpStatus> {
-        unimplemented!()
+    /// Reads a status code from another server, which unlike `new` accepts any
+    /// code from 100 to 599 so it can be passed on as it is.
+    pub fn parse(src: &str) -> nom::IResult<&str, HttpStatus> {
+        let (remaining_src, digits) =
+            nom::bytes::complete::take_while_m_n(3, 3, |c: char| c.is_ascii_digit())(src)?;
+        match digits.parse::<usize>() {
+            Ok(value) if (100..600).contains(&value) => Ok((remaining_src, HttpStatus { value })),
+            _ => Err(nom::Err::Error(nom::error::Error::new(
+                src,
+                nom::error::ErrorKind::Verify,
+            ))),
+        }
     }
-    pub fn emit(&self) -> Vec<u8> {
-        (match self.value {
-            200 => "200 Ok",
-            301 => "301 Moved Permanently",
-            400 => "400 Bad Request",
-            404 => "404 Not Found",
+    /// The reason phrase, like `Not Found`.
+    pub fn reason(&self) -> &'static str {
+        match self.value {
+            101 => "Switching Protocols",
+            200 => "Ok",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            207 => "Multi-Status",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            307 => "Temporary Redirect",
+            304 => "Not Modified",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            406 => "Not Acceptable",
+            409 => "Conflict",
+            410 => "Gone",
+            412 => "Precondition Failed",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            423 => "Locked",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
             _ => "",
-        })
-        .as_bytes()
-        .to_vec()
+        }
+    }
+    pub fn emit(&self) -> Vec<u8> {
+        format!("{} {}", self.value, self.reason()).into_bytes()
     }
 }
 impl Display for HttpStatus {
@@ -85,7 +206,7 @@ impl Display for HttpStatus {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum HttpMethod {
     Get,
     Head,
@@ -96,6 +217,8 @@ pub enum HttpMethod {
     Options,
     Trace,
     Patch,
+    /// Any other method token, such as WebDAV's `PROPFIND`, in upper case.
+    Extension(String),
 }
 impl HttpMethod {
     pub fn new(value: &str) -> Result<HttpMethod, ()> {
@@ -105,36 +228,25 @@ impl HttpMethod {
             Err(())
         }
     }
-    pub fn parse<'a>(src: &'a str) -> nom::IResult<&'a str, HttpMethod> {
-        match nom::branch::alt((
-            nom::bytes::complete::tag_no_case("GET"),
-            nom::bytes::complete::tag_no_case("HEAD"),
-            nom::bytes::complete::tag_no_case("POST"),
-            nom::bytes::complete::tag_no_case("PUT"),
-            nom::bytes::complete::tag_no_case("DELETE"),
-            nom::bytes::complete::tag_no_case("CONNECT"),
-            nom::bytes::complete::tag_no_case("OPTIONS"),
-            nom::bytes::complete::tag_no_case("TRACE"),
-            nom::bytes::complete::tag_no_case("PATCH"),
-        ))(src)
-        {
-            Ok((remaining_src, method)) => match &method.to_ascii_uppercase()[..] {
-                "GET" => Ok((remaining_src, HttpMethod::Get)),
-                "HEAD" => Ok((remaining_src, HttpMethod::Head)),
-                "POST" => Ok((remaining_src, HttpMethod::Post)),
-                "PUT" => Ok((remaining_src, HttpMethod::Put)),
-                "DELETE" => Ok((remaining_src, HttpMethod::Delete)),
-                "CONNECT" => Ok((remaining_src, HttpMethod::Connect)),
-                "OPTIONS" => Ok((remaining_src, HttpMethod::Options)),
-                "TRACE" => Ok((remaining_src, HttpMethod::Trace)),
-                "PATCH" => Ok((remaining_src, HttpMethod::Patch)),
-                _ => Err(nom::Err::Failure(nom::error::Error::new(
-                    src,
-                    nom::error::ErrorKind::Verify,
-                ))),
+    pub fn parse(src: &str) -> nom::IResult<&str, HttpMethod> {
+        let (remaining_src, method) = nom::bytes::complete::take_while1(|c: char| {
+            c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+        })(src)?;
+        Ok((
+            remaining_src,
+            match &method.to_ascii_uppercase()[..] {
+                "GET" => HttpMethod::Get,
+                "HEAD" => HttpMethod::Head,
+                "POST" => HttpMethod::Post,
+                "PUT" => HttpMethod::Put,
+                "DELETE" => HttpMethod::Delete,
+                "CONNECT" => HttpMethod::Connect,
+                "OPTIONS" => HttpMethod::Options,
+                "TRACE" => HttpMethod::Trace,
+                "PATCH" => HttpMethod::Patch,
+                extension => HttpMethod::Extension(extension.to_owned()),
             },
-            Err(e) => Err(e),
-        }
+        ))
     }
     pub fn emit(&self) -> Vec<u8> {
         (match self {
@@ -147,6 +259,7 @@ impl HttpMethod {
             HttpMethod::Options => "OPTIONS",
             HttpMethod::Trace => "TRACE",
             HttpMethod::Patch => "PATCH",
+            HttpMethod::Extension(method) => method,
         })
         .as_bytes()
         .to_vec()
@@ -158,18 +271,241 @@ impl Display for HttpMethod {
     }
 }
 
+/// Why a request body couldn't be read.
+#[derive(Debug)]
+pub enum BodyError {
+    Io(std::io::Error),
+    TooLarge,
+    ContentType,
+    Json(serde_json::Error),
+    /// The body doesn't follow the format its content type promises.
+    Malformed,
+}
+impl BodyError {
+    /// The status to answer the request with.
+    pub fn status(&self) -> usize {
+        match self {
+            BodyError::Io(_) | BodyError::Json(_) | BodyError::Malformed => 400,
+            BodyError::TooLarge => 413,
+            BodyError::ContentType => 415,
+        }
+    }
+}
+impl From<std::io::Error> for BodyError {
+    fn from(error: std::io::Error) -> BodyError {
+        BodyError::Io(error)
+    }
+}
+
+/// A request body, read from the connection as it is consumed.
+///
+/// Implements `AsyncRead`, so large bodies can be streamed with
+/// `tokio::io::copy` instead of being held in memory.
+pub struct RequestBody {
+    buffered: Vec<u8>,
+    position: usize,
+    reader: Option<Take<Box<dyn AsyncRead + Send + Sync + Unpin>>>,
+    limit: usize,
+    timeout: Option<Duration>,
+    idle: Option<Pin<Box<tokio::time::Sleep>>>,
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+impl Default for RequestBody {
+    fn default() -> RequestBody {
+        RequestBody::from_bytes(vec![])
+    }
+}
+impl RequestBody {
+    /// A body of `length` bytes, starting with the bytes already read past the
+    /// headers and continuing from `reader`. `limit` caps `to_vec`.
+    pub fn new(
+        mut buffered: Vec<u8>,
+        reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+        length: u64,
+        limit: usize,
+    ) -> RequestBody {
+        buffered.truncate(length as usize);
+        let rest = length - buffered.len() as u64;
+        RequestBody {
+            buffered,
+            position: 0,
+            reader: Some(reader.take(rest)),
+            limit,
+            timeout: None,
+            idle: None,
+            deadline: None,
+        }
+    }
+    /// Fails reads that wait longer than `timeout` for the client to send more.
+    pub fn read_timeout(mut self, timeout: Duration) -> RequestBody {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Fails reads once `timeout` has passed since now, however steadily the
+    /// client is sending.
+    pub fn total_timeout(mut self, timeout: Duration) -> RequestBody {
+        self.deadline = Some(Box::pin(tokio::time::sleep(timeout)));
+        self
+    }
+    pub fn from_bytes(bytes: Vec<u8>) -> RequestBody {
+        RequestBody {
+            limit: bytes.len(),
+            buffered: bytes,
+            position: 0,
+            reader: None,
+            timeout: None,
+            idle: None,
+            deadline: None,
+        }
+    }
+    /// The most bytes handlers should accept.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+    /// The number of bytes left to read.
+    pub fn len(&self) -> u64 {
+        let unread = (self.buffered.len() - self.position) as u64;
+        unread
+            + self
+                .reader
+                .as_ref()
+                .map(|reader| reader.limit())
+                .unwrap_or(0)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Reads the rest of the body into memory.
+    pub async fn to_vec(&mut self) -> Result<Vec<u8>, BodyError> {
+        if self.len() > self.limit as u64 {
+            return Err(BodyError::TooLarge);
+        }
+        let mut out = Vec::with_capacity(self.len() as usize);
+        self.read_to_end(&mut out).await?;
+        Ok(out)
+    }
+    /// Reads the rest of a `Transfer-Encoding: chunked` body into memory,
+    /// decoded and without its trailers.
+    pub async fn dechunk(&mut self) -> Result<Vec<u8>, BodyError> {
+        let limit = self.limit;
+        let mut reader = tokio::io::BufReader::new(self);
+        let mut out = vec![];
+        let mut line = String::new();
+        loop {
+            line.clear();
+            // Size lines are short; this keeps a client from sending an endless one.
+            (&mut reader).take(1024).read_line(&mut line).await?;
+            if !line.ends_with('\n') {
+                return Err(BodyError::Malformed);
+            }
+            // Chunk extensions, after a `;`, are ignored.
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| BodyError::Malformed)?;
+            if size == 0 {
+                break;
+            }
+            if size > limit - out.len() {
+                return Err(BodyError::TooLarge);
+            }
+            let start = out.len();
+            out.resize(start + size, 0);
+            reader.read_exact(&mut out[start..]).await?;
+            let mut end = [0; 2];
+            reader.read_exact(&mut end).await?;
+            if &end != b"\r\n" {
+                return Err(BodyError::Malformed);
+            }
+        }
+        // Trailers, up to the blank line that ends the body.
+        loop {
+            line.clear();
+            (&mut reader).take(8192).read_line(&mut line).await?;
+            if !line.ends_with('\n') {
+                return Err(BodyError::Malformed);
+            }
+            if line.trim_end().is_empty() {
+                return Ok(out);
+            }
+        }
+    }
+}
+impl AsyncRead for RequestBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let body = &mut *self;
+        if body.position < body.buffered.len() {
+            let count = buf.remaining().min(body.buffered.len() - body.position);
+            buf.put_slice(&body.buffered[body.position..body.position + count]);
+            body.position += count;
+            return Poll::Ready(Ok(()));
+        }
+        let reader = match body.reader.as_mut() {
+            Some(reader) => reader,
+            None => return Poll::Ready(Ok(())),
+        };
+        if let Some(deadline) = body.deadline.as_mut().filter(|_| reader.limit() > 0) {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(std::io::ErrorKind::TimedOut.into()));
+            }
+        }
+        match Pin::new(reader).poll_read(cx, buf) {
+            Poll::Pending => {}
+            ready => {
+                body.idle = None;
+                return ready;
+            }
+        }
+        let timeout = match body.timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+        let idle = body
+            .idle
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match idle.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                body.idle = None;
+                Poll::Ready(Err(std::io::ErrorKind::TimedOut.into()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+impl std::fmt::Debug for RequestBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RequestBody({} bytes)", self.len())
+    }
+}
+impl PartialEq for RequestBody {
+    fn eq(&self, other: &RequestBody) -> bool {
+        self.buffered[self.position..] == other.buffered[other.position..]
+            && self.len() == other.len()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub path: Url,
     pub version: String,
     pub headers: Vec<HttpHeader>,
+    /// Parameters captured from the route pattern, e.g. `id` for `/users/:id`.
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+    /// The address of the client that sent the request, when it came over the network.
+    #[serde(default)]
+    pub remote_addr: Option<SocketAddr>,
+    #[serde(skip)]
+    pub body: RequestBody,
 }
 impl HttpRequest {
     pub fn new(_value: &str) -> Result<HttpRequest, ()> {
         unimplemented!()
     }
-    pub fn parse<'a>(src: &'a str) -> nom::IResult<&'a str, HttpRequest> {
+    pub fn parse(src: &str) -> nom::IResult<&str, HttpRequest> {
         match nom::sequence::tuple((
             HttpMethod::parse,
             nom::character::complete::space0,
@@ -207,6 +543,9 @@ impl HttpRequest {
                             path,
                             version: format!("{}.{}", version0, version1),
                             headers,
+                            params: HashMap::new(),
+                            remote_addr: None,
+                            body: RequestBody::default(),
                         },
                     ))
                 } else {
@@ -219,15 +558,140 @@ impl HttpRequest {
             Err(e) => Err(e),
         }
     }
+    /// A parameter captured from the route pattern.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+    /// Looks up the value of a header by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+    /// Replaces a `Transfer-Encoding: chunked` body with its decoded bytes and
+    /// a `Content-Length`, so handlers can treat it like any other body.
+    pub async fn decode_body(&mut self) -> Result<(), BodyError> {
+        let encoding = match self.header("Transfer-Encoding") {
+            Some(encoding) => encoding.to_ascii_lowercase(),
+            None => return Ok(()),
+        };
+        // Without chunked last, there is no telling where the body ends.
+        if encoding.rsplit(',').next().map(|coding| coding.trim()) != Some("chunked") {
+            return Err(BodyError::Malformed);
+        }
+        let limit = self.body.limit;
+        let body = self.body.dechunk().await?;
+        self.headers.retain(|header| {
+            !header.name.eq_ignore_ascii_case("Transfer-Encoding")
+                && !header.name.eq_ignore_ascii_case("Content-Length")
+        });
+        self.headers
+            .push(HttpHeader::new("Content-Length", &body.len().to_string()));
+        self.body = RequestBody::from_bytes(body);
+        self.body.limit = limit;
+        Ok(())
+    }
+    /// The decoded query string, in order, including repeated names.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        self.path
+            .query_pairs()
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect()
+    }
+    /// The first value of a query parameter.
+    pub fn query(&self, name: &str) -> Option<String> {
+        self.path
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+    /// Every value of a repeated query parameter, like `?tag=a&tag=b`.
+    pub fn query_all(&self, name: &str) -> Vec<String> {
+        self.path
+            .query_pairs()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .collect()
+    }
+    /// The cookies sent in `Cookie` headers.
+    pub fn cookies(&self) -> HashMap<String, String> {
+        self.headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|header| header.value.split(';'))
+            .filter_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                let value = value.trim().trim_matches('"');
+                Some((name.trim().to_owned(), value.to_owned()))
+            })
+            .collect()
+    }
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().remove(name)
+    }
+    /// The user name and password from an `Authorization: Basic` header.
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let decoded = self
+            .header("Authorization")?
+            .strip_prefix("Basic ")
+            .and_then(|encoded| base64::decode(encoded.trim()).ok())?;
+        let credentials = String::from_utf8(decoded).ok()?;
+        let (user, password) = credentials.split_once(':')?;
+        Some((user.to_owned(), password.to_owned()))
+    }
+    /// The user from an `Authorization: Basic` header, if the password is
+    /// theirs in `users`. Passwords are compared in constant time.
+    pub fn basic_auth_user(&self, users: &HashMap<String, String>) -> Option<String> {
+        let (user, password) = self.basic_auth()?;
+        let expected = users.get(&user)?.as_bytes();
+        let difference = expected
+            .iter()
+            .zip(password.as_bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b));
+        match expected.len() == password.len() && difference == 0 {
+            true => Some(user),
+            false => None,
+        }
+    }
+    /// Whether the `Content-Type` is `mimetype`, ignoring parameters like charset.
+    pub fn has_content_type(&self, mimetype: &str) -> bool {
+        match self.header("Content-Type") {
+            Some(value) => value
+                .split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .eq_ignore_ascii_case(mimetype),
+            None => false,
+        }
+    }
+    /// Reads an `application/x-www-form-urlencoded` body, in order, including repeated names.
+    pub async fn form(&mut self) -> Result<Vec<(String, String)>, BodyError> {
+        if !self.has_content_type("application/x-www-form-urlencoded") {
+            return Err(BodyError::ContentType);
+        }
+        let body = self.body.to_vec().await?;
+        Ok(url::form_urlencoded::parse(&body)
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect())
+    }
+    /// Deserializes an `application/json` body.
+    pub async fn json<T: DeserializeOwned>(&mut self) -> Result<T, BodyError> {
+        if !self.has_content_type("application/json") {
+            return Err(BodyError::ContentType);
+        }
+        let body = self.body.to_vec().await?;
+        serde_json::from_slice(&body).map_err(BodyError::Json)
+    }
     pub fn emit(&self) -> Vec<u8> {
-        let mut out = format!(
-            "{} {} HTTP/{}\r\n",
-            self.method,
-            self.path.path(),
-            self.version
-        )
-        .as_bytes()
-        .to_vec();
+        let target = match self.path.query() {
+            Some(query) => format!("{}?{}", self.path.path(), query),
+            None => self.path.path().to_owned(),
+        };
+        let mut out = format!("{} {} HTTP/{}\r\n", self.method, target, self.version)
+            .as_bytes()
+            .to_vec();
         for header in &self.headers {
             out.append(&mut header.emit());
         }
@@ -241,12 +705,199 @@ impl Display for HttpRequest {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie to send in a `Set-Cookie` header.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<i64>,
+    pub expires: Option<chrono::DateTime<chrono::Utc>>,
+    pub same_site: Option<SameSite>,
+    pub secure: bool,
+    pub http_only: bool,
+}
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            same_site: None,
+            secure: false,
+            http_only: false,
+        }
+    }
+    pub fn path(mut self, path: &str) -> Cookie {
+        self.path = Some(path.to_owned());
+        self
+    }
+    pub fn domain(mut self, domain: &str) -> Cookie {
+        self.domain = Some(domain.to_owned());
+        self
+    }
+    /// Seconds until the cookie expires; zero or less deletes it.
+    pub fn max_age(mut self, seconds: i64) -> Cookie {
+        self.max_age = Some(seconds);
+        self
+    }
+    pub fn expires(mut self, expires: chrono::DateTime<chrono::Utc>) -> Cookie {
+        self.expires = Some(expires);
+        self
+    }
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+    /// Reads the value of a `Set-Cookie` header, ignoring attributes it doesn't know.
+    pub fn parse(value: &str) -> Option<Cookie> {
+        let mut parts = value.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let mut cookie = Cookie::new(name, value.trim());
+        for attribute in parts {
+            let (key, value) = match attribute.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => (attribute.trim(), ""),
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "path" => cookie.path = Some(value.to_owned()),
+                "domain" => cookie.domain = Some(value.to_owned()),
+                "max-age" => cookie.max_age = value.parse().ok(),
+                "expires" => {
+                    cookie.expires = chrono::DateTime::parse_from_rfc2822(value)
+                        .ok()
+                        .map(|expires| expires.with_timezone(&chrono::Utc))
+                }
+                "samesite" => {
+                    cookie.same_site = match value.to_ascii_lowercase().as_str() {
+                        "strict" => Some(SameSite::Strict),
+                        "lax" => Some(SameSite::Lax),
+                        "none" => Some(SameSite::None),
+                        _ => None,
+                    }
+                }
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                _ => {}
+            }
+        }
+        Some(cookie)
+    }
+    /// The value of the `Set-Cookie` header for this cookie.
+    pub fn emit(&self) -> String {
+        let mut out = format!("{}={}", self.name, self.value);
+        if let Some(path) = &self.path {
+            out.push_str(&format!("; Path={}", path));
+        }
+        if let Some(domain) = &self.domain {
+            out.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(max_age) = self.max_age {
+            out.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if let Some(expires) = self.expires {
+            out.push_str(&format!(
+                "; Expires={}",
+                expires.format("%a, %d %b %Y %H:%M:%S GMT")
+            ));
+        }
+        if let Some(same_site) = self.same_site {
+            out.push_str(&format!("; SameSite={:?}", same_site));
+        }
+        if self.secure {
+            out.push_str("; Secure");
+        }
+        if self.http_only {
+            out.push_str("; HttpOnly");
+        }
+        out
+    }
+}
+impl Display for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.emit())
+    }
+}
+
+/// A response body that is read from elsewhere, like an upstream server, as it
+/// is sent instead of being held in memory.
+pub struct ResponseStream {
+    pub reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+    /// The number of bytes it has, if known; otherwise it ends the connection.
+    pub length: Option<u64>,
+}
+impl std::fmt::Debug for ResponseStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.length {
+            Some(length) => write!(f, "ResponseStream({} bytes)", length),
+            None => write!(f, "ResponseStream(until closed)"),
+        }
+    }
+}
+impl PartialEq for ResponseStream {
+    /// Streams can't be compared without reading them, so they never are equal.
+    fn eq(&self, _other: &ResponseStream) -> bool {
+        false
+    }
+}
+
+/// A future that uses a connection after it has switched protocols.
+pub type UpgradeFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Takes over the connection once a `101 Switching Protocols` response has
+/// been sent, given the socket and any bytes the client sent after its request.
+pub struct OnUpgrade(pub Box<dyn FnOnce(TcpStream, Vec<u8>) -> UpgradeFuture + Send + Sync>);
+impl std::fmt::Debug for OnUpgrade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OnUpgrade")
+    }
+}
+impl PartialEq for OnUpgrade {
+    fn eq(&self, _other: &OnUpgrade) -> bool {
+        false
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct HttpResponseBuilder {
     pub version: String,
     pub status: HttpStatus,
     pub headers: HashMap<String, String>,
+    pub cookies: Vec<Cookie>,
+    pub set_cookies: Vec<String>,
     pub body: Vec<u8>,
+    #[serde(skip)]
+    pub stream: Option<ResponseStream>,
+    #[serde(skip)]
+    pub upgrade: Option<OnUpgrade>,
+}
+impl Default for HttpResponseBuilder {
+    fn default() -> HttpResponseBuilder {
+        HttpResponseBuilder::new()
+    }
 }
 impl HttpResponseBuilder {
     pub fn new() -> HttpResponseBuilder {
@@ -254,7 +905,11 @@ impl HttpResponseBuilder {
             version: "1.1".to_owned(),
             status: HttpStatus::new(200).unwrap(),
             headers: HashMap::new(),
+            cookies: vec![],
+            set_cookies: vec![],
             body: vec![],
+            stream: None,
+            upgrade: None,
         }
     }
     pub fn version(mut self, version: &str) -> HttpResponseBuilder {
@@ -270,18 +925,61 @@ impl HttpResponseBuilder {
             .insert(String::from(header_name), String::from(header_value));
         self
     }
+    /// Adds a `Set-Cookie` header; unlike `header`, this can be repeated.
+    pub fn cookie(mut self, cookie: Cookie) -> HttpResponseBuilder {
+        self.cookies.push(cookie);
+        self
+    }
     pub fn body(mut self, body: Vec<u8>) -> HttpResponseBuilder {
         self.body = body;
         self
     }
+    /// Sends the body from a reader while the response is written, instead of `body`.
+    pub fn stream(
+        mut self,
+        reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+        length: Option<u64>,
+    ) -> HttpResponseBuilder {
+        self.stream = Some(ResponseStream { reader, length });
+        self
+    }
+    /// Switches protocols: answers with `101 Switching Protocols` and `Upgrade`,
+    /// then passes the connection to `on_upgrade`.
+    pub fn upgrade<F, Fut>(mut self, protocol: &str, on_upgrade: F) -> HttpResponseBuilder
+    where
+        F: FnOnce(TcpStream, Vec<u8>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self = self
+            .status(101)
+            .header("Connection", "Upgrade")
+            .header("Upgrade", protocol);
+        self.upgrade = Some(OnUpgrade(Box::new(move |socket, buffered| {
+            Box::pin(on_upgrade(socket, buffered))
+        })));
+        self
+    }
     pub fn build(mut self) -> HttpResponse {
-        let body_len = self.body.len();
-        self = self.header("Content-Length", &body_len.to_string());
+        let length = match &self.stream {
+            Some(stream) => stream.length,
+            None => Some(self.body.len() as u64),
+        };
+        match length {
+            // Informational responses have no body, nor a length for one.
+            _ if self.status.value < 200 => {}
+            Some(length) => self = self.header("Content-Length", &length.to_string()),
+            // Without a length, the end of the body is when the connection closes.
+            None => self = self.header("Connection", "close"),
+        }
         HttpResponse {
             version: self.version,
             status: self.status,
             headers: self.headers,
+            cookies: self.cookies,
+            set_cookies: self.set_cookies,
             body: self.body,
+            stream: self.stream,
+            upgrade: self.upgrade,
         }
     }
 }
@@ -291,14 +989,78 @@ pub struct HttpResponse {
     pub version: String,
     pub status: HttpStatus,
     pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub cookies: Vec<Cookie>,
+    /// `Set-Cookie` values sent exactly as they were received, such as an
+    /// upstream's, which `Cookie` might not round-trip.
+    #[serde(default)]
+    pub set_cookies: Vec<String>,
     pub body: Vec<u8>,
+    /// A body to send after `body`, as it is read.
+    #[serde(skip)]
+    pub stream: Option<ResponseStream>,
+    /// What takes over the connection after a `101 Switching Protocols`.
+    #[serde(skip)]
+    pub upgrade: Option<OnUpgrade>,
 }
 impl HttpResponse {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new() -> HttpResponseBuilder {
         HttpResponseBuilder::new()
     }
-    pub fn parse<'a>(_src: &'a str) -> nom::IResult<&'a str, HttpResponse> {
-        unimplemented!()
+    /// Parses a status line and headers, leaving the body as the remaining input.
+    /// Repeated headers are joined with commas, except `Set-Cookie`, whose
+    /// values are kept as they are in `set_cookies`.
+    pub fn parse(src: &str) -> nom::IResult<&str, HttpResponse> {
+        let (remaining_src, (_, version0, _, version1, _, status, _, _, _, headers, _)) =
+            nom::sequence::tuple((
+                nom::bytes::complete::tag("HTTP/"),
+                nom::character::complete::digit1,
+                nom::character::complete::char('.'),
+                nom::character::complete::digit1,
+                nom::character::complete::space1,
+                HttpStatus::parse,
+                nom::character::complete::space0,
+                nom::bytes::complete::take_until("\r\n"), // reason
+                nom::character::complete::crlf,
+                nom::multi::many0(HttpHeader::parse),
+                nom::character::complete::crlf,
+            ))(src)?;
+        let mut response = HttpResponse {
+            version: format!("{}.{}", version0, version1),
+            status,
+            headers: HashMap::new(),
+            cookies: vec![],
+            set_cookies: vec![],
+            body: vec![],
+            stream: None,
+            upgrade: None,
+        };
+        for header in headers {
+            if header.name.eq_ignore_ascii_case("Set-Cookie") {
+                response.set_cookies.push(header.value);
+                continue;
+            }
+            let value = match response.header(&header.name) {
+                Some(existing) => format!("{}, {}", existing, header.value),
+                None => header.value,
+            };
+            response.remove_header(&header.name);
+            response.headers.insert(header.name, value);
+        }
+        Ok((remaining_src, response))
+    }
+    /// Looks up the value of a header by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    /// Removes a header by case-insensitive name.
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|header, _| !header.eq_ignore_ascii_case(name));
     }
     pub fn emit(&self) -> Vec<u8> {
         let mut out = format!("HTTP/{} {}\r\n", self.version, self.status)
@@ -307,6 +1069,12 @@ impl HttpResponse {
         for header in &self.headers {
             out.append(&mut HttpHeader::new(header.0, header.1).emit());
         }
+        for cookie in &self.cookies {
+            out.append(&mut HttpHeader::new("Set-Cookie", &cookie.emit()).emit());
+        }
+        for cookie in &self.set_cookies {
+            out.append(&mut HttpHeader::new("Set-Cookie", cookie).emit());
+        }
         out.append(&mut b"\r\n".to_vec());
         out.append(&mut self.body.clone());
         out
@@ -315,6 +1083,14 @@ impl HttpResponse {
         self.headers
             .insert(String::from(header_name), String::from(header_value));
     }
+    pub fn add_cookie(&mut self, cookie: Cookie) {
+        self.cookies.push(cookie);
+    }
+    /// Replaces the body, keeping `Content-Length` in sync.
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.set_header("Content-Length", &body.len().to_string());
+        self.body = body;
+    }
 }
 impl Display for HttpResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -351,8 +1127,14 @@ mod tests {
         assert_eq!(Ok(("", HttpMethod::Get)), HttpMethod::parse(correct1));
         let correct2 = "post";
         assert_eq!(Ok(("", HttpMethod::Post)), HttpMethod::parse(correct2));
-        let correct3 = "puttest";
-        assert_eq!(Ok(("test", HttpMethod::Put)), HttpMethod::parse(correct3));
+        let correct3 = "put test";
+        assert_eq!(Ok((" test", HttpMethod::Put)), HttpMethod::parse(correct3));
+        let extension = "PropFind /";
+        assert_eq!(
+            Ok((" /", HttpMethod::Extension("PROPFIND".to_owned()))),
+            HttpMethod::parse(extension)
+        );
+        assert!(HttpMethod::parse(" GET").is_err());
     }
 
     #[test]
@@ -361,4 +1143,105 @@ mod tests {
             "GET / HTTP/1.1\r\nX-Powered-By: red bull and skittles\r\nserver: GlassCannon\r\n\r\n";
         assert!(HttpMethod::parse(correct).is_ok());
     }
+
+    #[test]
+    fn request_query_and_cookies() {
+        let (_rest, request) = HttpRequest::parse(
+            "GET /search?q=glass+cannon&tag=a&tag=b HTTP/1.1\r\nCookie: session=abc; theme=\"dark\"\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.query("q"), Some("glass cannon".to_owned()));
+        assert_eq!(request.query_all("tag"), vec!["a", "b"]);
+        assert_eq!(request.cookie("session"), Some("abc".to_owned()));
+        assert_eq!(request.cookie("theme"), Some("dark".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn request_form() {
+        let (_rest, mut request) = HttpRequest::parse(
+            "POST /login HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\n",
+        )
+        .unwrap();
+        request.body = RequestBody::from_bytes(b"user=garen&note=hi%21".to_vec());
+        let form = request.form().await.unwrap();
+        assert_eq!(
+            form,
+            vec![
+                ("user".to_owned(), "garen".to_owned()),
+                ("note".to_owned(), "hi!".to_owned())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn request_body_timeout() {
+        // A client that sends part of the body, then stalls.
+        let (client, server) = tokio::io::duplex(64);
+        let mut body = RequestBody::new(b"user=".to_vec(), Box::new(server), 20, 1024)
+            .read_timeout(Duration::from_millis(50));
+        let error = body.to_vec().await.unwrap_err();
+        assert_eq!(error.status(), 400);
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn request_body_chunked() {
+        let decode = |head: &str, body: &[u8], limit| {
+            let mut request = HttpRequest::parse(head).unwrap().1;
+            request.body =
+                RequestBody::new(body.to_vec(), Box::new(tokio::io::empty()), u64::MAX, limit);
+            async move { request.decode_body().await.map(|()| request) }
+        };
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let body = b"5\r\nhello\r\n6;x=y\r\n world\r\n0\r\nTrailer: 1\r\n\r\n";
+        let mut request = decode(chunked, body, 1024).await.unwrap();
+        assert_eq!(request.header("Content-Length"), Some("11"));
+        assert_eq!(request.header("Transfer-Encoding"), None);
+        assert_eq!(request.body.to_vec().await.unwrap(), b"hello world");
+        let error = decode(chunked, body, 8).await.unwrap_err();
+        assert_eq!(error.status(), 413);
+        // Cut off before the last chunk.
+        assert!(decode(chunked, &body[..20], 1024).await.is_err());
+        let gzip = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+        assert_eq!(decode(gzip, b"", 1024).await.unwrap_err().status(), 400);
+        // A slow client can't hold a body open past its total timeout.
+        let (_client, server) = tokio::io::duplex(64);
+        let mut body = RequestBody::new(vec![], Box::new(server), 20, 1024)
+            .total_timeout(Duration::from_millis(50));
+        assert_eq!(body.to_vec().await.unwrap_err().status(), 400);
+    }
+
+    #[test]
+    fn cookie_emit() {
+        let cookie = Cookie::new("session", "abc")
+            .path("/")
+            .max_age(3600)
+            .same_site(SameSite::Lax)
+            .secure(true)
+            .http_only(true);
+        assert_eq!(
+            cookie.emit(),
+            "session=abc; Path=/; Max-Age=3600; SameSite=Lax; Secure; HttpOnly"
+        );
+    }
+
+    #[test]
+    fn response_parse() {
+        let (rest, response) = HttpResponse::parse(
+            "HTTP/1.1 418 I'm a teapot\r\ncontent-type: text/plain\r\nVary: Accept\r\nvary: Origin\r\nSet-Cookie: a=1; Secure; Partitioned\r\nSet-Cookie: b=2; Expires=Thu, 01 Jan 1970 00:00:00 GMT\r\n\r\nshort and stout",
+        )
+        .unwrap();
+        assert_eq!(rest, "short and stout");
+        assert_eq!(response.status.value, 418);
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.header("vary"), Some("Accept, Origin"));
+        assert_eq!(
+            response.set_cookies,
+            vec![
+                "a=1; Secure; Partitioned",
+                "b=2; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+            ]
+        );
+        assert!(HttpResponse::parse("HTTP/1.1 99 Odd\r\n\r\n").is_err());
+    }
 }
@@ -1,17 +1,67 @@
+#![allow(clippy::result_unit_err)]
+
+pub mod cache;
+pub mod cgi;
+pub mod compression;
+pub mod error_pages;
+pub mod fastcgi;
+pub mod files;
 pub mod http;
+pub mod livereload;
+pub mod markdown;
+pub mod middleware;
+pub mod multipart;
+pub mod proxy;
+pub mod rewrite;
+pub mod router;
 pub mod server;
+pub mod sourceview;
+pub mod ssi;
+pub mod template;
+#[cfg(test)]
+mod testing;
+pub mod tus;
+pub mod upstream;
+pub mod watch;
+pub mod webdav;
+pub mod websocket;
 
+pub use files::StaticFiles;
 use log::*;
-pub use server::ServerError;
-use server::{Config, Server};
+pub use router::{Handler, Router};
+pub use server::{Config, Middleware, Next, Server, ServerError};
 
 pub static ERROR400: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>400 Bad Request</title></head><body><h1>400 Bad Request</h1></body></html>";
+pub static ERROR401: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>401 Unauthorized</title></head><body><h1>401 Unauthorized</h1></body></html>";
+pub static ERROR403: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>403 Forbidden</title></head><body><h1>403 Forbidden</h1></body></html>";
 pub static ERROR404: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>404 Not Found</title></head><body><h1>404 Not Found</h1></body></html>";
+pub static ERROR405: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>405 Method Not Allowed</title></head><body><h1>405 Method Not Allowed</h1></body></html>";
+pub static ERROR409: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>409 Conflict</title></head><body><h1>409 Conflict</h1></body></html>";
+pub static ERROR413: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>413 Payload Too Large</title></head><body><h1>413 Payload Too Large</h1></body></html>";
+pub static ERROR415: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>415 Unsupported Media Type</title></head><body><h1>415 Unsupported Media Type</h1></body></html>";
+pub static ERROR429: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>429 Too Many Requests</title></head><body><h1>429 Too Many Requests</h1></body></html>";
 pub static ERROR500: &str = "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>500 Internal Server Error</title></head><body><h1>500 Internal Server Error</h1></body></html>";
 
-pub async fn start() -> Result<Server, ServerError> {
-    let config = Config::from_file("glasscannon.toml").await;
-    // Set up fern logging.
+/// The built-in page for an error status, in the same form as the `ERROR` statics.
+pub(crate) fn error_page(status: usize) -> String {
+    let status =
+        http::HttpStatus::new(status).unwrap_or_else(|_| http::HttpStatus::new(400).unwrap());
+    format!(
+        "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><title>{0}</title></head><body><h1>{0}</h1></body></html>",
+        status
+    )
+}
+
+/// Generates precompressed sidecars for every compressible file under `dir`.
+pub async fn compress(dir: &str) -> Result<usize, ServerError> {
+    let config = Config::from_file("glasscannon.toml").await?;
+    compression::compress_directory(std::path::PathBuf::from(dir), &config).await
+}
+
+/// Logs to stdout and `glasscannon.log`. Debug builds always log everything.
+///
+/// The level can be changed afterwards with `set_log_level`.
+pub fn setup_logging(level: log::LevelFilter) {
     fern::Dispatch::new()
         .format(move |out, message, record| {
             out.finish(format_args!(
@@ -22,18 +72,33 @@ pub async fn start() -> Result<Server, ServerError> {
                 message = message,
             ))
         })
-        .level(if cfg!(debug_assertions) {
-            log::LevelFilter::Trace
-        } else if let Ok(ref config) = config {
-            config.loglevel
-        } else {
-            log::LevelFilter::Info
-        })
+        .level(log::LevelFilter::Trace)
         .chain(std::io::stdout())
         .chain(fern::log_file("glasscannon.log").unwrap())
         .apply()
         .unwrap();
-    if let Ok(config) = config {
+    set_log_level(level);
+}
+
+/// Changes the level set up by `setup_logging`, except in debug builds.
+pub fn set_log_level(level: log::LevelFilter) {
+    if !cfg!(debug_assertions) {
+        log::set_max_level(level);
+    }
+}
+
+/// Starts the server from `glasscannon.toml`, serving the resources root at `/`.
+/// In dev mode, open pages reload when files under the resources root change.
+///
+/// To add your own routes, register handlers on the returned server's `router()`;
+/// the resources root answers whatever they don't.
+pub async fn start(dev: bool) -> Result<Server, ServerError> {
+    // Set up first so problems with the config file are logged.
+    setup_logging(log::LevelFilter::Info);
+    let config = Config::from_file("glasscannon.toml").await;
+    if let Ok(mut config) = config {
+        set_log_level(config.loglevel);
+        config.dev = dev;
         info!(
             "Starting GlassCannon v{} on port {}",
             env!("CARGO_PKG_VERSION"),
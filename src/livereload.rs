@@ -0,0 +1,117 @@
+use log::*;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// The path the injected script listens for change notifications on.
+pub static ENDPOINT: &str = "/__glasscannon/livereload";
+
+/// Reloads the page when a resource changes, or swaps just the stylesheet when
+/// only CSS changed.
+pub static SCRIPT: &str = "<script>(function(){var source=new EventSource(\"/__glasscannon/livereload\");source.addEventListener(\"reload\",function(){location.reload();});source.addEventListener(\"css\",function(e){document.querySelectorAll(\"link[rel=stylesheet]\").forEach(function(link){var url=new URL(link.href);if(url.pathname===e.data){url.searchParams.set(\"glasscannon\",Date.now());link.href=url.href;}});});})();</script>";
+
+/// Inserts the live-reload script before `</body>`, or at the end if there is none.
+pub fn inject(body: &[u8]) -> Vec<u8> {
+    let html = String::from_utf8_lossy(body);
+    match html.rfind("</body>") {
+        Some(index) => format!("{}{}{}", &html[..index], SCRIPT, &html[index..]).into_bytes(),
+        None => format!("{}{}", html, SCRIPT).into_bytes(),
+    }
+}
+
+/// Browser tabs listening for changes over Server-Sent Events.
+#[derive(Default)]
+pub struct LiveReload {
+    clients: Vec<TcpStream>,
+}
+impl LiveReload {
+    pub fn new() -> LiveReload {
+        LiveReload::default()
+    }
+    /// Answers an event stream request and keeps the connection open.
+    pub async fn add_client(&mut self, mut socket: TcpStream) {
+        let headers = b"HTTP/1.1 200 Ok\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nserver: GlassCannon\r\n\r\n";
+        if socket.write_all(headers).await.is_ok() {
+            self.clients.push(socket);
+        }
+    }
+    /// Tells every client which URL paths changed, dropping the ones that went away.
+    pub async fn notify(&mut self, changed: &[String]) {
+        if self.clients.is_empty() {
+            return;
+        }
+        let css_only = changed.iter().all(|path| path.ends_with(".css"));
+        let message: String = if css_only {
+            changed
+                .iter()
+                .map(|path| format!("event: css\ndata: {}\n\n", path))
+                .collect()
+        } else {
+            format!("event: reload\ndata: {}\n\n", changed.join(" "))
+        };
+        debug!("Notifying {} live-reload clients", self.clients.len());
+        let mut connected = vec![];
+        for mut socket in self.clients.drain(..) {
+            let sent =
+                tokio::time::timeout(Duration::from_secs(1), socket.write_all(message.as_bytes()))
+                    .await;
+            if let Ok(Ok(())) = sent {
+                connected.push(socket);
+            }
+        }
+        self.clients = connected;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn live_reload_inject() {
+        let page = inject(b"<html><body><p>hi</p></body></html>");
+        assert_eq!(
+            String::from_utf8(page).unwrap(),
+            format!("<html><body><p>hi</p>{}</body></html>", SCRIPT)
+        );
+        let fragment = inject(b"<p>hi</p>");
+        assert_eq!(
+            String::from_utf8(fragment).unwrap(),
+            format!("<p>hi</p>{}", SCRIPT)
+        );
+    }
+
+    #[tokio::test]
+    async fn live_reload_notify() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut browser = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let gone = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut live_reload = LiveReload::new();
+        for _ in 0..2 {
+            live_reload
+                .add_client(listener.accept().await.unwrap().0)
+                .await;
+        }
+        drop(gone);
+        live_reload.notify(&["/site.css".to_owned()]).await;
+        live_reload
+            .notify(&["/site.css".to_owned(), "/index.html".to_owned()])
+            .await;
+        // Writing to a closed socket only fails once the peer has reset it.
+        live_reload.notify(&["/index.html".to_owned()]).await;
+        assert_eq!(live_reload.clients.len(), 1);
+        drop(live_reload);
+        let mut received = String::new();
+        browser.read_to_string(&mut received).await.unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 Ok\r\nContent-Type: text/event-stream\r\n"));
+        assert!(received.ends_with(
+            "\r\n\r\nevent: css\ndata: /site.css\n\nevent: reload\ndata: /site.css /index.html\n\nevent: reload\ndata: /index.html\n\n"
+        ));
+    }
+}
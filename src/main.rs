@@ -3,18 +3,28 @@ use log::*;
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && args[1] == "compress" {
+        let dir = args.get(2).map(|dir| dir.as_str()).unwrap_or("./res/");
+        match glasscannon::compress(dir).await {
+            Ok(count) => println!("Wrote {} precompressed files in {}", count, dir),
+            Err(e) => {
+                eprintln!("{}", e.message());
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
     match run_server().await {
         Ok(_) => {}
         Err(e) => {
             error!("{}", e.message());
             std::process::exit(1);
-        },
+        }
     }
 }
 
 async fn run_server() -> Result<(), ServerError> {
-    let mut server = glasscannon::start().await?;
-    loop {
-        server.update().await?;
-    }
+    let dev = std::env::args().any(|arg| arg == "--dev");
+    glasscannon::start(dev).await?.run().await
 }
@@ -0,0 +1,223 @@
+use crate::files::{modified_time, StaticFiles};
+use crate::http::*;
+use crate::template;
+use pulldown_cmark::{Event, HeadingLevel, Options, Parser, Tag};
+use std::collections::HashMap;
+use std::path::Path;
+use toml::Value;
+
+/// Markdown files rendered to HTML, from `[markdown]`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MarkdownConfig {
+    pub enabled: bool,
+    pub extensions: Vec<String>,
+    /// The URL path of a template to wrap rendered pages in, which gets
+    /// `{{ title }}`, `{{ content | raw }}` and the front matter as variables.
+    pub template: Option<String>,
+}
+impl Default for MarkdownConfig {
+    fn default() -> MarkdownConfig {
+        MarkdownConfig {
+            enabled: true,
+            extensions: vec!["md".to_owned(), "markdown".to_owned()],
+            template: None,
+        }
+    }
+}
+impl MarkdownConfig {
+    pub fn from_toml(cfg: &Value) -> MarkdownConfig {
+        let mut config = MarkdownConfig::default();
+        if let Some(Value::Boolean(enabled)) = cfg.get("enabled") {
+            config.enabled = *enabled;
+        }
+        if let Some(Value::Array(extensions)) = cfg.get("extensions") {
+            config.extensions = extensions
+                .iter()
+                .filter_map(|ext| {
+                    ext.as_str()
+                        .map(|ext| ext.trim_start_matches('.').to_owned())
+                })
+                .collect();
+        }
+        if let Some(Value::String(template)) = cfg.get("template") {
+            config.template = Some(template.clone());
+        }
+        config
+    }
+    /// Whether the file at a URL path is Markdown.
+    pub fn applies(&self, url_path: &str) -> bool {
+        self.enabled
+            && Path::new(url_path)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+    }
+}
+
+/// Whether a request for a Markdown file asks for its source with `?raw`.
+pub fn wants_raw(request: &HttpRequest) -> bool {
+    request
+        .query_pairs()
+        .iter()
+        .any(|(name, value)| name == "raw" && value != "0" && value != "false")
+}
+
+/// Renders a Markdown file to an HTML page, in its `layout` or the configured
+/// template if there is one.
+pub(crate) async fn render(
+    files: &StaticFiles,
+    request: &HttpRequest,
+    url_path: &str,
+    file_path: &Path,
+) -> Result<Vec<u8>, String> {
+    if let Some(body) = files.rendered.get(url_path).await {
+        return Ok(body);
+    }
+    let modified = modified_time(file_path).await;
+    let source = tokio::fs::read(file_path)
+        .await
+        .map_err(|_| format!("can't read {}", url_path))?;
+    let source = String::from_utf8_lossy(&source);
+    let (mut locals, markdown) = front_matter(&source)?;
+    let (content, heading) = to_html(markdown);
+    if !locals.contains_key("title") {
+        let name = url_path.rsplit('/').next().unwrap_or(url_path);
+        let name = percent_encoding::percent_decode_str(name).decode_utf8_lossy();
+        locals.insert(
+            "title".to_owned(),
+            heading.unwrap_or_else(|| name.to_string()),
+        );
+    }
+    let layout = match locals.get("layout") {
+        Some(layout) => Some(template::resolve(url_path, layout)?),
+        None => files.config.markdown.template.clone(),
+    };
+    let (body, mut dependencies, volatile) = match layout {
+        Some(layout) => {
+            locals.insert("content".to_owned(), content);
+            let rendered = template::render_page(files, request, &layout, locals).await?;
+            (rendered.body, rendered.dependencies, rendered.volatile)
+        }
+        None => {
+            let page = format!(
+                "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>{}</title></head><body>{}</body></html>",
+                escape_html(&locals["title"]),
+                content
+            );
+            (page.into_bytes(), vec![], false)
+        }
+    };
+    if !volatile {
+        dependencies.push((file_path.to_owned(), modified));
+        files.rendered.insert(url_path, body.clone(), dependencies);
+    }
+    Ok(body)
+}
+
+/// Splits off YAML front matter between `---` lines, or TOML between `+++`
+/// lines, keeping its plain values as template variables.
+fn front_matter(source: &str) -> Result<(HashMap<String, String>, &str), String> {
+    let mut locals = HashMap::new();
+    let source = source.strip_prefix('\u{feff}').unwrap_or(source);
+    for fence in ["---", "+++"].iter() {
+        let rest = match source.strip_prefix(fence) {
+            Some(rest) if rest.starts_with('\n') || rest.starts_with("\r\n") => rest,
+            _ => continue,
+        };
+        let closing = format!("\n{}", fence);
+        let end = match rest.find(&closing) {
+            Some(end) => end,
+            None => continue,
+        };
+        let matter = &rest[..end];
+        let body = rest[end + closing.len()..].trim_start_matches(['\r', '\n']);
+        if *fence == "---" {
+            let values: serde_yaml::Mapping = match matter.trim().is_empty() {
+                true => serde_yaml::Mapping::new(),
+                false => {
+                    serde_yaml::from_str(matter).map_err(|e| format!("bad front matter: {}", e))?
+                }
+            };
+            for (key, value) in values {
+                let value = match value {
+                    serde_yaml::Value::String(text) => text,
+                    serde_yaml::Value::Number(number) => number.to_string(),
+                    serde_yaml::Value::Bool(flag) => flag.to_string(),
+                    _ => continue,
+                };
+                if let serde_yaml::Value::String(key) = key {
+                    locals.insert(key, value);
+                }
+            }
+        } else {
+            let values: toml::value::Table =
+                toml::from_str(matter).map_err(|e| format!("bad front matter: {}", e))?;
+            for (key, value) in values {
+                let value = match value {
+                    Value::String(text) => text,
+                    Value::Table(_) | Value::Array(_) => continue,
+                    other => other.to_string(),
+                };
+                locals.insert(key, value);
+            }
+        }
+        return Ok((locals, body));
+    }
+    Ok((locals, source))
+}
+
+/// Renders CommonMark with tables, strikethrough, task lists and footnotes,
+/// returning the HTML and the text of the first top-level heading.
+fn to_html(markdown: &str) -> (String, Option<String>) {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_FOOTNOTES;
+    let events: Vec<Event> = Parser::new_ext(markdown, options).collect();
+    let mut heading: Option<String> = None;
+    let mut in_heading = false;
+    for event in &events {
+        match event {
+            Event::Start(Tag::Heading(HeadingLevel::H1, ..)) if heading.is_none() => {
+                in_heading = true;
+                heading = Some(String::new());
+            }
+            Event::End(Tag::Heading(..)) => in_heading = false,
+            Event::Text(text) | Event::Code(text) if in_heading => {
+                heading.get_or_insert_with(String::new).push_str(text);
+            }
+            _ => {}
+        }
+    }
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events.into_iter());
+    (html, heading)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_front_matter() {
+        let (locals, body) =
+            front_matter("---\ntitle: Runbook\nlayout: /doc.tmpl.html\ntags: [a]\n---\n# Hi")
+                .unwrap();
+        assert_eq!(locals["title"], "Runbook");
+        assert_eq!(locals["layout"], "/doc.tmpl.html");
+        assert!(!locals.contains_key("tags"));
+        assert_eq!(body, "# Hi");
+        let (locals, body) = front_matter("+++\ntitle = \"Notes\"\n+++\ntext").unwrap();
+        assert_eq!(locals["title"], "Notes");
+        assert_eq!(body, "text");
+        assert_eq!(
+            front_matter("--- not front matter").unwrap().1,
+            "--- not front matter"
+        );
+        let (html, heading) =
+            to_html("# Deploy `v2`\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\n```sh\nmake\n```\n");
+        assert_eq!(heading.as_deref(), Some("Deploy v2"));
+        assert!(html.contains("<table>"));
+        assert!(html.contains("<pre><code class=\"language-sh\">make\n</code></pre>"));
+    }
+}
@@ -0,0 +1,366 @@
+use crate::compression::{self, CompressionConfig, Encoding};
+use crate::http::*;
+use crate::server::{Middleware, Next, ServerError};
+use async_trait::async_trait;
+use log::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use toml::Value;
+
+/// A built-in middleware and its settings, from a `[[middleware]]` table.
+#[derive(Debug, PartialEq, Clone)]
+pub enum MiddlewareKind {
+    AccessLog,
+    Headers {
+        set: Vec<(String, String)>,
+        remove: Vec<String>,
+    },
+    BasicAuth {
+        realm: String,
+        users: HashMap<String, String>,
+    },
+    RateLimit {
+        requests: u32,
+        per: Duration,
+    },
+    Compress,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MiddlewareConfig {
+    /// Only requests under this path prefix pass through the middleware.
+    pub path: String,
+    pub kind: MiddlewareKind,
+}
+impl MiddlewareConfig {
+    pub fn new(kind: MiddlewareKind) -> MiddlewareConfig {
+        MiddlewareConfig {
+            path: "/".to_owned(),
+            kind,
+        }
+    }
+    pub fn from_toml(cfg: &Value) -> Result<MiddlewareConfig, ServerError> {
+        let kind = match cfg.get("type").and_then(|kind| kind.as_str()) {
+            Some("log") => MiddlewareKind::AccessLog,
+            Some("headers") => {
+                let mut set = vec![];
+                if let Some(Value::Table(cfg_set)) = cfg.get("set") {
+                    for (name, value) in cfg_set {
+                        match value.as_str() {
+                            Some(value) => set.push((name.clone(), value.to_owned())),
+                            None => return Err(ServerError::ConfigError),
+                        }
+                    }
+                }
+                let mut remove = vec![];
+                if let Some(Value::Array(cfg_remove)) = cfg.get("remove") {
+                    for name in cfg_remove {
+                        match name.as_str() {
+                            Some(name) => remove.push(name.to_owned()),
+                            None => return Err(ServerError::ConfigError),
+                        }
+                    }
+                }
+                MiddlewareKind::Headers { set, remove }
+            }
+            Some("basic_auth") => {
+                let realm = match cfg.get("realm") {
+                    Some(Value::String(realm)) => realm.clone(),
+                    _ => "GlassCannon".to_owned(),
+                };
+                let mut users = HashMap::new();
+                if let Some(Value::Table(cfg_users)) = cfg.get("users") {
+                    for (name, password) in cfg_users {
+                        match password.as_str() {
+                            Some(password) => users.insert(name.clone(), password.to_owned()),
+                            None => return Err(ServerError::ConfigError),
+                        };
+                    }
+                }
+                MiddlewareKind::BasicAuth { realm, users }
+            }
+            Some("rate_limit") => {
+                let requests = match cfg.get("requests") {
+                    Some(Value::Integer(requests)) if *requests > 0 => *requests as u32,
+                    _ => return Err(ServerError::ConfigError),
+                };
+                let per = match cfg.get("per") {
+                    Some(Value::Integer(per)) if *per > 0 => *per as u64,
+                    _ => 60,
+                };
+                MiddlewareKind::RateLimit {
+                    requests,
+                    per: Duration::from_secs(per),
+                }
+            }
+            Some("compress") => MiddlewareKind::Compress,
+            _ => return Err(ServerError::ConfigError),
+        };
+        let mut config = MiddlewareConfig::new(kind);
+        if let Some(Value::String(path)) = cfg.get("path") {
+            config.path = path.clone();
+        }
+        Ok(config)
+    }
+    pub fn build(&self, compression: &CompressionConfig) -> Arc<dyn Middleware> {
+        let middleware: Arc<dyn Middleware> = match &self.kind {
+            MiddlewareKind::AccessLog => Arc::new(AccessLog),
+            MiddlewareKind::Headers { set, remove } => Arc::new(Headers {
+                set: set.clone(),
+                remove: remove.clone(),
+            }),
+            MiddlewareKind::BasicAuth { realm, users } => {
+                Arc::new(BasicAuth::new(realm, users.clone()))
+            }
+            MiddlewareKind::RateLimit { requests, per } => {
+                Arc::new(RateLimit::new(*requests, *per))
+            }
+            MiddlewareKind::Compress => Arc::new(Compress::new(compression.clone())),
+        };
+        if self.path == "/" {
+            middleware
+        } else {
+            Arc::new(Scoped {
+                prefix: self.path.clone(),
+                inner: middleware,
+            })
+        }
+    }
+}
+
+/// Runs `inner` only for requests under `prefix`.
+pub struct Scoped {
+    pub prefix: String,
+    pub inner: Arc<dyn Middleware>,
+}
+#[async_trait]
+impl Middleware for Scoped {
+    async fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse {
+        if request.path.path().starts_with(&self.prefix) {
+            self.inner.handle(request, next).await
+        } else {
+            next.run(request).await
+        }
+    }
+}
+
+/// Logs the status, method and path of every request.
+pub struct AccessLog;
+#[async_trait]
+impl Middleware for AccessLog {
+    async fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse {
+        let method = request.method.clone();
+        let path = request.path.path().to_owned();
+        let response = next.run(request).await;
+        info!("{} {} {}", response.status.value, method, path);
+        response
+    }
+}
+
+/// Sets and removes response headers.
+pub struct Headers {
+    pub set: Vec<(String, String)>,
+    pub remove: Vec<String>,
+}
+#[async_trait]
+impl Middleware for Headers {
+    async fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse {
+        let mut response = next.run(request).await;
+        for name in &self.remove {
+            response
+                .headers
+                .retain(|header, _| !header.eq_ignore_ascii_case(name));
+        }
+        for (name, value) in &self.set {
+            response.set_header(name, value);
+        }
+        response
+    }
+}
+
+/// Requires HTTP Basic credentials matching one of `users`.
+pub struct BasicAuth {
+    realm: String,
+    users: HashMap<String, String>,
+}
+impl BasicAuth {
+    pub fn new(realm: &str, users: HashMap<String, String>) -> BasicAuth {
+        BasicAuth {
+            realm: realm.to_owned(),
+            users,
+        }
+    }
+    pub fn authorized(&self, request: &HttpRequest) -> bool {
+        request.basic_auth_user(&self.users).is_some()
+    }
+}
+#[async_trait]
+impl Middleware for BasicAuth {
+    async fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse {
+        if self.authorized(&request) {
+            next.run(request).await
+        } else {
+            HttpResponse::new()
+                .status(401)
+                .header("Content-Type", "text/html")
+                .header(
+                    "WWW-Authenticate",
+                    &format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+                )
+                .body(crate::ERROR401.as_bytes().to_vec())
+                .build()
+        }
+    }
+}
+
+/// Allows each client address `requests` requests in every window of `per`.
+pub struct RateLimit {
+    requests: u32,
+    per: Duration,
+    clients: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+impl RateLimit {
+    pub fn new(requests: u32, per: Duration) -> RateLimit {
+        RateLimit {
+            requests,
+            per,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+}
+#[async_trait]
+impl Middleware for RateLimit {
+    async fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse {
+        let ip = match request.remote_addr {
+            Some(addr) => addr.ip(),
+            None => return next.run(request).await,
+        };
+        let retry_after = {
+            let now = Instant::now();
+            let mut clients = self.clients.lock().unwrap();
+            clients.retain(|_, (start, _)| now.duration_since(*start) < self.per);
+            let (start, count) = clients.entry(ip).or_insert((now, 0));
+            *count += 1;
+            if *count > self.requests {
+                Some(self.per - now.duration_since(*start))
+            } else {
+                None
+            }
+        };
+        match retry_after {
+            Some(retry_after) => HttpResponse::new()
+                .status(429)
+                .header("Content-Type", "text/html")
+                .header("Retry-After", &retry_after.as_secs().max(1).to_string())
+                .body(crate::ERROR429.as_bytes().to_vec())
+                .build(),
+            None => next.run(request).await,
+        }
+    }
+}
+
+/// Compresses responses that handlers didn't already encode.
+pub struct Compress {
+    config: CompressionConfig,
+}
+impl Compress {
+    pub fn new(config: CompressionConfig) -> Compress {
+        Compress { config }
+    }
+}
+#[async_trait]
+impl Middleware for Compress {
+    async fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse {
+        let accept_encoding = request.header("Accept-Encoding").map(|a| a.to_owned());
+        let mut response = next.run(request).await;
+        let compressible = match response.headers.get("Content-Type") {
+            Some(mime) => self.config.is_compressible(mime),
+            None => false,
+        };
+        if !compressible
+            || response.stream.is_some()
+            || response.headers.contains_key("Content-Encoding")
+        {
+            return response;
+        }
+        response.set_header("Vary", "Accept-Encoding");
+        let accept_encoding = accept_encoding.as_deref();
+        // Small bodies are sent as they are, unless the client refuses that.
+        if response.body.len() < self.config.min_size
+            && compression::negotiate(accept_encoding, &[]).is_some()
+        {
+            return response;
+        }
+        let encoding = match compression::negotiate(accept_encoding, &Encoding::PREFERRED) {
+            Some(encoding) => encoding,
+            None => {
+                let mut refused = crate::files::error_response(406);
+                refused.set_header("Vary", "Accept-Encoding");
+                return refused;
+            }
+        };
+        if encoding != Encoding::Identity {
+            match compression::compress(&response.body, encoding, &self.config) {
+                Ok(body) => {
+                    response.set_header("Content-Encoding", encoding.name());
+                    response.set_body(body);
+                }
+                Err(e) => warn!("Could not compress response: {}", e),
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+
+    #[tokio::test]
+    async fn middleware_chain() {
+        let build = |toml: &str| {
+            MiddlewareConfig::from_toml(&toml.parse::<Value>().unwrap())
+                .unwrap()
+                .build(&CompressionConfig::default())
+        };
+        let middleware = vec![
+            build(
+                "type = 'headers'\nset = { X-Frame-Options = 'DENY' }\nremove = ['X-Powered-By']",
+            ),
+            build("type = 'basic_auth'\npath = '/admin/'\nusers = { ops = 'secret' }"),
+            build("type = 'rate_limit'\nrequests = 2"),
+        ];
+        let mut router = Router::new();
+        router.get("/*", |_request: HttpRequest| async {
+            HttpResponse::new()
+                .status(200)
+                .header("X-Powered-By", "PHP")
+                .build()
+        });
+        let run = |head: &str| {
+            let mut request = HttpRequest::parse(head).unwrap().1;
+            request.remote_addr = Some("192.0.2.1:4000".parse().unwrap());
+            Next::new(&middleware, &router).run(request)
+        };
+        let public = run("GET /blog HTTP/1.1\r\n\r\n").await;
+        assert_eq!(public.status.value, 200);
+        assert_eq!(public.header("X-Frame-Options"), Some("DENY"));
+        assert_eq!(public.header("X-Powered-By"), None);
+        let denied = run("GET /admin/ HTTP/1.1\r\n\r\n").await;
+        assert_eq!(denied.status.value, 401);
+        assert_eq!(denied.header("X-Frame-Options"), Some("DENY"));
+        let admin =
+            run("GET /admin/ HTTP/1.1\r\nAuthorization: Basic b3BzOnNlY3JldA==\r\n\r\n").await;
+        assert_eq!(admin.status.value, 200);
+        // The 401 never reached the limiter, which comes after basic_auth.
+        let limited = run("GET /blog HTTP/1.1\r\n\r\n").await;
+        assert_eq!(limited.status.value, 429);
+        assert!(limited.header("Retry-After").is_some());
+        for toml in ["type = 'rate_limit'", "type = 'gzip'"].iter() {
+            assert!(MiddlewareConfig::from_toml(&toml.parse::<Value>().unwrap()).is_err());
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use crate::http::{BodyError, HttpHeader, HttpRequest, RequestBody};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use toml::Value;
+
+/// Part headers larger than this are treated as malformed.
+const MAX_PART_HEADER_SIZE: usize = 16 * 1024;
+/// How much to read from the body at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MultipartLimits {
+    pub max_parts: usize,
+    pub max_part_size: u64,
+}
+impl Default for MultipartLimits {
+    fn default() -> MultipartLimits {
+        MultipartLimits {
+            max_parts: 128,
+            max_part_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+impl MultipartLimits {
+    pub fn from_toml(cfg: &Value) -> MultipartLimits {
+        let mut limits = MultipartLimits::default();
+        if let Some(Value::Integer(max_parts)) = cfg.get("max_parts") {
+            limits.max_parts = *max_parts as usize;
+        }
+        if let Some(Value::Integer(max_part_size)) = cfg.get("max_part_size") {
+            limits.max_part_size = *max_part_size as u64;
+        }
+        limits
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    /// Before the first boundary, or between a part's content and the next boundary.
+    Boundary,
+    Content,
+    Done,
+}
+
+/// Reads the parts of a `multipart/form-data` body one at a time, without
+/// holding more than a small window of it in memory.
+pub struct Multipart<R> {
+    reader: R,
+    /// `\r\n--boundary`, which ends every part.
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    state: State,
+    eof: bool,
+    parts: usize,
+    limits: MultipartLimits,
+}
+impl<'a> Multipart<&'a mut RequestBody> {
+    /// Reads the body of a request with a `multipart/form-data` content type.
+    pub fn from_request(
+        request: &'a mut HttpRequest,
+        limits: MultipartLimits,
+    ) -> Result<Multipart<&'a mut RequestBody>, BodyError> {
+        if !request.has_content_type("multipart/form-data") {
+            return Err(BodyError::ContentType);
+        }
+        let boundary = request
+            .header("Content-Type")
+            .and_then(|value| parameter(value, "boundary"))
+            .ok_or(BodyError::Malformed)?;
+        Ok(Multipart::new(&mut request.body, &boundary, limits))
+    }
+}
+impl<R: AsyncRead + Unpin> Multipart<R> {
+    pub fn new(reader: R, boundary: &str, limits: MultipartLimits) -> Multipart<R> {
+        Multipart {
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // The first boundary has no line break before it, so pretend it does.
+            buffer: b"\r\n".to_vec(),
+            state: State::Boundary,
+            eof: false,
+            parts: 0,
+            limits,
+        }
+    }
+    /// Moves on to the next part, skipping whatever is left of the current one.
+    pub async fn next_part(&mut self) -> Result<Option<Part<'_, R>>, BodyError> {
+        while self.state == State::Content {
+            self.read_chunk().await?;
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+        // Find the boundary, dropping any preamble before it.
+        let start = loop {
+            if let Some(index) = find(&self.buffer, &self.delimiter) {
+                break index + self.delimiter.len();
+            }
+            let keep = self.delimiter.len().min(self.buffer.len());
+            self.buffer.drain(..self.buffer.len() - keep);
+            if !self.fill().await? {
+                return Err(BodyError::Malformed);
+            }
+        };
+        while self.buffer.len() < start + 2 {
+            if !self.fill().await? {
+                return Err(BodyError::Malformed);
+            }
+        }
+        if &self.buffer[start..start + 2] == b"--" {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        self.buffer.drain(..start);
+        // The rest of the boundary line, then the part's headers.
+        let header_end = loop {
+            if let Some(index) = find(&self.buffer, b"\r\n\r\n") {
+                break index + 4;
+            } else if self.buffer.len() > MAX_PART_HEADER_SIZE || !self.fill().await? {
+                return Err(BodyError::Malformed);
+            }
+        };
+        let line_end = find(&self.buffer, b"\r\n").unwrap_or(0) + 2;
+        let head = String::from_utf8_lossy(&self.buffer[line_end..header_end]).to_string();
+        self.buffer.drain(..header_end);
+        let mut headers = vec![];
+        let mut rest = head.as_str();
+        while let Ok((remaining, header)) = HttpHeader::parse(rest) {
+            headers.push(header);
+            rest = remaining;
+        }
+        self.parts += 1;
+        if self.parts > self.limits.max_parts {
+            return Err(BodyError::TooLarge);
+        }
+        self.state = State::Content;
+        Ok(Some(Part::new(self, headers)))
+    }
+    /// Returns the next piece of the current part's content, or `None` at its end.
+    async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, BodyError> {
+        loop {
+            if let Some(index) = find(&self.buffer, &self.delimiter) {
+                self.state = State::Boundary;
+                if index == 0 {
+                    return Ok(None);
+                }
+                return Ok(Some(self.buffer.drain(..index).collect()));
+            }
+            // Everything except a possible partial delimiter at the end is content.
+            let safe = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                return Ok(Some(self.buffer.drain(..safe).collect()));
+            }
+            if !self.fill().await? {
+                return Err(BodyError::Malformed);
+            }
+        }
+    }
+    /// Reads more of the body into the buffer, returning false at its end.
+    async fn fill(&mut self) -> Result<bool, BodyError> {
+        if self.eof {
+            return Ok(false);
+        }
+        let start = self.buffer.len();
+        self.buffer.resize(start + READ_SIZE, 0);
+        let count = self.reader.read(&mut self.buffer[start..]).await?;
+        self.buffer.truncate(start + count);
+        self.eof = count == 0;
+        Ok(count > 0)
+    }
+}
+
+/// One part of a multipart body. Its content is read in chunks with `chunk`,
+/// or streamed somewhere with `copy_to`.
+pub struct Part<'a, R> {
+    pub headers: Vec<HttpHeader>,
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    multipart: &'a mut Multipart<R>,
+    read: u64,
+}
+impl<'a, R: AsyncRead + Unpin> Part<'a, R> {
+    fn new(multipart: &'a mut Multipart<R>, headers: Vec<HttpHeader>) -> Part<'a, R> {
+        let disposition = headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("Content-Disposition"))
+            .map(|header| header.value.clone())
+            .unwrap_or_default();
+        Part {
+            name: parameter(&disposition, "name"),
+            filename: parameter(&disposition, "filename"),
+            headers,
+            multipart,
+            read: 0,
+        }
+    }
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    }
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+    /// The next piece of content, or `None` once the part is finished.
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>, BodyError> {
+        if self.multipart.state != State::Content {
+            return Ok(None);
+        }
+        let chunk = self.multipart.read_chunk().await?;
+        if let Some(chunk) = &chunk {
+            self.read += chunk.len() as u64;
+            if self.read > self.multipart.limits.max_part_size {
+                return Err(BodyError::TooLarge);
+            }
+        }
+        Ok(chunk)
+    }
+    /// Reads the whole part into memory.
+    pub async fn bytes(&mut self) -> Result<Vec<u8>, BodyError> {
+        let mut out = vec![];
+        while let Some(mut chunk) = self.chunk().await? {
+            out.append(&mut chunk);
+        }
+        Ok(out)
+    }
+    pub async fn text(&mut self) -> Result<String, BodyError> {
+        Ok(String::from_utf8_lossy(&self.bytes().await?).to_string())
+    }
+    /// Streams the rest of the part into `writer`, returning the number of bytes written.
+    pub async fn copy_to<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+    ) -> Result<u64, BodyError> {
+        let mut written = 0;
+        while let Some(chunk) = self.chunk().await? {
+            writer.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+}
+
+/// Finds a `name=value` or `name="value"` parameter in a header value like
+/// `form-data; name="file"; filename="a.txt"`.
+fn parameter(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            Some(value.replace("\\\"", "\""))
+        } else {
+            None
+        }
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\nContent-Type: text/plain\r\n\r\nline one\r\nline two\r\n--XyZ--\r\n";
+
+    #[tokio::test]
+    async fn multipart_parts() {
+        let mut multipart = Multipart::new(BODY, "XyZ", MultipartLimits::default());
+        let mut title = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(title.name.as_deref(), Some("title"));
+        assert_eq!(title.filename, None);
+        assert_eq!(title.text().await.unwrap(), "Hello");
+        let mut file = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(file.filename.as_deref(), Some("a.txt"));
+        assert_eq!(file.content_type(), Some("text/plain"));
+        let mut out = vec![];
+        file.copy_to(&mut out).await.unwrap();
+        assert_eq!(out, b"line one\r\nline two");
+        assert!(multipart.next_part().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn multipart_limits() {
+        let limits = MultipartLimits {
+            max_parts: 1,
+            max_part_size: 1024,
+        };
+        let mut multipart = Multipart::new(BODY, "XyZ", limits);
+        assert!(multipart.next_part().await.unwrap().is_some());
+        assert!(multipart.next_part().await.is_err());
+    }
+}
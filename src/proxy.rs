@@ -0,0 +1,509 @@
+use crate::cache::{self, Cache};
+use crate::files::error_response;
+use crate::http::*;
+use crate::router::Handler;
+use crate::server::ServerError;
+use crate::upstream::{Lease, Upstream};
+use async_trait::async_trait;
+use log::*;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use toml::Value;
+use url::Url;
+
+/// Upstream response headers that don't fit in this many bytes are refused.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+/// Headers that only apply to one connection, so aren't passed on.
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Request headers that are set by the proxy rather than passed on.
+const REPLACED: &[&str] = &[
+    "Host",
+    "Content-Length",
+    "Expect",
+    "Forwarded",
+    "X-Forwarded-For",
+    "X-Forwarded-Host",
+    "X-Forwarded-Proto",
+];
+
+/// A path prefix forwarded to an upstream HTTP server, from a `[[proxy]]` table.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProxyConfig {
+    /// The URL path requests are forwarded from, like `/api/`.
+    pub path: String,
+    /// The upstream's `host:port`.
+    pub address: String,
+    /// The `Host` the upstream is known by, when the request's isn't passed on.
+    pub upstream_host: String,
+    /// A path on the upstream that forwarded paths are put under.
+    pub base: String,
+    /// Whether `path` is taken off the front of forwarded paths.
+    pub strip_prefix: bool,
+    /// The `Host` to send: the upstream's by default, or a fixed name.
+    pub host: Option<String>,
+    /// Send the client's `Host` instead.
+    pub preserve_host: bool,
+    /// Seconds to wait for the upstream to connect and start answering, after
+    /// which the client gets 504.
+    pub timeout: u64,
+    /// The `[[upstream]]` group named by the upstream's host, if there is one,
+    /// whose backends are used instead of `address`.
+    pub pool: Option<String>,
+    /// Whether responses are kept in the `[cache]`.
+    pub cache: bool,
+}
+impl ProxyConfig {
+    pub fn from_toml(cfg: &Value) -> Result<ProxyConfig, ServerError> {
+        let path = match cfg.get("path") {
+            Some(Value::String(path)) if path.starts_with('/') => path.clone(),
+            _ => return Err(ServerError::ConfigError),
+        };
+        let upstream = match cfg.get("upstream").and_then(|upstream| upstream.as_str()) {
+            Some(upstream) => Url::parse(upstream).map_err(|_| ServerError::ConfigError)?,
+            None => return Err(ServerError::ConfigError),
+        };
+        // There is no TLS here, so only plain HTTP upstreams can be reached.
+        let host = match upstream.host_str() {
+            Some(host) if upstream.scheme() == "http" => host.to_owned(),
+            _ => return Err(ServerError::ConfigError),
+        };
+        let port = upstream.port_or_known_default().unwrap_or(80);
+        let mut config = ProxyConfig {
+            path,
+            address: format!(
+                "{}:{}",
+                host.trim_start_matches('[').trim_end_matches(']'),
+                port
+            ),
+            upstream_host: match upstream.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host,
+            },
+            base: upstream.path().trim_end_matches('/').to_owned(),
+            strip_prefix: false,
+            host: None,
+            preserve_host: false,
+            timeout: 30,
+            pool: None,
+            cache: false,
+        };
+        if let Some(Value::Boolean(strip_prefix)) = cfg.get("strip_prefix") {
+            config.strip_prefix = *strip_prefix;
+        }
+        if let Some(Value::String(host)) = cfg.get("host") {
+            config.host = Some(host.clone());
+        }
+        if let Some(Value::Boolean(preserve_host)) = cfg.get("preserve_host") {
+            config.preserve_host = *preserve_host;
+        }
+        if let Some(Value::Integer(timeout)) = cfg.get("timeout") {
+            config.timeout = *timeout as u64;
+        }
+        if let Some(Value::Boolean(cache)) = cfg.get("cache") {
+            config.cache = *cache;
+        }
+        Ok(config)
+    }
+    /// The path and query to request from the upstream.
+    pub fn target(&self, request: &HttpRequest) -> String {
+        let path = request.path.path();
+        let path = match self.strip_prefix {
+            true => path
+                .strip_prefix(self.path.trim_end_matches('/'))
+                .unwrap_or(path),
+            false => path,
+        };
+        let mut target = format!("{}/{}", self.base, path.trim_start_matches('/'));
+        if let Some(query) = request.path.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        target
+    }
+}
+
+/// Forwards requests to an upstream HTTP/1.1 server, streaming bodies both ways.
+#[derive(Clone)]
+pub struct Proxy {
+    config: ProxyConfig,
+    pool: Option<Arc<Upstream>>,
+    cache: Option<Arc<Cache>>,
+}
+impl Proxy {
+    pub fn new(config: ProxyConfig) -> Proxy {
+        Proxy {
+            config,
+            pool: None,
+            cache: None,
+        }
+    }
+    /// Spreads requests over a pool's backends instead of the configured address.
+    pub fn pool(mut self, pool: Arc<Upstream>) -> Proxy {
+        self.pool = Some(pool);
+        self
+    }
+    /// Keeps responses in a cache, and answers from it when it can.
+    pub fn cache(mut self, cache: Arc<Cache>) -> Proxy {
+        self.cache = Some(cache);
+        self
+    }
+    /// Connects to the configured address, or to a backend from the pool,
+    /// trying the others while connecting fails.
+    async fn connect(&self, request: &HttpRequest) -> std::io::Result<(TcpStream, Option<Lease>)> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Ok((TcpStream::connect(&self.config.address).await?, None)),
+        };
+        let mut tried = vec![];
+        let mut last_error = None;
+        while let Some(index) = pool.pick(request, &tried) {
+            tried.push(index);
+            let lease = pool.lease(index);
+            match TcpStream::connect(lease.address()).await {
+                Ok(upstream) => return Ok((upstream, Some(lease))),
+                Err(e) => {
+                    warn!(
+                        "Could not connect to {} in upstream {}: {}",
+                        lease.address(),
+                        pool.name(),
+                        e
+                    );
+                    lease.report(false);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                format!("no backend of {} is available", pool.name()),
+            )
+        }))
+    }
+    /// The request line and headers to send upstream, without hop-by-hop
+    /// headers and with the client's address added to the forwarding ones.
+    fn upstream_head(&self, request: &HttpRequest) -> Vec<u8> {
+        let connection_headers: Vec<String> = request
+            .header("Connection")
+            .unwrap_or("")
+            .split(',')
+            .map(|name| name.trim().to_owned())
+            .collect();
+        let mut headers: Vec<HttpHeader> = request
+            .headers
+            .iter()
+            .filter(|header| {
+                !HOP_BY_HOP
+                    .iter()
+                    .chain(REPLACED.iter())
+                    .map(|name| name.to_string())
+                    .chain(connection_headers.iter().cloned())
+                    .any(|name| name.eq_ignore_ascii_case(&header.name))
+            })
+            .map(|header| HttpHeader::new(&header.name, &header.value))
+            .collect();
+        let client_host = request.header("Host");
+        let host = match (&self.config.host, client_host) {
+            (_, Some(client_host)) if self.config.preserve_host => client_host.to_owned(),
+            (Some(host), _) => host.clone(),
+            _ => self.config.upstream_host.clone(),
+        };
+        headers.insert(0, HttpHeader::new("Host", &host));
+        if let Some(remote_addr) = request.remote_addr {
+            let ip = remote_addr.ip().to_string();
+            let forwarded_for = match request.header("X-Forwarded-For") {
+                Some(earlier) => format!("{}, {}", earlier, ip),
+                None => ip,
+            };
+            headers.push(HttpHeader::new("X-Forwarded-For", &forwarded_for));
+        }
+        headers.push(HttpHeader::new("X-Forwarded-Proto", "http"));
+        if let Some(client_host) = client_host {
+            headers.push(HttpHeader::new("X-Forwarded-Host", client_host));
+        }
+        let mut forwarded = vec![];
+        if let Some(remote_addr) = request.remote_addr {
+            forwarded.push(match remote_addr {
+                std::net::SocketAddr::V4(addr) => format!("for={}", addr.ip()),
+                std::net::SocketAddr::V6(addr) => format!("for=\"[{}]\"", addr.ip()),
+            });
+        }
+        if let Some(client_host) = client_host {
+            forwarded.push(format!("host=\"{}\"", client_host.replace('"', "")));
+        }
+        forwarded.push("proto=http".to_owned());
+        let forwarded = match request.header("Forwarded") {
+            Some(earlier) => format!("{}, {}", earlier, forwarded.join(";")),
+            None => forwarded.join(";"),
+        };
+        headers.push(HttpHeader::new("Forwarded", &forwarded));
+        if !request.body.is_empty() || request.header("Content-Length").is_some() {
+            headers.push(HttpHeader::new(
+                "Content-Length",
+                &request.body.len().to_string(),
+            ));
+        }
+        // One request per connection, so the upstream marks the end of bodies
+        // without a length by closing it.
+        headers.push(HttpHeader::new("Connection", "close"));
+        let mut out = format!(
+            "{} {} HTTP/1.1\r\n",
+            request.method,
+            self.config.target(request)
+        )
+        .into_bytes();
+        for header in &headers {
+            out.extend(header.emit());
+        }
+        out.extend(b"\r\n");
+        out
+    }
+    async fn forward(&self, request: &mut HttpRequest) -> Result<HttpResponse, ServerError> {
+        let head = self.upstream_head(request);
+        let timeout = Duration::from_secs(self.config.timeout);
+        let mut lease = None;
+        let exchange = async {
+            let (mut upstream, leased) = self.connect(request).await?;
+            lease = leased;
+            upstream.write_all(&head).await?;
+            tokio::io::copy(&mut request.body, &mut upstream).await?;
+            read_head(upstream).await
+        };
+        let answer = tokio::time::timeout(timeout, exchange).await;
+        if let Some(lease) = &lease {
+            lease.report(matches!(answer, Ok(Ok(Some(_)))));
+        }
+        let address = match &lease {
+            Some(lease) => lease.address().to_owned(),
+            None => self.config.address.clone(),
+        };
+        let (response, upstream, leftover) = match answer {
+            Ok(Ok(Some(answer))) => answer,
+            Ok(Ok(None)) => {
+                error!(
+                    "Upstream {} sent a malformed response for {}",
+                    address,
+                    request.path.path()
+                );
+                return Ok(error_response(502));
+            }
+            Ok(Err(e)) => {
+                error!(
+                    "Could not reach upstream {} for {}: {}",
+                    address,
+                    request.path.path(),
+                    e
+                );
+                return Ok(error_response(502));
+            }
+            Err(_) => {
+                error!("Upstream {} timed out for {}", address, request.path.path());
+                return Ok(error_response(504));
+            }
+        };
+        Ok(relay(request, response, upstream, leftover, lease))
+    }
+    /// Forwards a request, answering with an error page if that fails.
+    pub(crate) async fn fetch(&self, request: &mut HttpRequest) -> HttpResponse {
+        match self.forward(request).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Could not proxy {}: {}", request.path.path(), e.message());
+                error_response(502)
+            }
+        }
+    }
+}
+
+/// Reads the upstream's response head, skipping interim 1xx responses.
+/// Returns it with the connection and whatever body bytes came with it, or
+/// `None` if it isn't a valid response.
+pub(crate) async fn read_head(
+    mut upstream: TcpStream,
+) -> std::io::Result<Option<(HttpResponse, TcpStream, Vec<u8>)>> {
+    let mut data = Vec::with_capacity(4096);
+    loop {
+        let end = loop {
+            if let Some(index) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+                break index + 4;
+            } else if data.len() > MAX_HEADER_SIZE {
+                return Ok(None);
+            }
+            if upstream.read_buf(&mut data).await? == 0 {
+                return Ok(None);
+            }
+        };
+        let rest = data.split_off(end);
+        let response = match HttpResponse::parse(&String::from_utf8_lossy(&data)) {
+            Ok((_, response)) => response,
+            Err(_) => return Ok(None),
+        };
+        data = rest;
+        if response.status.value >= 200 {
+            return Ok(Some((response, upstream, data)));
+        }
+    }
+}
+
+/// Turns the upstream's response into one for the client, streaming its body.
+fn relay(
+    request: &HttpRequest,
+    mut response: HttpResponse,
+    upstream: TcpStream,
+    leftover: Vec<u8>,
+    lease: Option<Lease>,
+) -> HttpResponse {
+    let connection_headers: Vec<String> = response
+        .header("Connection")
+        .unwrap_or("")
+        .split(',')
+        .map(|name| name.trim().to_owned())
+        .collect();
+    for name in connection_headers.iter() {
+        response.remove_header(name);
+    }
+    let chunked = response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+    for name in HOP_BY_HOP {
+        response.remove_header(name);
+    }
+    let length = response
+        .header("Content-Length")
+        .and_then(|length| length.trim().parse::<u64>().ok());
+    // These never have a body, whatever their headers say.
+    if request.method == HttpMethod::Head || [204, 304].contains(&response.status.value) {
+        return response;
+    }
+    response.remove_header("Content-Length");
+    let body = Leased {
+        reader: std::io::Cursor::new(leftover).chain(upstream),
+        _lease: lease,
+    };
+    let (reader, length): (Box<dyn AsyncRead + Send + Sync + Unpin>, _) = match (chunked, length) {
+        // Chunks are passed through as they are and end with the connection.
+        (true, _) => {
+            response.set_header("Transfer-Encoding", "chunked");
+            (Box::new(body), None)
+        }
+        (false, Some(length)) => (Box::new(body.take(length)), Some(length)),
+        (false, None) => (Box::new(body), None),
+    };
+    // The upstream's status may be one `status()` doesn't accept.
+    let mut builder = HttpResponse::new().stream(reader, length);
+    builder.status = response.status;
+    builder.headers = response.headers;
+    builder.set_cookies = response.set_cookies;
+    builder.build()
+}
+
+/// A response body that keeps its request counted against the backend
+/// until it has been sent.
+struct Leased<R> {
+    reader: R,
+    _lease: Option<Lease>,
+}
+impl<R: AsyncRead + Unpin> AsyncRead for Leased<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+#[async_trait]
+impl Handler for Proxy {
+    async fn handle(&self, request: HttpRequest) -> HttpResponse {
+        let mut request = request;
+        match &self.cache {
+            Some(cache) => cache::respond(cache, self, request).await,
+            None => self.fetch(&mut request).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn config(toml: &str) -> ProxyConfig {
+        ProxyConfig::from_toml(&toml::from_str(toml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn proxy_head() {
+        let proxy = Proxy::new(config(
+            "path = \"/api/\"\nupstream = \"http://127.0.0.1:8080/v1/\"\nstrip_prefix = true",
+        ));
+        let (_, mut request) = HttpRequest::parse(
+            "GET /api/users?page=2 HTTP/1.1\r\nHost: example.com\r\nConnection: keep-alive, X-Secret\r\nX-Secret: 1\r\nX-Forwarded-For: 10.0.0.1\r\nAccept: */*\r\n\r\n",
+        )
+        .unwrap();
+        request.remote_addr = Some("192.168.1.5:4000".parse().unwrap());
+        let head = String::from_utf8(proxy.upstream_head(&request)).unwrap();
+        assert!(head.starts_with("GET /v1/users?page=2 HTTP/1.1\r\nHost: 127.0.0.1:8080\r\n"));
+        assert!(head.contains("\r\nAccept: */*\r\n"));
+        assert!(head.contains("\r\nX-Forwarded-For: 10.0.0.1, 192.168.1.5\r\n"));
+        assert!(head.contains("\r\nX-Forwarded-Host: example.com\r\n"));
+        assert!(head.contains("\r\nForwarded: for=192.168.1.5;host=\"example.com\";proto=http\r\n"));
+        assert!(!head.contains("X-Secret"));
+        assert!(!head.contains("keep-alive"));
+        let unstripped = config("path = \"/api/\"\nupstream = \"http://backend\"");
+        assert_eq!(unstripped.target(&request), "/api/users?page=2");
+        assert_eq!(unstripped.address, "backend:80");
+        assert!(ProxyConfig::from_toml(
+            &toml::from_str("path = \"/\"\nupstream = \"https://backend\"").unwrap()
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_streams_bodies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut data = vec![];
+            while !String::from_utf8_lossy(&data).ends_with("\r\n\r\nping") {
+                socket.read_buf(&mut data).await.unwrap();
+            }
+            socket
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 4\r\nKeep-Alive: timeout=5\r\n\r\npong")
+                .await
+                .unwrap();
+        });
+        let proxy = Proxy::new(config(&format!(
+            "path = \"/\"\nupstream = \"http://{}\"",
+            address
+        )));
+        let (_, mut request) =
+            HttpRequest::parse("POST /echo HTTP/1.1\r\nContent-Length: 4\r\n\r\n").unwrap();
+        request.body = RequestBody::from_bytes(b"ping".to_vec());
+        let mut response = proxy.handle(request).await;
+        assert_eq!(response.status.value, 201);
+        assert_eq!(response.header("Content-Length"), Some("4"));
+        assert_eq!(response.header("Keep-Alive"), None);
+        let mut body = vec![];
+        let stream = response.stream.as_mut().unwrap();
+        stream.reader.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, b"pong");
+    }
+}
@@ -0,0 +1,353 @@
+use crate::files::error_response;
+use crate::http::*;
+use crate::server::ServerError;
+use regex::{Captures, Regex};
+use std::collections::HashSet;
+use toml::Value;
+
+/// How many times rewritten requests are matched again before giving up.
+const MAX_PASSES: usize = 10;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RuleAction {
+    /// Serve another path without telling the client.
+    Rewrite,
+    /// Send the client elsewhere with this status.
+    Redirect(usize),
+}
+
+/// A `[[rewrite]]` or `[[redirect]]` rule.
+///
+/// `path`, `host` and `query` are regular expressions, or globs with
+/// `syntax = "glob"`, where `*` matches within a path segment, `**` matches
+/// across segments and `?` matches one character. Each glob wildcard is a
+/// capture group. In `to`, `$1` or `${1}` is a group from `path`, and
+/// `${name}` is a named group from any of the three.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub action: RuleAction,
+    path: Regex,
+    host: Option<Regex>,
+    query: Option<Regex>,
+    to: String,
+    /// Whether the request's query string is added to a `to` without one.
+    keep_query: bool,
+}
+impl PartialEq for Rule {
+    fn eq(&self, other: &Rule) -> bool {
+        let pattern = |regex: &Option<Regex>| regex.as_ref().map(|r| r.as_str().to_owned());
+        self.action == other.action
+            && self.path.as_str() == other.path.as_str()
+            && pattern(&self.host) == pattern(&other.host)
+            && pattern(&self.query) == pattern(&other.query)
+            && self.to == other.to
+            && self.keep_query == other.keep_query
+    }
+}
+impl Rule {
+    pub fn from_toml(cfg: &Value, redirect: bool) -> Result<Rule, ServerError> {
+        let glob = match cfg.get("syntax").and_then(|syntax| syntax.as_str()) {
+            None | Some("regex") => false,
+            Some("glob") => true,
+            Some(_) => return Err(ServerError::ConfigError),
+        };
+        let pattern = |key: &str| -> Result<Option<Regex>, ServerError> {
+            match cfg.get(key) {
+                Some(Value::String(pattern)) => {
+                    let pattern = match glob {
+                        true => glob_to_regex(pattern),
+                        false => pattern.clone(),
+                    };
+                    Regex::new(&pattern)
+                        .map(Some)
+                        .map_err(|_| ServerError::ConfigError)
+                }
+                None => Ok(None),
+                Some(_) => Err(ServerError::ConfigError),
+            }
+        };
+        let to = match cfg.get("to") {
+            Some(Value::String(to)) => to.clone(),
+            _ => return Err(ServerError::ConfigError),
+        };
+        let action = if redirect {
+            match cfg.get("status") {
+                None => RuleAction::Redirect(301),
+                Some(Value::Integer(status)) if [301, 302, 303, 307, 308].contains(status) => {
+                    RuleAction::Redirect(*status as usize)
+                }
+                _ => return Err(ServerError::ConfigError),
+            }
+        } else if to.starts_with('/') {
+            RuleAction::Rewrite
+        } else {
+            // Only redirects can leave the server.
+            return Err(ServerError::ConfigError);
+        };
+        Ok(Rule {
+            action,
+            path: pattern("path")?.ok_or(ServerError::ConfigError)?,
+            host: pattern("host")?,
+            query: pattern("query")?,
+            to,
+            keep_query: !matches!(cfg.get("keep_query"), Some(Value::Boolean(false))),
+        })
+    }
+    /// The target of the rule for a request, if it matches. A missing host or
+    /// query is assumed to match any condition on it.
+    fn apply(&self, host: Option<&str>, path: &str, query: Option<&str>) -> Option<String> {
+        let path_captures = self.path.captures(path)?;
+        let mut captures = vec![path_captures];
+        for (condition, value) in [(&self.host, host), (&self.query, query)].iter() {
+            if let (Some(condition), Some(value)) = (condition, value) {
+                captures.push(condition.captures(value)?);
+            }
+        }
+        let mut target = expand(&self.to, &captures);
+        if self.keep_query && !target.contains('?') {
+            if let Some(query) = query.filter(|query| !query.is_empty()) {
+                target.push('?');
+                target.push_str(query);
+            }
+        }
+        Some(target)
+    }
+}
+
+/// What the rules make of a request.
+#[derive(Debug, PartialEq)]
+enum Resolution {
+    Unchanged,
+    Rewritten(String),
+    Redirect(usize, String),
+    Loop,
+}
+
+/// Runs a request through the rules. Redirects answer at once; rewrites
+/// replace the path and query and are matched again, so they can chain.
+fn resolve(rules: &[Rule], host: Option<&str>, path: &str, query: Option<&str>) -> Resolution {
+    let mut current: Option<String> = None;
+    for _ in 0..MAX_PASSES {
+        let (path, query) = match &current {
+            Some(target) => match target.split_once('?') {
+                Some((path, query)) => (path, Some(query)),
+                None => (target.as_str(), None),
+            },
+            None => (path, query),
+        };
+        let matched = rules
+            .iter()
+            .find_map(|rule| Some((rule.action, rule.apply(host, path, query)?)));
+        match matched {
+            Some((RuleAction::Redirect(status), target)) => {
+                return Resolution::Redirect(status, target)
+            }
+            Some((RuleAction::Rewrite, target)) => {
+                if current.as_ref() == Some(&target) {
+                    return Resolution::Loop;
+                }
+                current = Some(target);
+            }
+            None => {
+                return match current {
+                    Some(target) => Resolution::Rewritten(target),
+                    None => Resolution::Unchanged,
+                }
+            }
+        }
+    }
+    Resolution::Loop
+}
+
+/// Applies the rules to a request, rewriting its URL in place. Returns the
+/// response for requests the rules answer themselves.
+pub fn apply(rules: &[Rule], request: &mut HttpRequest) -> Option<HttpResponse> {
+    if rules.is_empty() {
+        return None;
+    }
+    let host = request.header("Host").map(|host| host.to_ascii_lowercase());
+    let host = host
+        .as_deref()
+        .map(|host| host.split(':').next().unwrap_or(host));
+    let query = request.path.query().map(str::to_owned);
+    match resolve(rules, host, request.path.path(), query.as_deref()) {
+        Resolution::Unchanged => None,
+        Resolution::Rewritten(target) => match request.path.join(&target) {
+            Ok(url) => {
+                log::debug!("Rewrote {} to {}", request.path.path(), target);
+                request.path = url;
+                None
+            }
+            Err(_) => Some(error_response(500)),
+        },
+        Resolution::Redirect(status, target) => Some(
+            HttpResponse::new()
+                .status(status)
+                .header("Location", &target)
+                .build(),
+        ),
+        Resolution::Loop => {
+            log::error!("Rewrite rules loop on {}", request.path.path());
+            Some(error_response(500))
+        }
+    }
+}
+
+/// Follows each rule's target through the rules, with sample values for its
+/// captures, and reports the first path that leads back to itself.
+pub fn find_loop(rules: &[Rule]) -> Option<Vec<String>> {
+    for rule in rules {
+        for sample in ["x", "1"].iter() {
+            let samples: Vec<String> = (0..10).map(|_| sample.to_string()).collect();
+            let mut target = expand_with(&rule.to, &|_| sample.to_string(), &samples);
+            let mut seen = vec![];
+            let mut visited = HashSet::new();
+            loop {
+                // A target with a scheme or authority is another site.
+                if !target.starts_with('/') || target.starts_with("//") {
+                    break;
+                }
+                if !visited.insert(target.clone()) {
+                    seen.push(target);
+                    return Some(seen);
+                }
+                seen.push(target.clone());
+                let (path, query) = match target.split_once('?') {
+                    Some((path, query)) => (path, Some(query)),
+                    None => (target.as_str(), None),
+                };
+                target = match resolve(rules, None, path, query) {
+                    Resolution::Redirect(_, next) | Resolution::Rewritten(next) => next,
+                    Resolution::Loop => return Some(seen),
+                    Resolution::Unchanged => break,
+                };
+            }
+        }
+    }
+    None
+}
+
+/// Substitutes `$1`, `${1}` and `${name}` in a rule's target.
+fn expand(to: &str, captures: &[Captures]) -> String {
+    let numbered: Vec<String> = (0..captures[0].len())
+        .map(|i| captures[0].get(i).map_or("", |m| m.as_str()).to_owned())
+        .collect();
+    let named = |name: &str| {
+        captures
+            .iter()
+            .find_map(|captures| captures.name(name))
+            .map_or("", |m| m.as_str())
+            .to_owned()
+    };
+    expand_with(to, &named, &numbered)
+}
+
+fn expand_with(to: &str, named: &dyn Fn(&str) -> String, numbered: &[String]) -> String {
+    let group = |name: &str| match name.parse::<usize>() {
+        Ok(index) => numbered.get(index).cloned().unwrap_or_default(),
+        Err(_) => named(name),
+    };
+    let mut out = String::new();
+    let mut rest = to;
+    while let Some(index) = rest.find('$') {
+        out.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+        if let Some(braced) = rest.strip_prefix('{') {
+            if let Some(end) = braced.find('}') {
+                out.push_str(&group(&braced[..end]));
+                rest = &braced[end + 1..];
+                continue;
+            }
+        }
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if digits > 0 {
+            out.push_str(&group(&rest[..digits]));
+            rest = &rest[digits..];
+        } else if let Some(stripped) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = stripped;
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str("(.*)");
+            }
+            '*' => regex.push_str("([^/]*)"),
+            '?' => regex.push_str("([^/])"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(toml: &str, redirect: bool) -> Rule {
+        Rule::from_toml(&toml.parse::<Value>().unwrap(), redirect).unwrap()
+    }
+
+    #[test]
+    fn rules_resolve() {
+        let rules = vec![
+            rule(
+                "path = '/blog/**'\nto = '/posts/$1'\nsyntax = 'glob'\nstatus = 308",
+                true,
+            ),
+            rule(
+                "path = '^/p/(?P<id>\\d+)$'\nquery = '^lang=(?P<lang>\\w+)$'\nto = '/pages/${lang}/${id}.html'\nkeep_query = false",
+                false,
+            ),
+            rule("path = '^/pages/en/(.*)$'\nto = '/$1'", false),
+        ];
+        assert_eq!(
+            resolve(&rules, None, "/blog/2021/hello", Some("ref=rss")),
+            Resolution::Redirect(308, "/posts/2021/hello?ref=rss".to_owned())
+        );
+        assert_eq!(
+            resolve(&rules, None, "/p/42", Some("lang=en")),
+            Resolution::Rewritten("/42.html".to_owned())
+        );
+        assert_eq!(
+            resolve(&rules, None, "/p/42", Some("x=1")),
+            Resolution::Unchanged
+        );
+        assert_eq!(find_loop(&rules), None);
+    }
+
+    #[test]
+    fn rules_loop() {
+        let rules = vec![
+            rule("path = '^/a/(.*)$'\nto = '/b/$1'", true),
+            rule("path = '^/b/(.*)$'\nto = '/a/$1'", true),
+        ];
+        assert_eq!(
+            find_loop(&rules),
+            Some(vec![
+                "/b/x".to_owned(),
+                "/a/x".to_owned(),
+                "/b/x".to_owned()
+            ])
+        );
+        let rewrites = vec![rule("path = '^/(.*)$'\nto = '/$1'", false)];
+        assert!(find_loop(&rewrites).is_some());
+        let moved = vec![rule(
+            "path = '^/(.*)$'\nto = 'https://newsite.example.com/$1'",
+            true,
+        )];
+        assert_eq!(find_loop(&moved), None);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::http::*;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+/// Something that can answer a request.
+///
+/// Implemented for async closures, so `|request: HttpRequest| async move { ... }`
+/// can be registered directly.
+#[async_trait]
+pub trait Handler: Send + Sync {
+    async fn handle(&self, request: HttpRequest) -> HttpResponse;
+}
+#[async_trait]
+impl<F, Fut> Handler for F
+where
+    F: Fn(HttpRequest) -> Fut + Send + Sync,
+    Fut: Future<Output = HttpResponse> + Send,
+{
+    async fn handle(&self, request: HttpRequest) -> HttpResponse {
+        self(request).await
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+/// A route path such as `/api/users/:id` or `/files/*path`.
+///
+/// `:name` matches a single segment and `*name` (or a bare `*`) matches the
+/// rest of the path, so it may only come last.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PathPattern {
+    segments: Vec<Segment>,
+}
+impl PathPattern {
+    pub fn parse(pattern: &str) -> PathPattern {
+        let segments = pattern
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_owned())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Wildcard(if name.is_empty() { "*" } else { name }.to_owned())
+                } else {
+                    Segment::Literal(segment.to_owned())
+                }
+            })
+            .collect();
+        PathPattern { segments }
+    }
+    /// Matches a URL path, returning the captured parameters, percent-decoded.
+    pub fn matches(&self, path: &str) -> Option<HashMap<String, String>> {
+        // Each segment is decoded on its own, so an encoded `/` stays inside it.
+        let parts: Vec<String> = path
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                percent_encoding::percent_decode_str(part)
+                    .decode_utf8_lossy()
+                    .into_owned()
+            })
+            .collect();
+        let mut params = HashMap::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Wildcard(name) => {
+                    params.insert(name.clone(), parts[index.min(parts.len())..].join("/"));
+                    return Some(params);
+                }
+                Segment::Literal(literal) => {
+                    if parts.get(index) != Some(literal) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), parts.get(index)?.clone());
+                }
+            }
+        }
+        if parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Route {
+    method: Option<HttpMethod>,
+    pattern: PathPattern,
+    handler: Arc<dyn Handler>,
+}
+
+/// Dispatches requests to the first registered route matching their method and
+/// path, or to the fallback when no route claims the path.
+#[derive(Default, Clone)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Arc<dyn Handler>>,
+}
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+    pub fn route<H: Handler + 'static>(
+        &mut self,
+        method: HttpMethod,
+        pattern: &str,
+        handler: H,
+    ) -> &mut Router {
+        self.routes.push(Route {
+            method: Some(method),
+            pattern: PathPattern::parse(pattern),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+    pub fn get<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route(HttpMethod::Get, pattern, handler)
+    }
+    pub fn post<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route(HttpMethod::Post, pattern, handler)
+    }
+    pub fn put<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route(HttpMethod::Put, pattern, handler)
+    }
+    pub fn patch<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route(HttpMethod::Patch, pattern, handler)
+    }
+    pub fn delete<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.route(HttpMethod::Delete, pattern, handler)
+    }
+    /// Registers a handler for every method on a path pattern.
+    pub fn any<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Router {
+        self.routes.push(Route {
+            method: None,
+            pattern: PathPattern::parse(pattern),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+    /// Hands everything under `prefix` to a handler, whatever the method.
+    /// The handler sees the full request path.
+    pub fn mount<H: Handler + 'static>(&mut self, prefix: &str, handler: H) -> &mut Router {
+        self.any(&format!("{}/*", prefix.trim_end_matches('/')), handler)
+    }
+    /// Answers requests for paths no route matches, whenever it is set.
+    pub fn fallback<H: Handler + 'static>(&mut self, handler: H) -> &mut Router {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+    pub async fn dispatch(&self, mut request: HttpRequest) -> HttpResponse {
+        let mut allowed = vec![];
+        for route in &self.routes {
+            let params = match route.pattern.matches(request.path.path()) {
+                Some(params) => params,
+                None => continue,
+            };
+            let accepts = match &route.method {
+                None => true,
+                Some(method) => {
+                    *method == request.method
+                        || (*method == HttpMethod::Get && request.method == HttpMethod::Head)
+                }
+            };
+            // A path claimed by method-specific routes doesn't fall through to a catch-all.
+            if accepts && route.method.is_none() && !allowed.is_empty() {
+                break;
+            } else if accepts {
+                request.params = params;
+                return route.handler.handle(request).await;
+            } else if let Some(method) = &route.method {
+                allowed.push(method.clone());
+            }
+        }
+        if let (true, Some(fallback)) = (allowed.is_empty(), &self.fallback) {
+            fallback.handle(request).await
+        } else if allowed.is_empty() {
+            HttpResponse::new()
+                .status(404)
+                .header("Content-Type", "text/html")
+                .body(crate::ERROR404.as_bytes().to_vec())
+                .build()
+        } else if request.method == HttpMethod::Options {
+            allowed.push(HttpMethod::Options);
+            HttpResponse::new()
+                .status(204)
+                .header("Allow", &allow_header(&allowed))
+                .build()
+        } else {
+            HttpResponse::new()
+                .status(405)
+                .header("Content-Type", "text/html")
+                .header("Allow", &allow_header(&allowed))
+                .body(crate::ERROR405.as_bytes().to_vec())
+                .build()
+        }
+    }
+}
+
+/// Formats a list of methods as the value of an `Allow` header.
+pub(crate) fn allow_header(methods: &[HttpMethod]) -> String {
+    methods
+        .iter()
+        .map(|method| method.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn pattern_matches() {
+        let pattern = PathPattern::parse("/api/users/:id");
+        let params = pattern.matches("/api/users/42").unwrap();
+        assert_eq!(params.get("id").map(|id| id.as_str()), Some("42"));
+        assert!(pattern.matches("/api/users").is_none());
+        assert!(pattern.matches("/api/users/42/posts").is_none());
+        assert!(pattern.matches("/api/groups/42").is_none());
+        let params = pattern.matches("/api/users/J%C3%B6rg").unwrap();
+        assert_eq!(params.get("id").map(|id| id.as_str()), Some("Jörg"));
+    }
+
+    #[test]
+    fn pattern_wildcard() {
+        let pattern = PathPattern::parse("/files/*path");
+        let params = pattern.matches("/files/a/b.txt").unwrap();
+        assert_eq!(params.get("path").map(|p| p.as_str()), Some("a/b.txt"));
+        let params = pattern.matches("/files/my%20notes/b.txt").unwrap();
+        assert_eq!(
+            params.get("path").map(|p| p.as_str()),
+            Some("my notes/b.txt")
+        );
+        assert!(pattern.matches("/files").is_some());
+        assert!(PathPattern::parse("/*").matches("/").is_some());
+    }
+
+    #[tokio::test]
+    async fn routes_after_start() {
+        let resources = TempDir::new("routes");
+        resources.write("index.html", "home");
+        let mut config = resources.config();
+        config.port = 0;
+        config.watch = false;
+        let mut server = Server::start(config).await.unwrap();
+        let router = server.router();
+        router.get("/api/health", |_request: HttpRequest| async {
+            HttpResponse::new().status(200).body(b"ok".to_vec()).build()
+        });
+        let request = |head: &str| HttpRequest::parse(head).unwrap().1;
+        let health = router
+            .dispatch(request("GET /api/health HTTP/1.1\r\n\r\n"))
+            .await;
+        assert_eq!(health.body, b"ok");
+        let home = router.dispatch(request("GET / HTTP/1.1\r\n\r\n")).await;
+        assert_eq!(home.body, b"home");
+        // A path a route claims isn't handed to the files for other methods.
+        let post = router
+            .dispatch(request("POST /api/health HTTP/1.1\r\n\r\n"))
+            .await;
+        assert_eq!(post.status.value, 405);
+    }
+}
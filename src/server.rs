@@ -1,34 +1,44 @@
+use crate::cache::{Cache, CacheConfig};
+use crate::cgi::{Cgi, CgiConfig};
+use crate::compression::CompressionConfig;
+use crate::error_pages::{self, ErrorContext, ErrorPages};
+use crate::fastcgi::FastCgiConfig;
+use crate::files::{self, StaticFiles};
 use crate::http::*;
+use crate::livereload::{self, LiveReload};
+use crate::markdown::MarkdownConfig;
+use crate::middleware::{MiddlewareConfig, MiddlewareKind};
+use crate::multipart::MultipartLimits;
+use crate::proxy::{Proxy, ProxyConfig};
+use crate::rewrite::{self, Rule};
+use crate::router::Router;
+use crate::sourceview::SourceViewConfig;
+use crate::ssi::SsiConfig;
+use crate::template::TemplateConfig;
+use crate::tus::{Tus, TusConfig};
+use crate::upstream::{Upstream, UpstreamConfig};
+use crate::watch::ResourceWatcher;
+use crate::webdav;
+use async_trait::async_trait;
 use log::*;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
-#[async_recursion::async_recursion]
-async fn get_files(dir: PathBuf, config: &Config) -> Result<Vec<String>, ServerError> {
-    if !dir.as_path().exists() {
-        return Err(ServerError::FileLoadError);
-    }
-    let mut file_paths = vec![];
-    let mut entries = tokio::fs::read_dir(dir.as_path()).await?;
-    while let Some(entry) = entries.next_entry().await? {
-        file_paths.push(entry.path());
-    }
-    let mut recursive_paths = vec![];
-    for path in &file_paths {
-        if path.as_path().is_file() {
-            recursive_paths.push(String::from(path.to_str().unwrap_or("ERR")));
-        } else if path.as_path().is_dir() {
-            recursive_paths.append(&mut get_files(path.clone(), config).await?);
-        }
-    }
-    let mut set = HashSet::new();
-    recursive_paths.retain(|x| set.insert(x.clone()));
-    recursive_paths.retain(|x| config.preload.contains(&String::from(&x[5..])));
-    Ok(recursive_paths)
-}
+/// Requests whose headers don't fit in this many bytes are rejected.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+/// How long to wait for a client to send its request headers.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a streamed response, like a proxied body, may go without sending
+/// anything before it is abandoned.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a client may take to send a whole request body.
+const BODY_TIMEOUT: Duration = Duration::from_secs(300);
 
 #[derive(Debug, PartialEq)]
 pub struct Config {
@@ -37,6 +47,32 @@ pub struct Config {
     pub preload: Vec<String>,
     pub mimetypes: HashMap<String, String>,
     pub loglevel: log::LevelFilter,
+    pub max_body_size: usize,
+    pub watch: bool,
+    pub watch_debounce: u64,
+    pub dev: bool,
+    pub locations: Vec<Location>,
+    pub middleware: Vec<MiddlewareConfig>,
+    pub compression: CompressionConfig,
+    pub multipart: MultipartLimits,
+    pub tus: Option<TusConfig>,
+    /// `[[cgi]]` script directories.
+    pub cgi: Vec<CgiConfig>,
+    /// `[[fastcgi]]` backends.
+    pub fastcgi: Vec<FastCgiConfig>,
+    /// `[[proxy]]` upstreams.
+    pub proxies: Vec<ProxyConfig>,
+    /// `[[upstream]]` backend pools that proxies forward to by name.
+    pub upstreams: Vec<UpstreamConfig>,
+    /// The `[cache]` that proxies with `cache = true` keep responses in.
+    pub cache: Option<CacheConfig>,
+    /// `[[redirect]]` rules, then `[[rewrite]]` rules, in the order they are checked.
+    pub rules: Vec<Rule>,
+    pub error_pages: ErrorPages,
+    pub ssi: SsiConfig,
+    pub template: TemplateConfig,
+    pub markdown: MarkdownConfig,
+    pub source_view: SourceViewConfig,
 }
 impl Config {
     pub fn new(
@@ -52,6 +88,26 @@ impl Config {
             preload,
             mimetypes,
             loglevel,
+            max_body_size: 16 * 1024 * 1024,
+            watch: true,
+            watch_debounce: 200,
+            dev: false,
+            locations: vec![],
+            middleware: vec![MiddlewareConfig::new(MiddlewareKind::AccessLog)],
+            compression: CompressionConfig::default(),
+            multipart: MultipartLimits::default(),
+            tus: None,
+            cgi: vec![],
+            fastcgi: vec![],
+            proxies: vec![],
+            upstreams: vec![],
+            cache: None,
+            rules: vec![],
+            error_pages: ErrorPages::default(),
+            ssi: SsiConfig::default(),
+            template: TemplateConfig::default(),
+            markdown: MarkdownConfig::default(),
+            source_view: SourceViewConfig::default(),
         }
     }
     pub async fn from_file(path: &str) -> Result<Config, ServerError> {
@@ -60,6 +116,25 @@ impl Config {
         let mut preload = vec![];
         let mut mimetypes = HashMap::new();
         let mut loglevel = log::LevelFilter::Info;
+        let mut max_body_size = 16 * 1024 * 1024;
+        let mut watch = true;
+        let mut watch_debounce = 200;
+        let mut compression = CompressionConfig::default();
+        let mut multipart = MultipartLimits::default();
+        let mut tus = None;
+        let mut cgi = vec![];
+        let mut fastcgi = vec![];
+        let mut proxies = vec![];
+        let mut upstreams = vec![];
+        let mut cache = None;
+        let mut error_pages = ErrorPages::default();
+        let mut ssi = SsiConfig::default();
+        let mut template = TemplateConfig::default();
+        let mut markdown = MarkdownConfig::default();
+        let mut source_view = SourceViewConfig::default();
+        let mut rules = vec![];
+        let mut locations = vec![];
+        let mut middleware = None;
         if Path::new(path).exists() {
             use toml::Value;
             let mut contents = vec![];
@@ -90,6 +165,82 @@ impl Config {
                             _ => {}
                         }
                     }
+                    if let Some(Value::Integer(cfg_max_body_size)) = cfg_server.get("max_body_size")
+                    {
+                        max_body_size = *cfg_max_body_size as usize;
+                    }
+                    if let Some(Value::Boolean(cfg_watch)) = cfg_server.get("watch") {
+                        watch = *cfg_watch;
+                    }
+                    if let Some(Value::Integer(cfg_debounce)) = cfg_server.get("watch_debounce") {
+                        watch_debounce = *cfg_debounce as u64;
+                    }
+                }
+                if let Some(Value::Array(cfg_locations)) = cfg.get("location") {
+                    for cfg_location in cfg_locations {
+                        locations.push(Location::from_toml(cfg_location)?);
+                    }
+                }
+                if let Some(Value::Array(cfg_middleware)) = cfg.get("middleware") {
+                    let mut chain = vec![];
+                    for cfg_entry in cfg_middleware {
+                        chain.push(MiddlewareConfig::from_toml(cfg_entry)?);
+                    }
+                    middleware = Some(chain);
+                }
+                if let Some(cfg_compression) = cfg.get("compression") {
+                    compression = CompressionConfig::from_toml(cfg_compression);
+                }
+                if let Some(cfg_multipart) = cfg.get("multipart") {
+                    multipart = MultipartLimits::from_toml(cfg_multipart);
+                }
+                for (key, redirect) in [("redirect", true), ("rewrite", false)].iter() {
+                    if let Some(Value::Array(cfg_rules)) = cfg.get(*key) {
+                        for cfg_rule in cfg_rules {
+                            rules.push(Rule::from_toml(cfg_rule, *redirect)?);
+                        }
+                    }
+                }
+                if let Some(cfg_ssi) = cfg.get("ssi") {
+                    ssi = SsiConfig::from_toml(cfg_ssi);
+                }
+                if let Some(cfg_template) = cfg.get("template") {
+                    template = TemplateConfig::from_toml(cfg_template);
+                }
+                if let Some(cfg_markdown) = cfg.get("markdown") {
+                    markdown = MarkdownConfig::from_toml(cfg_markdown);
+                }
+                if let Some(cfg_source_view) = cfg.get("source_view") {
+                    source_view = SourceViewConfig::from_toml(cfg_source_view)?;
+                }
+                if let Some(cfg_error_pages) = cfg.get("error_pages") {
+                    error_pages = ErrorPages::from_toml(cfg_error_pages)?;
+                }
+                if let Some(cfg_tus) = cfg.get("tus") {
+                    tus = Some(TusConfig::from_toml(cfg_tus)?);
+                }
+                if let Some(Value::Array(cfg_cgi)) = cfg.get("cgi") {
+                    for cfg_entry in cfg_cgi {
+                        cgi.push(CgiConfig::from_toml(cfg_entry)?);
+                    }
+                }
+                if let Some(Value::Array(cfg_fastcgi)) = cfg.get("fastcgi") {
+                    for cfg_entry in cfg_fastcgi {
+                        fastcgi.push(FastCgiConfig::from_toml(cfg_entry)?);
+                    }
+                }
+                if let Some(Value::Array(cfg_proxies)) = cfg.get("proxy") {
+                    for cfg_entry in cfg_proxies {
+                        proxies.push(ProxyConfig::from_toml(cfg_entry)?);
+                    }
+                }
+                if let Some(Value::Array(cfg_upstreams)) = cfg.get("upstream") {
+                    for cfg_entry in cfg_upstreams {
+                        upstreams.push(UpstreamConfig::from_toml(cfg_entry)?);
+                    }
+                }
+                if let Some(cfg_cache) = cfg.get("cache") {
+                    cache = Some(CacheConfig::from_toml(cfg_cache)?);
                 }
                 if let Some(Value::Table(cfg_mimetypes)) = cfg.get("mimetypes") {
                     for k in cfg_mimetypes.keys() {
@@ -107,126 +258,543 @@ impl Config {
             warn!("No config file detected! Creating one at glasscannon.toml...");
             File::create(&path).await?.write_all(b"[server]\nport = 15000\nresources = \"./res/\"\npreload = []\nloglevel = \"info\" # See https://docs.rs/log/0.4.14/log/enum.LevelFilter.html\n\n[mimetypes]\n\"text/html\" = \"html\"").await?;
         }
-        Ok(Config::new(port, resources, preload, mimetypes, loglevel))
+        let mut config = Config::new(port, resources, preload, mimetypes, loglevel);
+        config.max_body_size = max_body_size;
+        config.watch = watch;
+        config.watch_debounce = watch_debounce;
+        config.locations = locations;
+        if let Some(middleware) = middleware {
+            config.middleware = middleware;
+        }
+        config.compression = compression;
+        config.multipart = multipart;
+        // Finished uploads are written where the location's users could PUT them.
+        if let Some(tus) = &tus {
+            match config.location(&tus.destination) {
+                Some(location) if location.writable => {}
+                _ => return Err(ServerError::ConfigError),
+            }
+        }
+        config.tus = tus;
+        config.cgi = cgi;
+        config.fastcgi = fastcgi;
+        for (index, upstream) in upstreams.iter().enumerate() {
+            if upstreams[..index]
+                .iter()
+                .any(|earlier| earlier.name == upstream.name)
+            {
+                return Err(ServerError::ConfigError);
+            }
+        }
+        for proxy in proxies.iter_mut() {
+            if upstreams
+                .iter()
+                .any(|upstream| upstream.name == proxy.upstream_host)
+            {
+                proxy.pool = Some(proxy.upstream_host.clone());
+            }
+        }
+        if cache.is_none() && proxies.iter().any(|proxy| proxy.cache) {
+            return Err(ServerError::ConfigError);
+        }
+        if let Some(cycle) = rewrite::find_loop(&rules) {
+            error!("Rewrite and redirect rules loop: {}", cycle.join(" -> "));
+            return Err(ServerError::ConfigError);
+        }
+        config.proxies = proxies;
+        config.cache = cache;
+        config.upstreams = upstreams;
+        config.error_pages = error_pages;
+        config.ssi = ssi;
+        config.template = template;
+        config.markdown = markdown;
+        config.source_view = source_view;
+        config.rules = rules;
+        Ok(config)
+    }
+    /// Finds the most specific location that a URL path falls under.
+    pub fn location(&self, url_path: &str) -> Option<&Location> {
+        self.locations
+            .iter()
+            .filter(|location| url_path.starts_with(&location.path))
+            .max_by_key(|location| location.path.len())
+    }
+    /// The methods a request to `url_path` may use.
+    pub fn allowed_methods(&self, url_path: &str) -> Vec<HttpMethod> {
+        match self.location(url_path) {
+            Some(location) => location
+                .supported_methods()
+                .into_iter()
+                .filter(|method| location.methods.contains(method))
+                .collect(),
+            None => vec![HttpMethod::Get, HttpMethod::Head, HttpMethod::Options],
+        }
+    }
+    /// Maps a file under the resources root to the URL path it is served at.
+    pub fn url_path(&self, file_path: &Path) -> Option<String> {
+        let relative = file_path.strip_prefix(&self.resources).ok()?;
+        let mut url_path = String::new();
+        for component in relative.components() {
+            url_path.push('/');
+            url_path.push_str(component.as_os_str().to_str()?);
+        }
+        Some(url_path)
+    }
+    /// Maps a URL path to the file under the resources root it names, decoding
+    /// percent-escapes. Returns `None` for paths that would step outside the root.
+    pub fn file_path(&self, url_path: &str) -> Option<PathBuf> {
+        let mut file_path = self.resources.clone();
+        for segment in url_path.split('/').filter(|segment| !segment.is_empty()) {
+            let segment = percent_encoding::percent_decode_str(segment)
+                .decode_utf8()
+                .ok()?;
+            if segment == "." || segment == ".." || segment.contains(['/', '\\', '\0']) {
+                return None;
+            }
+            file_path.push(segment.as_ref());
+        }
+        Some(file_path)
+    }
+    /// Looks up the MIME type for a URL path by its extension.
+    pub fn mimetype(&self, url_path: &str) -> &str {
+        Path::new(url_path)
+            .extension()
+            .and_then(|ext| self.mimetypes.get(ext.to_str()?))
+            .map(|mime| mime.as_str())
+            .unwrap_or("application/octet-stream")
+    }
+}
+
+/// Settings for every URL path under `path`, from a `[[location]]` table.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Location {
+    pub path: String,
+    pub methods: Vec<HttpMethod>,
+    /// Whether authenticated clients may PUT, POST and DELETE files here.
+    pub writable: bool,
+    /// User names and passwords allowed to write.
+    pub users: HashMap<String, String>,
+    /// Whether WebDAV clients can browse here, and manage files if it is writable.
+    pub webdav: bool,
+    /// Paths to try in order for GET and HEAD, where `$uri` is the request path,
+    /// optionally ending in `=status` to answer with when none exist.
+    pub try_files: Vec<String>,
+    /// Whether code files here are shown highlighted unless `?view=raw` is asked for.
+    pub view_source: bool,
+}
+impl Location {
+    pub fn new(path: &str) -> Location {
+        Location {
+            path: path.to_owned(),
+            methods: vec![HttpMethod::Get, HttpMethod::Head, HttpMethod::Options],
+            writable: false,
+            users: HashMap::new(),
+            webdav: false,
+            try_files: vec![],
+            view_source: false,
+        }
+    }
+    /// The methods the server can answer here, before the `methods` allowlist.
+    pub fn supported_methods(&self) -> Vec<HttpMethod> {
+        let mut methods = vec![HttpMethod::Get, HttpMethod::Head, HttpMethod::Options];
+        if self.writable {
+            methods.extend(vec![HttpMethod::Put, HttpMethod::Post, HttpMethod::Delete]);
+        }
+        if self.webdav {
+            methods.extend(webdav::methods(self.writable));
+        }
+        methods
+    }
+    pub fn from_toml(cfg: &toml::Value) -> Result<Location, ServerError> {
+        use toml::Value;
+        let mut location = match cfg.get("path") {
+            Some(Value::String(path)) if path.starts_with('/') => Location::new(path),
+            _ => return Err(ServerError::ConfigError),
+        };
+        if let Some(Value::Boolean(writable)) = cfg.get("writable") {
+            location.writable = *writable;
+        }
+        if let Some(Value::Boolean(webdav)) = cfg.get("webdav") {
+            location.webdav = *webdav;
+        }
+        if let Some(Value::Boolean(view_source)) = cfg.get("view_source") {
+            location.view_source = *view_source;
+        }
+        if let Some(Value::Array(cfg_methods)) = cfg.get("methods") {
+            location.methods.clear();
+            for cfg_method in cfg_methods {
+                match cfg_method.as_str().map(HttpMethod::parse) {
+                    Some(Ok(("", method))) => location.methods.push(method),
+                    _ => return Err(ServerError::ConfigError),
+                }
+            }
+        } else {
+            location.methods = location.supported_methods();
+        }
+        if let Some(Value::Table(cfg_users)) = cfg.get("users") {
+            for (name, password) in cfg_users {
+                match password.as_str() {
+                    Some(password) => location.users.insert(name.clone(), password.to_owned()),
+                    None => return Err(ServerError::ConfigError),
+                };
+            }
+        }
+        if let Some(Value::Array(cfg_try_files)) = cfg.get("try_files") {
+            for (i, entry) in cfg_try_files.iter().enumerate() {
+                let valid = match entry.as_str() {
+                    Some(entry) if entry.starts_with('/') || entry.starts_with("$uri") => true,
+                    // Only the last entry can be a status, since nothing after it is tried.
+                    Some(entry) if i == cfg_try_files.len() - 1 => entry
+                        .strip_prefix('=')
+                        .and_then(|status| status.parse().ok())
+                        .is_some_and(|status| status >= 400 && HttpStatus::new(status).is_ok()),
+                    _ => false,
+                };
+                match entry.as_str() {
+                    Some(entry) if valid => location.try_files.push(entry.to_owned()),
+                    _ => return Err(ServerError::ConfigError),
+                }
+            }
+        }
+        // Writes are never open to everyone.
+        if location.writable && location.users.is_empty() {
+            return Err(ServerError::ConfigError);
+        }
+        Ok(location)
+    }
+}
+
+/// Wraps request handling: it can inspect or modify the request, answer it
+/// itself instead of calling `next`, or post-process the response `next` returns.
+///
+/// Middleware runs in the order it was added, each one wrapping the rest of
+/// the chain and finally the router.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse;
+}
+
+/// The rest of the middleware chain, ending at the router.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    router: &'a Router,
+}
+impl<'a> Next<'a> {
+    pub fn new(middleware: &'a [Arc<dyn Middleware>], router: &'a Router) -> Next<'a> {
+        Next { middleware, router }
+    }
+    pub async fn run(self, request: HttpRequest) -> HttpResponse {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => {
+                middleware
+                    .handle(request, Next::new(rest, self.router))
+                    .await
+            }
+            None => self.router.dispatch(request).await,
+        }
     }
 }
 
 pub struct Server {
     listener: TcpListener,
-    resources: HashMap<String, Option<Vec<u8>>>,
-    config: Config,
+    router: Arc<Router>,
+    middleware: Arc<Vec<Arc<dyn Middleware>>>,
+    files: StaticFiles,
+    watcher: Option<ResourceWatcher>,
+    live_reload: Arc<tokio::sync::Mutex<LiveReload>>,
+    upstreams: Vec<Arc<Upstream>>,
+    cache: Option<Arc<Cache>>,
+    config: Arc<Config>,
 }
 impl Server {
-    pub async fn start(config: Config) -> Result<Server, ServerError> {
-        let mut resources = HashMap::new();
-        for path in get_files(PathBuf::from("./res/"), &config).await? {
-            let mut contents = vec![];
-            let trimmed_path = (&path.clone().as_str()[5..]).to_owned();
-            if config.preload.contains(&trimmed_path) {
-                File::open(&path).await?.read_to_end(&mut contents).await?;
-                resources.insert(trimmed_path, Some(contents));
-            } else {
-                resources.insert(trimmed_path, None);
-            }
-        }
+    /// Binds the listener and loads the preload cache, without registering any routes.
+    pub async fn new(config: Config) -> Result<Server, ServerError> {
+        let config = Arc::new(config);
+        let files = StaticFiles::load(config.clone()).await?;
+        let watcher = if config.watch || config.dev {
+            Some(ResourceWatcher::new(
+                &config.resources,
+                Duration::from_millis(config.watch_debounce),
+            )?)
+        } else {
+            None
+        };
+        let middleware = config
+            .middleware
+            .iter()
+            .map(|middleware| middleware.build(&config.compression))
+            .collect();
+        let upstreams = config
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                let upstream = Arc::new(Upstream::new(upstream.clone()));
+                upstream.spawn_health_checks();
+                upstream
+            })
+            .collect();
+        let cache = match &config.cache {
+            Some(cache) => Some(Arc::new(Cache::open(cache.clone()).await?)),
+            None => None,
+        };
         Ok(Server {
             listener: TcpListener::bind(format!("localhost:{}", config.port)).await?,
-            resources,
+            router: Arc::new(Router::new()),
+            middleware: Arc::new(middleware),
+            files,
+            watcher,
+            live_reload: Arc::new(tokio::sync::Mutex::new(LiveReload::new())),
+            upstreams,
+            cache,
             config,
         })
     }
+    /// Starts a server that serves the resources root for any path its routes don't claim.
+    pub async fn start(config: Config) -> Result<Server, ServerError> {
+        let mut server = Server::new(config).await?;
+        let files = server.static_files();
+        if let Some(tus) = &server.config.tus {
+            let endpoint = tus.path.clone();
+            server.router().mount(&endpoint, Tus::new(files.clone()));
+        }
+        for proxy in server.config.proxies.clone() {
+            let endpoint = proxy.path.clone();
+            let pool = server
+                .upstreams
+                .iter()
+                .find(|upstream| proxy.pool.as_deref() == Some(upstream.name()))
+                .cloned();
+            let cache = server.cache.clone().filter(|_| proxy.cache);
+            let mut handler = Proxy::new(proxy);
+            if let Some(pool) = pool {
+                handler = handler.pool(pool);
+            }
+            if let Some(cache) = cache {
+                handler = handler.cache(cache);
+            }
+            server.router().mount(&endpoint, handler);
+        }
+        for cgi in server.config.cgi.clone() {
+            let endpoint = cgi.path.clone();
+            let handler = Cgi::new(server.config.clone(), cgi);
+            server.router().mount(&endpoint, handler);
+        }
+        server.router().fallback(files);
+        Ok(server)
+    }
+    /// The routes requests are dispatched to. Connections already being served
+    /// keep the routes they started with.
+    pub fn router(&mut self) -> &mut Router {
+        Arc::make_mut(&mut self.router)
+    }
+    /// The `[[upstream]]` pools, whose `status()` shows how each backend is doing.
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+    /// The `[cache]` of proxied responses, if there is one.
+    pub fn cache(&self) -> Option<&Arc<Cache>> {
+        self.cache.as_ref()
+    }
+    /// Adds middleware after the ones configured in `glasscannon.toml`.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Server {
+        Arc::make_mut(&mut self.middleware).push(Arc::new(middleware));
+        self
+    }
+    /// A handler serving the resources root, sharing this server's preload cache.
+    pub fn static_files(&self) -> StaticFiles {
+        self.files.clone()
+    }
+    pub async fn run(mut self) -> Result<(), ServerError> {
+        loop {
+            self.update().await?;
+        }
+    }
     pub async fn update(&mut self) -> Result<(), ServerError> {
-        let (socket, _) = self.listener.accept().await?;
-        socket.readable().await?;
-        let mut data = Vec::with_capacity(4096);
-        match socket.try_read_buf(&mut data) {
-            Ok(0) => return Ok(()),
-            Ok(_num_bytes) => {
-                self.handle_request(socket, String::from_utf8_lossy(&data).to_string())
-                    .await?
+        let watcher = &mut self.watcher;
+        let changed = async {
+            match watcher {
+                Some(watcher) => watcher.changed().await,
+                None => std::future::pending().await,
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
-            Err(e) => return Err(e.into()),
         };
+        let (socket, remote_addr) = tokio::select! {
+            accepted = self.listener.accept() => accepted?,
+            changed = changed => {
+                self.files.refresh(&changed).await;
+                if self.config.dev {
+                    self.live_reload.lock().await.notify(&changed).await;
+                }
+                return Ok(());
+            }
+        };
+        let connection = Connection {
+            router: self.router.clone(),
+            middleware: self.middleware.clone(),
+            files: self.files.clone(),
+            live_reload: self.live_reload.clone(),
+            config: self.config.clone(),
+        };
+        // Each connection is served alongside the others; a failed one is that
+        // client's problem, and only the listener's errors end the server.
+        tokio::spawn(async move {
+            if let Err(e) = connection.serve(socket, remote_addr).await {
+                warn!(
+                    "Dropped the connection from {}: {}",
+                    remote_addr,
+                    e.message()
+                );
+            }
+        });
         Ok(())
     }
+}
+
+/// What serving one connection needs from the server.
+struct Connection {
+    router: Arc<Router>,
+    middleware: Arc<Vec<Arc<dyn Middleware>>>,
+    files: StaticFiles,
+    live_reload: Arc<tokio::sync::Mutex<LiveReload>>,
+    config: Arc<Config>,
+}
+impl Connection {
+    async fn serve(
+        self,
+        mut socket: TcpStream,
+        remote_addr: SocketAddr,
+    ) -> Result<(), ServerError> {
+        let mut data = Vec::with_capacity(4096);
+        // Read up to the end of the headers; anything past them belongs to the body.
+        let header_end = loop {
+            if let Some(index) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+                break index + 4;
+            } else if data.len() > MAX_HEADER_SIZE {
+                break data.len();
+            }
+            match tokio::time::timeout(READ_TIMEOUT, socket.read_buf(&mut data)).await {
+                Ok(Ok(0)) | Err(_) => return Ok(()),
+                Ok(Ok(_num_bytes)) => {}
+                Ok(Err(e)) => {
+                    debug!("Could not read a request from {}: {}", remote_addr, e);
+                    return Ok(());
+                }
+            }
+        };
+        let body = data.split_off(header_end);
+        self.handle_request(
+            socket,
+            remote_addr,
+            String::from_utf8_lossy(&data).to_string(),
+            body,
+        )
+        .await
+    }
     async fn handle_request(
         &self,
-        mut socket: TcpStream,
+        socket: TcpStream,
+        remote_addr: SocketAddr,
         request_string: String,
+        body: Vec<u8>,
     ) -> Result<(), ServerError> {
-        let mut response = HttpResponse::new()
-            .status(400)
-            .header("Content-Type", "text/html")
-            .body(
-                self.resources
-                    .get(&"/400.html".to_owned())
-                    .unwrap_or(&Some(crate::ERROR400.as_bytes().to_vec()))
-                    .clone()
-                    .unwrap(),
-            )
-            .build();
-        if let Ok((_rest, request)) = HttpRequest::parse(&request_string) {
-            let mut url_path = request.path.path().clone();
-            if request.path.path() == "/" {
-                url_path = "/index.html";
+        let (reader, mut writer) = socket.into_split();
+        let mut reader = Some(reader);
+        // Bytes after a request to switch protocols belong to the new protocol.
+        let mut upgrade_data = vec![];
+        let mut response = files::error_response(400);
+        let mut context = ErrorContext::unknown();
+        let mut head = false;
+        if let Ok((_rest, mut request)) = HttpRequest::parse(&request_string) {
+            if self.config.dev && request.path.path() == livereload::ENDPOINT {
+                debug!("Live-reload client connected");
+                let socket = reader
+                    .take()
+                    .and_then(|reader| reader.reunite(writer).ok())
+                    .ok_or(ServerError::IoError)?;
+                self.live_reload.lock().await.add_client(socket).await;
+                return Ok(());
             }
-            let mut file_path = self.config.resources.clone();
-            file_path.push(&url_path[1..]);
-            if self.resources.contains_key(request.path.path()) {
-                response = HttpResponse::new()
-                    .status(200)
-                    .body(
-                        self.resources
-                            .get(request.path.path())
-                            .unwrap()
-                            .clone()
-                            .unwrap(),
-                    )
-                    .build();
-                if let Some(ext) = Path::new(url_path).extension() {
-                    if let Some(mime) = self.config.mimetypes.get(ext.to_str().unwrap()) {
-                        response.set_header("Content-Type", mime);
-                    }
-                } else {
-                    response.set_header("Content-Type", "application/octet-stream");
-                }
-            } else if file_path.as_path().exists() {
-                let mut contents = vec![];
-                File::open(file_path)
-                    .await?
-                    .read_to_end(&mut contents)
-                    .await?;
-                response = HttpResponse::new().status(200).body(contents).build();
-                if let Some(ext) = Path::new(url_path).extension() {
-                    if let Some(mime) = self.config.mimetypes.get(ext.to_str().unwrap()) {
-                        response.set_header("Content-Type", mime);
-                    }
-                } else {
-                    response.set_header("Content-Type", "application/octet-stream");
+            request.remote_addr = Some(remote_addr);
+            // A chunked body runs until its last chunk, which `decode_body` finds.
+            let length = match request.header("Transfer-Encoding") {
+                Some(_) => u64::MAX,
+                None => request
+                    .header("Content-Length")
+                    .and_then(|length| length.trim().parse::<u64>().ok())
+                    .unwrap_or(0),
+            };
+            if request.header("Upgrade").is_some() && length == 0 {
+                upgrade_data = body;
+            } else if let Some(reader) = reader.take() {
+                // Clients get as long to send each part of the body as they do the headers.
+                request.body =
+                    RequestBody::new(body, Box::new(reader), length, self.config.max_body_size)
+                        .read_timeout(READ_TIMEOUT)
+                        .total_timeout(BODY_TIMEOUT);
+            }
+            head = request.method == HttpMethod::Head;
+            context = ErrorContext::new(&request);
+            response = match request.decode_body().await {
+                Err(e) => {
+                    debug!(
+                        "Could not read a chunked body from {}: {:?}",
+                        remote_addr, e
+                    );
+                    files::error_response(e.status())
                 }
-            } else {
-                response = HttpResponse::new()
-                    .status(404)
-                    .header("Content-Type", "text/html")
-                    .body(
-                        self.resources
-                            .get(&"/404.html".to_owned())
-                            .unwrap_or(&Some(crate::ERROR404.as_bytes().to_vec()))
-                            .clone()
-                            .unwrap(),
-                    )
-                    .build();
+                Ok(()) => match rewrite::apply(&self.config.rules, &mut request) {
+                    Some(response) => response,
+                    None => Next::new(&self.middleware, &self.router).run(request).await,
+                },
+            };
+        }
+        // Only a request to switch protocols can be answered by switching.
+        let upgrade = match (response.upgrade.take(), reader) {
+            (Some(upgrade), Some(reader)) if response.status.value == 101 => {
+                Some((upgrade, reader))
             }
-            info!(
-                "{} {} {}",
-                response.status.value,
-                request.method,
-                request.path.path()
-            );
+            _ => None,
+        };
+        if response.status.value == 101 && upgrade.is_none() {
+            response = files::error_response(400);
         }
+        error_pages::apply(&self.files, &context, &mut response).await;
+        // HEAD gets the same headers as GET, including Content-Length, but no body.
+        if head {
+            response.body.clear();
+            response.stream = None;
+        }
+        // Replacing any other server's, like a proxied upstream's.
+        response.remove_header("server");
         response.set_header("server", "GlassCannon");
-        socket.write_all(&response.emit()).await?;
+        writer.write_all(&response.emit()).await?;
+        if let Some(stream) = response.stream.as_mut() {
+            // The source, like an upstream server, can fail or stall partway through.
+            // Closing the connection then tells the client the body is incomplete.
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                let count =
+                    match tokio::time::timeout(STREAM_TIMEOUT, stream.reader.read(&mut buffer))
+                        .await
+                    {
+                        Ok(Ok(0)) => break,
+                        Ok(Ok(count)) => count,
+                        Ok(Err(e)) => {
+                            warn!("Could not finish the response to {}: {}", remote_addr, e);
+                            return Ok(());
+                        }
+                        Err(_) => {
+                            warn!("The response to {} stalled; closing it", remote_addr);
+                            return Ok(());
+                        }
+                    };
+                writer.write_all(&buffer[..count]).await?;
+            }
+        }
+        // The connection is the handler's from here, alongside other requests.
+        if let Some((upgrade, reader)) = upgrade {
+            let socket = reader.reunite(writer).map_err(|_| ServerError::IoError)?;
+            tokio::spawn((upgrade.0)(socket, upgrade_data));
+        }
         Ok(())
     }
 }
@@ -237,6 +805,7 @@ pub enum ServerError {
     ParseError,
     ConfigError,
     FileLoadError,
+    WatchError,
 }
 impl ServerError {
     pub fn message(&self) -> &'static str {
@@ -246,6 +815,7 @@ impl ServerError {
             ParseError => "Could not parse network data",
             ConfigError => "Could not load config",
             FileLoadError => "Could not load files",
+            WatchError => "Could not watch resources for changes",
         }
     }
 }
@@ -264,3 +834,8 @@ impl From<toml::de::Error> for ServerError {
         ServerError::ConfigError
     }
 }
+impl From<notify::Error> for ServerError {
+    fn from(_error: notify::Error) -> ServerError {
+        ServerError::WatchError
+    }
+}
@@ -0,0 +1,205 @@
+use crate::files::{modified_time, StaticFiles};
+use crate::http::*;
+use crate::server::ServerError;
+use std::path::Path;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+use toml::Value;
+
+static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+
+fn syntaxes() -> &'static SyntaxSet {
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn themes() -> &'static ThemeSet {
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Highlighted views of code files, from `[source_view]`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SourceViewConfig {
+    pub enabled: bool,
+    /// One of syntect's default themes, like `InspiredGitHub` or `base16-ocean.dark`.
+    pub theme: String,
+    /// Files larger than this are shown without highlighting, which is slow.
+    pub max_highlight_size: u64,
+}
+impl Default for SourceViewConfig {
+    fn default() -> SourceViewConfig {
+        SourceViewConfig {
+            enabled: true,
+            theme: "InspiredGitHub".to_owned(),
+            max_highlight_size: 1024 * 1024,
+        }
+    }
+}
+impl SourceViewConfig {
+    pub fn from_toml(cfg: &Value) -> Result<SourceViewConfig, ServerError> {
+        let mut config = SourceViewConfig::default();
+        if let Some(Value::Boolean(enabled)) = cfg.get("enabled") {
+            config.enabled = *enabled;
+        }
+        if let Some(Value::String(theme)) = cfg.get("theme") {
+            if !themes().themes.contains_key(theme) {
+                return Err(ServerError::ConfigError);
+            }
+            config.theme = theme.clone();
+        }
+        if let Some(Value::Integer(size)) = cfg.get("max_highlight_size") {
+            config.max_highlight_size = *size as u64;
+        }
+        Ok(config)
+    }
+}
+
+/// Whether a file's extension has a known syntax, so it is worth viewing as source.
+pub fn has_syntax(url_path: &str) -> bool {
+    Path::new(url_path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| syntaxes().find_syntax_by_extension(ext).is_some())
+}
+
+/// Renders a text file as an HTML page with highlighting, line numbers and
+/// line anchors. Returns `None` for binary files, which are served as they are.
+pub(crate) async fn render(
+    files: &StaticFiles,
+    request: &HttpRequest,
+    url_path: &str,
+    file_path: &Path,
+) -> Result<Option<Vec<u8>>, ServerError> {
+    let key = format!("{}?view=source", url_path);
+    if let Some(body) = files.rendered.get(&key).await {
+        return Ok(Some(body));
+    }
+    let modified = modified_time(file_path).await;
+    let contents = tokio::fs::read(file_path).await?;
+    if contents.iter().take(8192).any(|byte| *byte == 0) {
+        return Ok(None);
+    }
+    let text = String::from_utf8_lossy(&contents).to_string();
+    let name = percent_encoding::percent_decode_str(url_path.rsplit('/').next().unwrap_or(""))
+        .decode_utf8_lossy()
+        .to_string();
+    let raw = format!("{}?view=raw", request.path.path());
+    let config = files.config.source_view.clone();
+    let highlight = contents.len() as u64 <= config.max_highlight_size;
+    // Highlighting is slow enough to hold up other requests.
+    let body =
+        tokio::task::spawn_blocking(move || page(&text, &name, &raw, &config.theme, highlight))
+            .await
+            .map_err(|_| ServerError::IoError)?
+            .into_bytes();
+    files
+        .rendered
+        .insert(&key, body.clone(), vec![(file_path.to_owned(), modified)]);
+    Ok(Some(body))
+}
+
+fn page(text: &str, name: &str, raw: &str, theme: &str, highlight: bool) -> String {
+    let set = syntaxes();
+    let theme = &themes().themes[theme];
+    let syntax = Path::new(name)
+        .extension()
+        .and_then(|ext| set.find_syntax_by_extension(ext.to_str()?))
+        .or_else(|| set.find_syntax_by_first_line(text.lines().next().unwrap_or("")))
+        .unwrap_or_else(|| set.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let mut rows = String::new();
+    let mut count = 0;
+    for line in LinesWithEndings::from(text) {
+        count += 1;
+        let html = match highlight {
+            true => highlighter
+                .highlight_line(line, set)
+                .ok()
+                .and_then(|regions| {
+                    styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()
+                }),
+            false => None,
+        }
+        .unwrap_or_else(|| escape_html(line));
+        write_row(&mut rows, count, &html.replace(['\r', '\n'], ""));
+    }
+    let (background, foreground) = match (theme.settings.background, theme.settings.foreground) {
+        (Some(bg), Some(fg)) => (
+            format!("#{:02x}{:02x}{:02x}", bg.r, bg.g, bg.b),
+            format!("#{:02x}{:02x}{:02x}", fg.r, fg.g, fg.b),
+        ),
+        _ => ("#fff".to_owned(), "#000".to_owned()),
+    };
+    format!(
+        "<!DOCTYPE html><html lang=\"en\" dir=\"ltr\"><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>{name}</title><style>{style}</style></head>\
+         <body style=\"background:{background};color:{foreground}\"><header><strong>{name}</strong> &middot; {count} lines &middot; {syntax} &middot; <a href=\"{raw}\">Raw</a></header>\
+         <table><tbody>{rows}</tbody></table><script>{script}</script></body></html>",
+        name = escape_html(name),
+        style = STYLE,
+        background = background,
+        foreground = foreground,
+        count = count,
+        syntax = escape_html(&syntax.name),
+        raw = escape_html(raw),
+        rows = rows,
+        script = SCRIPT,
+    )
+}
+
+fn write_row(rows: &mut String, number: usize, html: &str) {
+    rows.push_str(&format!(
+        "<tr id=\"L{0}\"><td class=\"n\"><a href=\"#L{0}\">{0}</a></td><td class=\"c\">{1}</td></tr>",
+        number, html
+    ));
+}
+
+const STYLE: &str =
+    "body{margin:0;font:14px/1.5 ui-monospace,SFMono-Regular,Menlo,Consolas,monospace}\
+header{padding:.5em 1em;border-bottom:1px solid #8884;font-family:system-ui,sans-serif}\
+header a{color:inherit}table{border-collapse:collapse}td{padding:0 1em;vertical-align:top}\
+td.c{white-space:pre;tab-size:4}td.n{text-align:right;user-select:none;opacity:.6}\
+td.n a{color:inherit;text-decoration:none}tr.hl{background:#fe04}";
+
+/// Highlights the lines in `#L10` or `#L10-L20`; shift-clicking a line
+/// number extends the selection from the first one.
+const SCRIPT: &str = "function mark(){document.querySelectorAll('tr.hl').forEach(function(r){r.classList.remove('hl')});\
+var m=location.hash.match(/^#L(\\d+)(?:-L?(\\d+))?$/);if(!m)return;var a=+m[1],b=+(m[2]||m[1]);\
+if(a>b){var t=a;a=b;b=t}for(var i=a;i<=b;i++){var r=document.getElementById('L'+i);if(r)r.classList.add('hl')}\
+var f=document.getElementById('L'+a);if(f)f.scrollIntoView({block:'center'})}\
+document.addEventListener('click',function(e){var l=e.target.closest('td.n a');var m=location.hash.match(/^#L(\\d+)/);\
+if(l&&e.shiftKey&&m){e.preventDefault();var n=l.textContent;location.hash=+m[1]<=+n?'L'+m[1]+'-L'+n:'L'+n+'-L'+m[1]}});\
+window.addEventListener('hashchange',mark);mark();";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_page() {
+        assert!(has_syntax("/src/main.rs"));
+        assert!(!has_syntax("/image.png"));
+        let html = page(
+            "fn main() {\n    println!(\"<hi>\");\n}\n",
+            "main.rs",
+            "/main.rs?view=raw",
+            "InspiredGitHub",
+            true,
+        );
+        assert!(html.contains("3 lines &middot; Rust"));
+        assert!(html.contains("<tr id=\"L2\"><td class=\"n\"><a href=\"#L2\">2</a></td>"));
+        assert!(html.contains("&lt;hi&gt;"));
+        assert!(html.contains("<a href=\"/main.rs?view=raw\">Raw</a>"));
+        let plain = page(
+            "a < b\n",
+            "notes.txt",
+            "/notes.txt?view=raw",
+            "InspiredGitHub",
+            false,
+        );
+        assert!(plain.contains("<td class=\"c\">a &lt; b</td>"));
+    }
+}
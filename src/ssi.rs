@@ -0,0 +1,579 @@
+use crate::files::{modified_time, Dependencies, StaticFiles};
+use crate::http::*;
+use crate::server::ServerError;
+use chrono::{DateTime, Local, Utc};
+use log::*;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::SystemTime;
+use toml::Value;
+
+/// What Apache shows in place of a directive that failed, unless `#config errmsg` changes it.
+const DEFAULT_ERRMSG: &str = "[an error occurred while processing this directive]";
+const DEFAULT_TIMEFMT: &str = "%A, %d-%b-%Y %H:%M:%S %Z";
+
+/// Which files get server-side includes processed, from `[ssi]`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SsiConfig {
+    pub enabled: bool,
+    pub extensions: Vec<String>,
+    /// How deeply included files may include others.
+    pub max_depth: usize,
+}
+impl Default for SsiConfig {
+    fn default() -> SsiConfig {
+        SsiConfig {
+            enabled: true,
+            extensions: vec!["shtml".to_owned()],
+            max_depth: 8,
+        }
+    }
+}
+impl SsiConfig {
+    pub fn from_toml(cfg: &Value) -> SsiConfig {
+        let mut config = SsiConfig::default();
+        if let Some(Value::Boolean(enabled)) = cfg.get("enabled") {
+            config.enabled = *enabled;
+        }
+        if let Some(Value::Array(extensions)) = cfg.get("extensions") {
+            config.extensions = extensions
+                .iter()
+                .filter_map(|ext| {
+                    ext.as_str()
+                        .map(|ext| ext.trim_start_matches('.').to_owned())
+                })
+                .collect();
+        }
+        if let Some(Value::Integer(max_depth)) = cfg.get("max_depth") {
+            config.max_depth = *max_depth as usize;
+        }
+        config
+    }
+    /// Whether the file at a URL path should be processed.
+    pub fn applies(&self, url_path: &str) -> bool {
+        self.enabled
+            && Path::new(url_path)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+    }
+}
+
+/// Processes the server-side includes in a page.
+pub(crate) async fn render(
+    files: &StaticFiles,
+    request: &HttpRequest,
+    url_path: &str,
+    file_path: &Path,
+) -> Result<Vec<u8>, ServerError> {
+    if let Some(body) = files.rendered.get(url_path).await {
+        return Ok(body);
+    }
+    let modified = modified_time(file_path).await;
+    let source = String::from_utf8_lossy(&tokio::fs::read(file_path).await?).to_string();
+    let mut context = Context {
+        files,
+        variables: HashMap::new(),
+        document_uri: request.path.path().to_owned(),
+        query: request.path.query().unwrap_or("").to_owned(),
+        last_modified: modified,
+        dependencies: vec![(file_path.to_owned(), modified)],
+        volatile: false,
+        timefmt: DEFAULT_TIMEFMT.to_owned(),
+        errmsg: DEFAULT_ERRMSG.to_owned(),
+    };
+    let mut out = String::new();
+    process(&mut context, url_path, &source, 0, &mut out).await;
+    let body = out.into_bytes();
+    if !context.volatile {
+        files
+            .rendered
+            .insert(url_path, body.clone(), context.dependencies);
+    }
+    Ok(body)
+}
+
+/// State while processing one request's page and everything it includes.
+struct Context<'a> {
+    files: &'a StaticFiles,
+    /// Variables from `#set`.
+    variables: HashMap<String, String>,
+    document_uri: String,
+    query: String,
+    last_modified: Option<SystemTime>,
+    dependencies: Dependencies,
+    /// Whether the output depends on the request or the time, so it can't be cached.
+    volatile: bool,
+    timefmt: String,
+    errmsg: String,
+}
+impl<'a> Context<'a> {
+    fn variable(&mut self, name: &str) -> Option<String> {
+        if let Some(value) = self.variables.get(name) {
+            return Some(value.clone());
+        }
+        match name {
+            "DATE_LOCAL" => {
+                self.volatile = true;
+                self.format_time(Local::now())
+            }
+            "DATE_GMT" => {
+                self.volatile = true;
+                self.format_time(Utc::now())
+            }
+            "QUERY_STRING" => {
+                self.volatile = true;
+                Some(self.query.clone())
+            }
+            "DOCUMENT_URI" => Some(self.document_uri.clone()),
+            "DOCUMENT_NAME" => Some(
+                percent_encoding::percent_decode_str(
+                    self.document_uri.rsplit('/').next().unwrap_or(""),
+                )
+                .decode_utf8_lossy()
+                .to_string(),
+            ),
+            "LAST_MODIFIED" => {
+                let modified = DateTime::<Local>::from(self.last_modified?);
+                self.format_time(modified)
+            }
+            _ => None,
+        }
+    }
+    fn format_time<Tz: chrono::TimeZone>(&self, time: DateTime<Tz>) -> Option<String>
+    where
+        Tz::Offset: std::fmt::Display,
+    {
+        // Invalid formats fail when written rather than panicking.
+        let mut out = String::new();
+        write!(out, "{}", time.format(&self.timefmt)).ok()?;
+        Some(out)
+    }
+    /// Replaces `$name` and `${name}` with variables; `\$` is a literal dollar sign.
+    fn substitute(&mut self, text: &str) -> String {
+        let mut out = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&'$') => {
+                    chars.next();
+                    out.push('$');
+                }
+                '$' => {
+                    let mut name = String::new();
+                    if chars.peek() == Some(&'{') {
+                        chars.next();
+                        for c in chars.by_ref() {
+                            if c == '}' {
+                                break;
+                            }
+                            name.push(c);
+                        }
+                    } else {
+                        while let Some(c) =
+                            chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_')
+                        {
+                            name.push(c);
+                        }
+                    }
+                    out.push_str(&self.variable(&name).unwrap_or_default());
+                }
+                c => out.push(c),
+            }
+        }
+        out
+    }
+}
+
+/// The state of one `#if` block.
+struct Condition {
+    /// Whether the block containing this one is being output.
+    parent: bool,
+    /// Whether a branch has already been taken.
+    taken: bool,
+    active: bool,
+}
+
+#[async_recursion::async_recursion]
+async fn process(
+    context: &mut Context<'_>,
+    url_path: &str,
+    source: &str,
+    depth: usize,
+    out: &mut String,
+) {
+    let mut conditions: Vec<Condition> = vec![];
+    let mut rest = source;
+    loop {
+        let active = conditions.last().is_none_or(|condition| condition.active);
+        let (start, end) = match rest.find("<!--#") {
+            Some(start) => match rest[start..].find("-->") {
+                Some(end) => (start, start + end),
+                None => break,
+            },
+            None => break,
+        };
+        if active {
+            out.push_str(&rest[..start]);
+        }
+        let directive = &rest[start + 5..end];
+        rest = &rest[end + 3..];
+        let (name, attributes) = match parse_directive(directive) {
+            Some(parsed) => parsed,
+            None => {
+                if active {
+                    warn!("Malformed directive in {}", url_path);
+                    out.push_str(&context.errmsg);
+                }
+                continue;
+            }
+        };
+        let attribute = |key: &str| {
+            attributes
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.clone())
+        };
+        let result = match (name.as_str(), conditions.last_mut()) {
+            ("if", _) => {
+                let taken = match (active, attribute("expr")) {
+                    (false, _) => Ok(false),
+                    (true, Some(expr)) => evaluate(context, &expr),
+                    (true, None) => Err("#if without expr".to_owned()),
+                };
+                let value = taken.as_ref().is_ok_and(|taken| *taken);
+                // A failed condition still opens a block, so its #endif matches.
+                conditions.push(Condition {
+                    parent: active,
+                    taken: value || taken.is_err(),
+                    active: value,
+                });
+                taken.map(drop)
+            }
+            ("elif", Some(condition)) => {
+                let taken = match (condition.parent && !condition.taken, attribute("expr")) {
+                    (false, _) => Ok(false),
+                    (true, Some(expr)) => evaluate(context, &expr),
+                    (true, None) => Err("#elif without expr".to_owned()),
+                };
+                condition.active = taken.as_ref().is_ok_and(|taken| *taken);
+                condition.taken |= condition.active || taken.is_err();
+                taken.map(drop)
+            }
+            ("else", Some(condition)) => {
+                condition.active = condition.parent && !condition.taken;
+                condition.taken = true;
+                Ok(())
+            }
+            ("endif", Some(_)) => {
+                conditions.pop();
+                Ok(())
+            }
+            ("elif", None) | ("else", None) | ("endif", None) => {
+                Err(format!("#{} without #if", name))
+            }
+            _ if !active => Ok(()),
+            ("include", _) => include(context, url_path, &attributes, depth, out).await,
+            ("echo", _) => match attribute("var") {
+                Some(var) => {
+                    let value = context
+                        .variable(&var)
+                        .unwrap_or_else(|| "(none)".to_owned());
+                    match attribute("encoding").as_deref() {
+                        Some("none") => out.push_str(&value),
+                        Some("url") => out.push_str(&encode_path_segment(&value)),
+                        _ => out.push_str(&escape_html(&value)),
+                    }
+                    Ok(())
+                }
+                None => Err("#echo without var".to_owned()),
+            },
+            ("set", _) => match (attribute("var"), attribute("value")) {
+                (Some(var), Some(value)) => {
+                    let value = context.substitute(&value);
+                    context.variables.insert(var, value);
+                    Ok(())
+                }
+                _ => Err("#set needs var and value".to_owned()),
+            },
+            ("config", _) => {
+                if let Some(timefmt) = attribute("timefmt") {
+                    context.timefmt = timefmt;
+                }
+                if let Some(errmsg) = attribute("errmsg") {
+                    context.errmsg = errmsg;
+                }
+                Ok(())
+            }
+            (name, _) => Err(format!("unknown directive #{}", name)),
+        };
+        if let Err(e) = result {
+            warn!("Server-side include in {}: {}", url_path, e);
+            out.push_str(&context.errmsg);
+        }
+    }
+    if conditions.last().is_none_or(|condition| condition.active) {
+        out.push_str(rest);
+    }
+}
+
+/// Inserts the file named by `virtual` (a URL path, relative to the page or
+/// absolute) or `file` (relative to the page, within its directory).
+async fn include(
+    context: &mut Context<'_>,
+    url_path: &str,
+    attributes: &[(String, String)],
+    depth: usize,
+    out: &mut String,
+) -> Result<(), String> {
+    let base = url::Url::parse("http://localhost")
+        .and_then(|base| base.join(url_path))
+        .map_err(|_| "bad page path".to_owned())?;
+    let target = match attributes.first() {
+        Some((key, value)) if key == "virtual" => context.substitute(value),
+        Some((key, value)) if key == "file" => {
+            let file = context.substitute(value);
+            if file.starts_with('/') || file.split('/').any(|segment| segment == "..") {
+                return Err(format!(
+                    "#include file can't leave the page's directory: {}",
+                    file
+                ));
+            }
+            file
+        }
+        _ => return Err("#include needs virtual or file".to_owned()),
+    };
+    let target = base
+        .join(&target)
+        .map_err(|_| format!("bad include path {}", target))?;
+    let target = target.path();
+    if depth >= context.files.config.ssi.max_depth {
+        return Err(format!("includes nested too deeply at {}", target));
+    }
+    let file_path = context
+        .files
+        .safe_path(target)
+        .ok_or_else(|| format!("can't include {}", target))?;
+    context
+        .dependencies
+        .push((file_path.clone(), modified_time(&file_path).await));
+    let contents = tokio::fs::read(&file_path)
+        .await
+        .map_err(|_| format!("can't read {}", target))?;
+    let contents = String::from_utf8_lossy(&contents);
+    if context.files.config.ssi.applies(target) {
+        process(context, target, &contents, depth + 1, out).await;
+    } else {
+        out.push_str(&contents);
+    }
+    Ok(())
+}
+
+/// Splits `include virtual="/footer.html"` into its name and attributes.
+fn parse_directive(directive: &str) -> Option<(String, Vec<(String, String)>)> {
+    let directive = directive.trim();
+    let name_end = directive
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(directive.len());
+    let name = directive[..name_end].to_ascii_lowercase();
+    if name.is_empty() {
+        return None;
+    }
+    let mut attributes = vec![];
+    let mut rest = directive[name_end..].trim_start();
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let value = value.trim_start();
+        let quote = value
+            .chars()
+            .next()
+            .filter(|c| ['"', '\'', '`'].contains(c))?;
+        let end = value[1..].find(quote)? + 1;
+        attributes.push((key.trim().to_ascii_lowercase(), value[1..end].to_owned()));
+        rest = value[end + 1..].trim_start();
+    }
+    Some((name, attributes))
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Text(String),
+    Regex(String),
+    Equal,
+    NotEqual,
+    Not,
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+/// Evaluates an `#if` expression in Apache's legacy syntax: strings (true if
+/// not empty), `=` and `!=` against a string or `/regex/`, `!`, `&&`, `||`
+/// and parentheses.
+fn evaluate(context: &mut Context<'_>, expr: &str) -> Result<bool, String> {
+    let tokens = tokenize(context, expr)?;
+    let mut position = 0;
+    let value = parse_or(&tokens, &mut position)?;
+    match position == tokens.len() {
+        true => Ok(value),
+        false => Err(format!("unexpected tokens in {}", expr)),
+    }
+}
+
+fn tokenize(context: &mut Context<'_>, expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '=' => Token::Equal,
+            '!' if chars.next_if_eq(&'=').is_some() => Token::NotEqual,
+            '!' => Token::Not,
+            '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+            '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+            '/' if matches!(tokens.last(), Some(Token::Equal) | Some(Token::NotEqual)) => {
+                let mut regex = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') if chars.peek() == Some(&'/') => {
+                            regex.push(chars.next().unwrap())
+                        }
+                        Some('/') => break,
+                        Some(c) => regex.push(c),
+                        None => return Err(format!("unterminated regex in {}", expr)),
+                    }
+                }
+                Token::Regex(regex)
+            }
+            '\'' | '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') if chars.peek() == Some(&c) => text.push(chars.next().unwrap()),
+                        Some(end) if end == c => break,
+                        Some(other) => text.push(other),
+                        None => return Err(format!("unterminated string in {}", expr)),
+                    }
+                }
+                Token::Text(context.substitute(&text))
+            }
+            c => {
+                let mut text = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"()=!&|".contains(*c))
+                {
+                    text.push(c);
+                }
+                Token::Text(context.substitute(&text))
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn parse_or(tokens: &[Token], position: &mut usize) -> Result<bool, String> {
+    let mut value = parse_and(tokens, position)?;
+    while tokens.get(*position) == Some(&Token::Or) {
+        *position += 1;
+        value |= parse_and(tokens, position)?;
+    }
+    Ok(value)
+}
+
+fn parse_and(tokens: &[Token], position: &mut usize) -> Result<bool, String> {
+    let mut value = parse_not(tokens, position)?;
+    while tokens.get(*position) == Some(&Token::And) {
+        *position += 1;
+        value &= parse_not(tokens, position)?;
+    }
+    Ok(value)
+}
+
+fn parse_not(tokens: &[Token], position: &mut usize) -> Result<bool, String> {
+    match tokens.get(*position) {
+        Some(Token::Not) => {
+            *position += 1;
+            Ok(!parse_not(tokens, position)?)
+        }
+        Some(Token::Open) => {
+            *position += 1;
+            let value = parse_or(tokens, position)?;
+            match tokens.get(*position) {
+                Some(Token::Close) => {
+                    *position += 1;
+                    Ok(value)
+                }
+                _ => Err("unbalanced parentheses".to_owned()),
+            }
+        }
+        Some(Token::Text(text)) => {
+            *position += 1;
+            let negate = match tokens.get(*position) {
+                Some(Token::Equal) => false,
+                Some(Token::NotEqual) => true,
+                _ => return Ok(!text.is_empty()),
+            };
+            *position += 1;
+            let matched = match tokens.get(*position) {
+                Some(Token::Text(other)) => text == other,
+                Some(Token::Regex(regex)) => Regex::new(regex)
+                    .map_err(|_| format!("bad regex /{}/", regex))?
+                    .is_match(text),
+                _ => return Err("comparison without a right-hand side".to_owned()),
+            };
+            *position += 1;
+            Ok(matched != negate)
+        }
+        _ => Err("expected a string".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn ssi_render() {
+        let resources = TempDir::new("ssi");
+        let pages = [
+            (
+                "index.shtml",
+                "<!--#set var=\"title\" value=\"Home of $DOCUMENT_NAME\" -->\
+                 <!--#include virtual=\"/parts/header.shtml\" -->\
+                 <!--#if expr=\"$QUERY_STRING = /^lang=fr/\" -->Bonjour\
+                 <!--#elif expr=\"$title && !($title = '')\" -->Hello\
+                 <!--#else -->Hi<!--#endif -->\
+                 <!--#include file=\"../secret\" -->",
+            ),
+            ("parts/header.shtml", "<h1><!--#echo var=\"title\" --></h1>"),
+        ];
+        for (file, contents) in pages.iter() {
+            resources.write(file, contents);
+        }
+        let files = StaticFiles::load(Arc::new(resources.config()))
+            .await
+            .unwrap();
+        let (_, request) = HttpRequest::parse("GET /index.shtml?lang=fr HTTP/1.1\r\n\r\n").unwrap();
+        let body = render(
+            &files,
+            &request,
+            "/index.shtml",
+            &resources.join("index.shtml"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            format!("<h1>Home of index.shtml</h1>Bonjour{}", DEFAULT_ERRMSG)
+        );
+        // Using the query string made the page uncacheable.
+        assert!(files.rendered.get("/index.shtml").await.is_none());
+    }
+}
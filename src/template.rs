@@ -0,0 +1,554 @@
+use crate::files::{modified_time, Dependencies, StaticFiles};
+use crate::http::*;
+use std::collections::HashMap;
+use toml::Value;
+
+/// How deeply layouts may extend layouts, and partials include partials.
+const MAX_DEPTH: usize = 16;
+
+/// Pages rendered as templates, from `[template]`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TemplateConfig {
+    pub enabled: bool,
+    /// Files whose names end with this are rendered.
+    pub suffix: String,
+    /// Values from `[template.vars]`, available to every template.
+    pub vars: toml::value::Table,
+}
+impl Default for TemplateConfig {
+    fn default() -> TemplateConfig {
+        TemplateConfig {
+            enabled: true,
+            suffix: ".tmpl.html".to_owned(),
+            vars: toml::value::Table::new(),
+        }
+    }
+}
+impl TemplateConfig {
+    pub fn from_toml(cfg: &Value) -> TemplateConfig {
+        let mut config = TemplateConfig::default();
+        if let Some(Value::Boolean(enabled)) = cfg.get("enabled") {
+            config.enabled = *enabled;
+        }
+        if let Some(Value::String(suffix)) = cfg.get("suffix") {
+            config.suffix = suffix.clone();
+        }
+        if let Some(Value::Table(vars)) = cfg.get("vars") {
+            config.vars = vars.clone();
+        }
+        config
+    }
+    /// Whether the file at a URL path is a template.
+    pub fn applies(&self, url_path: &str) -> bool {
+        self.enabled && url_path.ends_with(&self.suffix)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Operand {
+    Literal(String),
+    /// A dotted name like `query.page`.
+    Variable(Vec<String>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Expr {
+    Value(Operand),
+    Not(Operand),
+    Compare(Operand, bool, Operand),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Node {
+    Text(String),
+    /// `{{ expr }}`, escaped unless followed by `| raw`.
+    Output(Expr, bool),
+    Set(String, Expr),
+    If(Expr, Vec<Node>, Vec<Node>),
+    Block(String, Vec<Node>),
+    Include(String),
+    Extends(String),
+}
+
+/// Renders a template page for a request.
+///
+/// Templates use `{{ name }}` to output a value, HTML-escaped unless written
+/// `{{ name | raw }}`, and `{# ... #}` for comments. Tags are
+/// `{% extends "/layout.tmpl.html" %}`, `{% block name %}...{% endblock %}`,
+/// `{% include "partial.html" %}`, `{% set name = value %}` and
+/// `{% if value %}...{% else %}...{% endif %}`, where conditions can compare
+/// with `==` and `!=` or negate with `not`. Names are `path`, `query.<name>`,
+/// `header.<name>`, variables from `set`, and `[template.vars]`, optionally
+/// prefixed with `vars.`.
+pub(crate) async fn render(
+    files: &StaticFiles,
+    request: &HttpRequest,
+    url_path: &str,
+) -> Result<Vec<u8>, String> {
+    if let Some(body) = files.rendered.get(url_path).await {
+        return Ok(body);
+    }
+    let rendered = render_page(files, request, url_path, HashMap::new()).await?;
+    if !rendered.volatile {
+        files
+            .rendered
+            .insert(url_path, rendered.body.clone(), rendered.dependencies);
+    }
+    Ok(rendered.body)
+}
+
+/// A rendered template, with what it was rendered from.
+pub(crate) struct Rendered {
+    pub body: Vec<u8>,
+    pub dependencies: Dependencies,
+    /// Whether the output depends on more of the request than its path.
+    pub volatile: bool,
+}
+
+/// Renders a template without caching it, with some variables already set.
+pub(crate) async fn render_page(
+    files: &StaticFiles,
+    request: &HttpRequest,
+    url_path: &str,
+    locals: HashMap<String, String>,
+) -> Result<Rendered, String> {
+    let mut renderer = Renderer {
+        files,
+        path: request.path.path().to_owned(),
+        query: request.query_pairs(),
+        headers: request
+            .headers
+            .iter()
+            .map(|header| (header.name.to_ascii_lowercase(), header.value.clone()))
+            .collect(),
+        locals,
+        blocks: HashMap::new(),
+        dependencies: vec![],
+        volatile: false,
+    };
+    // Collect each page's blocks, then render the layout at the top.
+    let mut current = url_path.to_owned();
+    let mut nodes = renderer.load(&current).await?;
+    for _ in 0..MAX_DEPTH {
+        let parent = match nodes.iter().find(|node| !is_blank(node)) {
+            Some(Node::Extends(parent)) => resolve(&current, parent)?,
+            _ => break,
+        };
+        renderer.collect_blocks(&nodes);
+        for node in &nodes {
+            if let Node::Set(name, expr) = node {
+                let value = renderer.evaluate(expr);
+                renderer.locals.insert(name.clone(), value);
+            }
+        }
+        current = parent;
+        nodes = renderer.load(&current).await?;
+    }
+    let mut out = String::new();
+    renderer.render_nodes(&current, &nodes, 0, &mut out).await?;
+    Ok(Rendered {
+        body: out.into_bytes(),
+        dependencies: renderer.dependencies,
+        volatile: renderer.volatile,
+    })
+}
+
+struct Renderer<'a> {
+    files: &'a StaticFiles,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    /// Variables from `set`.
+    locals: HashMap<String, String>,
+    /// Blocks overridden by the pages extending a layout; the page furthest
+    /// from the layout wins.
+    blocks: HashMap<String, Vec<Node>>,
+    dependencies: Dependencies,
+    /// Whether the output depends on more of the request than its path.
+    volatile: bool,
+}
+impl<'a> Renderer<'a> {
+    async fn load(&mut self, url_path: &str) -> Result<Vec<Node>, String> {
+        let file_path = self
+            .files
+            .safe_path(url_path)
+            .ok_or_else(|| format!("can't use {} as a template", url_path))?;
+        self.dependencies
+            .push((file_path.clone(), modified_time(&file_path).await));
+        let source = tokio::fs::read(&file_path)
+            .await
+            .map_err(|_| format!("can't read {}", url_path))?;
+        parse(&String::from_utf8_lossy(&source)).map_err(|e| format!("{}: {}", url_path, e))
+    }
+    fn collect_blocks(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            match node {
+                Node::Block(name, body) => {
+                    self.blocks
+                        .entry(name.clone())
+                        .or_insert_with(|| body.clone());
+                    self.collect_blocks(body);
+                }
+                Node::If(_, then, otherwise) => {
+                    self.collect_blocks(then);
+                    self.collect_blocks(otherwise);
+                }
+                _ => {}
+            }
+        }
+    }
+    #[async_recursion::async_recursion]
+    async fn render_nodes(
+        &mut self,
+        url_path: &str,
+        nodes: &[Node],
+        depth: usize,
+        out: &mut String,
+    ) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err(format!(
+                "blocks or includes nested too deeply in {}",
+                url_path
+            ));
+        }
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Output(expr, raw) => {
+                    let value = self.evaluate(expr);
+                    match raw {
+                        true => out.push_str(&value),
+                        false => out.push_str(&escape_html(&value)),
+                    }
+                }
+                Node::Set(name, expr) => {
+                    let value = self.evaluate(expr);
+                    self.locals.insert(name.clone(), value);
+                }
+                Node::If(condition, then, otherwise) => {
+                    let branch = match truthy(&self.evaluate(condition)) {
+                        true => then,
+                        false => otherwise,
+                    };
+                    self.render_nodes(url_path, branch, depth, out).await?;
+                }
+                Node::Block(name, default) => {
+                    let body = self.blocks.get(name).unwrap_or(default).clone();
+                    self.render_nodes(url_path, &body, depth + 1, out).await?;
+                }
+                Node::Include(partial) => {
+                    let partial = resolve(url_path, partial)?;
+                    let nodes = self.load(&partial).await?;
+                    self.render_nodes(&partial, &nodes, depth + 1, out).await?;
+                }
+                Node::Extends(_) => return Err(format!("extends must come first in {}", url_path)),
+            }
+        }
+        Ok(())
+    }
+    fn evaluate(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Value(operand) => self.operand(operand),
+            Expr::Not(operand) => match truthy(&self.operand(operand)) {
+                true => String::new(),
+                false => "true".to_owned(),
+            },
+            Expr::Compare(left, equal, right) => {
+                match (self.operand(left) == self.operand(right)) == *equal {
+                    true => "true".to_owned(),
+                    false => String::new(),
+                }
+            }
+        }
+    }
+    fn operand(&mut self, operand: &Operand) -> String {
+        let name = match operand {
+            Operand::Literal(text) => return text.clone(),
+            Operand::Variable(name) => name,
+        };
+        match name
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<&str>>()
+            .as_slice()
+        {
+            ["path"] => self.path.clone(),
+            ["query", key] => {
+                self.volatile = true;
+                self.query
+                    .iter()
+                    .find(|(name, _)| name == key)
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or_default()
+            }
+            ["header", key] | ["headers", key] => {
+                self.volatile = true;
+                let key = key.to_ascii_lowercase();
+                self.headers
+                    .iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or_default()
+            }
+            [local] if self.locals.contains_key(*local) => self.locals[*local].clone(),
+            path => {
+                let path = match path {
+                    ["vars", rest @ ..] => rest,
+                    path => path,
+                };
+                let mut value = None;
+                for key in path {
+                    value = match value {
+                        None => self.files.config.template.vars.get(*key),
+                        Some(value) => value.get(*key),
+                    };
+                    if value.is_none() {
+                        break;
+                    }
+                }
+                match value {
+                    Some(Value::String(text)) => text.clone(),
+                    Some(Value::Table(_)) | Some(Value::Array(_)) | None => String::new(),
+                    Some(other) => other.to_string(),
+                }
+            }
+        }
+    }
+}
+
+fn truthy(value: &str) -> bool {
+    !value.is_empty() && value != "false"
+}
+
+fn is_blank(node: &Node) -> bool {
+    matches!(node, Node::Text(text) if text.trim().is_empty())
+}
+
+/// Resolves a template path relative to the one that names it.
+pub(crate) fn resolve(url_path: &str, target: &str) -> Result<String, String> {
+    url::Url::parse("http://localhost")
+        .and_then(|base| base.join(url_path))
+        .and_then(|base| base.join(target))
+        .map(|url| url.path().to_owned())
+        .map_err(|_| format!("bad template path {}", target))
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    Output(&'a str),
+    Tag(&'a str),
+}
+
+fn parse(source: &str) -> Result<Vec<Node>, String> {
+    let mut pieces = vec![];
+    let mut rest = source;
+    while let Some(start) = rest.find('{') {
+        let close = match &rest[start + 1..].chars().next() {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            Some('#') => "#}",
+            _ => {
+                pieces.push(Piece::Text(&rest[..start + 1]));
+                rest = &rest[start + 1..];
+                continue;
+            }
+        };
+        let end = rest[start + 2..]
+            .find(close)
+            .ok_or_else(|| format!("unclosed {}", &rest[start..start + 2]))?
+            + start
+            + 2;
+        pieces.push(Piece::Text(&rest[..start]));
+        let inner = rest[start + 2..end].trim();
+        match close {
+            "}}" => pieces.push(Piece::Output(inner)),
+            "%}" => pieces.push(Piece::Tag(inner)),
+            _ => {}
+        }
+        rest = &rest[end + 2..];
+    }
+    pieces.push(Piece::Text(rest));
+    let mut pieces = pieces.into_iter();
+    match parse_nodes(&mut pieces)? {
+        (nodes, None) => Ok(nodes),
+        (_, Some(tag)) => Err(format!("unexpected {{% {} %}}", tag)),
+    }
+}
+
+/// Parses up to the end of the input, or a tag that ends the enclosing
+/// block, which is returned with the nodes.
+fn parse_nodes<'a>(
+    pieces: &mut impl Iterator<Item = Piece<'a>>,
+) -> Result<(Vec<Node>, Option<String>), String> {
+    let mut nodes = vec![];
+    while let Some(piece) = pieces.next() {
+        let tag = match piece {
+            Piece::Text("") => continue,
+            Piece::Text(text) => {
+                nodes.push(Node::Text(text.to_owned()));
+                continue;
+            }
+            Piece::Output(inner) => {
+                let (expr, raw) = match inner.rsplit_once('|') {
+                    Some((expr, filter)) if filter.trim() == "raw" => (expr, true),
+                    _ => (inner, false),
+                };
+                nodes.push(Node::Output(parse_expr(expr)?, raw));
+                continue;
+            }
+            Piece::Tag(tag) => tag,
+        };
+        let (keyword, argument) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let argument = argument.trim();
+        match keyword {
+            "extends" | "include" => {
+                let target = match parse_operand(argument)? {
+                    Operand::Literal(target) => target,
+                    _ => return Err(format!("{} needs a quoted path", keyword)),
+                };
+                nodes.push(match keyword {
+                    "extends" => Node::Extends(target),
+                    _ => Node::Include(target),
+                });
+            }
+            "set" => match argument.split_once('=') {
+                Some((name, expr)) if is_name(name.trim()) => {
+                    nodes.push(Node::Set(name.trim().to_owned(), parse_expr(expr)?))
+                }
+                _ => return Err(format!("bad set: {}", argument)),
+            },
+            "block" if is_name(argument) => match parse_nodes(pieces)? {
+                (body, Some(end)) if end == "endblock" => {
+                    nodes.push(Node::Block(argument.to_owned(), body))
+                }
+                _ => return Err(format!("block {} without endblock", argument)),
+            },
+            "if" => {
+                let condition = parse_expr(argument)?;
+                let (then, end) = parse_nodes(pieces)?;
+                let otherwise = match end.as_deref() {
+                    Some("endif") => vec![],
+                    Some("else") => match parse_nodes(pieces)? {
+                        (otherwise, Some(end)) if end == "endif" => otherwise,
+                        _ => return Err("if without endif".to_owned()),
+                    },
+                    _ => return Err("if without endif".to_owned()),
+                };
+                nodes.push(Node::If(condition, then, otherwise));
+            }
+            "else" | "endif" | "endblock" => return Ok((nodes, Some(keyword.to_owned()))),
+            _ => return Err(format!("unknown tag {{% {} %}}", tag)),
+        }
+    }
+    Ok((nodes, None))
+}
+
+fn parse_expr(expr: &str) -> Result<Expr, String> {
+    let expr = expr.trim();
+    if let Some(operand) = expr.strip_prefix("not ") {
+        return Ok(Expr::Not(parse_operand(operand)?));
+    }
+    for (operator, equal) in [("==", true), ("!=", false)].iter() {
+        if let Some((left, right)) = split_outside_quotes(expr, operator) {
+            return Ok(Expr::Compare(
+                parse_operand(left)?,
+                *equal,
+                parse_operand(right)?,
+            ));
+        }
+    }
+    Ok(Expr::Value(parse_operand(expr)?))
+}
+
+fn parse_operand(operand: &str) -> Result<Operand, String> {
+    let operand = operand.trim();
+    for quote in ['"', '\''].iter() {
+        if let Some(inner) = operand
+            .strip_prefix(*quote)
+            .and_then(|operand| operand.strip_suffix(*quote))
+        {
+            return Ok(Operand::Literal(inner.to_owned()));
+        }
+    }
+    let name: Vec<String> = operand.split('.').map(str::to_owned).collect();
+    // Header names have dashes, but nothing else does.
+    match name.iter().all(|part| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    }) {
+        true => Ok(Operand::Variable(name)),
+        false => Err(format!("bad expression: {}", operand)),
+    }
+}
+
+fn split_outside_quotes<'a>(expr: &'a str, operator: &str) -> Option<(&'a str, &'a str)> {
+    let mut quote = None;
+    for (i, c) in expr.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if expr[i..].starts_with(operator) => {
+                return Some((&expr[..i], &expr[i + operator.len()..]))
+            }
+            None => {}
+        }
+    }
+    None
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn template_render() {
+        let resources = TempDir::new("template");
+        let pages = [
+            (
+                "base.tmpl.html",
+                "<title>{% block title %}{{ site }}{% endblock %}</title>{% include \"nav.html\" %}{% block body %}{% endblock %}",
+            ),
+            (
+                "nav.html",
+                "<a{% if path == '/docs/intro.tmpl.html' %} class=\"here\"{% endif %}>{{ vars.site }}</a>",
+            ),
+            (
+                "docs/intro.tmpl.html",
+                "{% extends \"/base.tmpl.html\" %}{% set page = 'Intro' %}\n{% block title %}{{ page }} - {{ site }}{% endblock %}{# unused #}{% block body %}<p>{{ note }} {{ note | raw }}</p>{% endblock %}",
+            ),
+        ];
+        for (file, contents) in pages.iter() {
+            resources.write(file, contents);
+        }
+        let mut config = resources.config();
+        config
+            .template
+            .vars
+            .insert("site".to_owned(), Value::String("Docs".to_owned()));
+        config
+            .template
+            .vars
+            .insert("note".to_owned(), Value::String("<b>".to_owned()));
+        let files = StaticFiles::load(Arc::new(config)).await.unwrap();
+        let (_, request) =
+            HttpRequest::parse("GET /docs/intro.tmpl.html HTTP/1.1\r\n\r\n").unwrap();
+        let body = render(&files, &request, "/docs/intro.tmpl.html")
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "<title>Intro - Docs</title><a class=\"here\">Docs</a><p>&lt;b&gt; <b></p>"
+        );
+        assert!(files.rendered.get("/docs/intro.tmpl.html").await.is_some());
+        assert!(parse("{% if x %}unterminated").is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! Fixtures shared by the unit tests.
+
+use crate::server::Config;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A scratch directory under the system temp dir, removed when dropped, so
+/// it is cleaned up even when an assertion fails.
+pub struct TempDir {
+    path: PathBuf,
+}
+impl TempDir {
+    /// Creates an empty `gc-<name>-<uuid>` directory.
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("gc-{}-{}", name, uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+    /// Writes a file, creating the directories above it.
+    pub fn write(&self, relative: &str, contents: impl AsRef<[u8]>) {
+        let path = self.path.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    /// A config with the directory as its resources root and logging off.
+    pub fn config(&self) -> Config {
+        Config::new(
+            15000,
+            self.path.clone(),
+            vec![],
+            HashMap::new(),
+            log::LevelFilter::Off,
+        )
+    }
+}
+impl Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
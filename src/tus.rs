@@ -0,0 +1,489 @@
+use crate::files::{error_response, upload_name, StaticFiles};
+use crate::http::*;
+use crate::router::Handler;
+use crate::server::{Config, ServerError};
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use toml::Value;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,creation-with-upload,termination,expiration";
+
+/// Settings for the tus resumable upload endpoint, from the `[tus]` table.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TusConfig {
+    /// The URL path uploads are created under.
+    pub path: String,
+    /// The URL path, in a writable location, that finished uploads are moved to.
+    pub destination: String,
+    /// Where partial uploads are kept, outside the resources root.
+    pub staging: PathBuf,
+    pub max_size: u64,
+    /// Seconds an unfinished upload is kept after its last write.
+    pub expiration: i64,
+}
+impl TusConfig {
+    pub fn from_toml(cfg: &Value) -> Result<TusConfig, ServerError> {
+        let mut config = TusConfig {
+            path: "/uploads/".to_owned(),
+            destination: match cfg.get("destination") {
+                Some(Value::String(destination)) if destination.starts_with('/') => {
+                    destination.clone()
+                }
+                _ => return Err(ServerError::ConfigError),
+            },
+            staging: PathBuf::from("./uploads/"),
+            max_size: 64 * 1024 * 1024 * 1024,
+            expiration: 24 * 3600,
+        };
+        if let Some(Value::String(path)) = cfg.get("path") {
+            config.path = path.clone();
+        }
+        if let Some(Value::String(staging)) = cfg.get("staging") {
+            config.staging = PathBuf::from(staging);
+        }
+        if let Some(Value::Integer(max_size)) = cfg.get("max_size") {
+            config.max_size = *max_size as u64;
+        }
+        if let Some(Value::Integer(expiration)) = cfg.get("expiration") {
+            config.expiration = *expiration;
+        }
+        Ok(config)
+    }
+}
+
+/// What is known about an upload, kept next to its data in the staging directory.
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadInfo {
+    length: u64,
+    filename: String,
+    /// The `Upload-Metadata` header it was created with.
+    metadata: Option<String>,
+    user: String,
+    /// Unix time after which it is deleted if still unfinished.
+    expires: i64,
+}
+
+/// A tus 1.0 endpoint. Uploads are staged until every byte has arrived, then
+/// moved into the resources root, so a dropped connection can resume with a
+/// `PATCH` from the last offset the server confirmed.
+#[derive(Clone)]
+pub struct Tus {
+    config: Arc<Config>,
+    files: StaticFiles,
+}
+impl Tus {
+    pub fn new(files: StaticFiles) -> Tus {
+        Tus {
+            config: files.config.clone(),
+            files,
+        }
+    }
+    fn tus(&self) -> &TusConfig {
+        self.config.tus.as_ref().expect("tus is not configured")
+    }
+    async fn serve(&self, request: &mut HttpRequest) -> Result<HttpResponse, ServerError> {
+        if request.method == HttpMethod::Options {
+            return Ok(HttpResponse::new()
+                .status(204)
+                .header("Tus-Resumable", TUS_VERSION)
+                .header("Tus-Version", TUS_VERSION)
+                .header("Tus-Extension", TUS_EXTENSIONS)
+                .header("Tus-Max-Size", &self.tus().max_size.to_string())
+                .build());
+        }
+        if request.header("Tus-Resumable") != Some(TUS_VERSION) {
+            let mut response = error_response(412);
+            response.set_header("Tus-Version", TUS_VERSION);
+            return Ok(response);
+        }
+        let user = match self.authorize(request) {
+            Some(user) => user,
+            None => {
+                let mut response = error_response(401);
+                response.set_header(
+                    "WWW-Authenticate",
+                    "Basic realm=\"GlassCannon\", charset=\"UTF-8\"",
+                );
+                return Ok(response);
+            }
+        };
+        let endpoint = self.tus().path.trim_end_matches('/').to_owned();
+        let id = request
+            .path
+            .path()
+            .strip_prefix(&endpoint)
+            .unwrap_or("")
+            .trim_matches('/')
+            .to_owned();
+        let mut response = match (&request.method, id.as_str()) {
+            (HttpMethod::Post, "") => self.create(request, &user, &endpoint).await?,
+            (_, "") => error_response(405),
+            // Ids are generated here, so anything else can't name an upload.
+            (_, id) if !id.chars().all(|c| c.is_ascii_hexdigit()) => error_response(404),
+            (HttpMethod::Head, id) => self.offset(id, &user).await?,
+            (HttpMethod::Patch, id) => self.append(request, id, &user).await?,
+            (HttpMethod::Delete, id) => self.terminate(id, &user).await?,
+            _ => error_response(405),
+        };
+        response.set_header("Tus-Resumable", TUS_VERSION);
+        Ok(response)
+    }
+    /// Finished uploads land in a writable location, so its users may upload.
+    fn authorize(&self, request: &HttpRequest) -> Option<String> {
+        let location = self.config.location(&self.tus().destination)?;
+        match location.writable {
+            true => request.basic_auth_user(&location.users),
+            false => None,
+        }
+    }
+    /// Creates an upload, optionally with the first chunk in the same request.
+    async fn create(
+        &self,
+        request: &mut HttpRequest,
+        user: &str,
+        endpoint: &str,
+    ) -> Result<HttpResponse, ServerError> {
+        self.expire().await;
+        let length = match request.header("Upload-Length").map(str::parse::<u64>) {
+            Some(Ok(length)) => length,
+            _ => return Ok(error_response(400)),
+        };
+        if length > self.tus().max_size {
+            return Ok(error_response(413));
+        }
+        let metadata = request.header("Upload-Metadata").map(str::to_owned);
+        let filename = metadata
+            .as_deref()
+            .and_then(|metadata| metadata_value(metadata, "filename"))
+            .and_then(|filename| upload_name(&filename).map(str::to_owned));
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let info = UploadInfo {
+            length,
+            filename: filename.unwrap_or_else(|| id.clone()),
+            metadata,
+            user: user.to_owned(),
+            expires: Utc::now().timestamp() + self.tus().expiration,
+        };
+        tokio::fs::create_dir_all(&self.tus().staging).await?;
+        File::create(self.data_path(&id)).await?;
+        self.save_info(&id, &info).await?;
+        info!(
+            "{} started uploading {} ({} bytes)",
+            user, info.filename, length
+        );
+        let location = format!("{}/{}", endpoint, id);
+        if request.has_content_type("application/offset+octet-stream") {
+            let mut response = self.receive(request, &id, info, 0).await?;
+            if response.status.value == 204 {
+                response.status = HttpStatus::new(201).unwrap();
+                response.set_header("Location", &location);
+            }
+            return Ok(response);
+        }
+        if length == 0 {
+            if let Some(status) = self.finish(&id, &info).await? {
+                return Ok(error_response(status));
+            }
+        }
+        Ok(HttpResponse::new()
+            .status(201)
+            .header("Location", &location)
+            .header("Upload-Expires", &http_date(info.expires))
+            .build())
+    }
+    /// Reports how much of an upload has arrived.
+    async fn offset(&self, id: &str, user: &str) -> Result<HttpResponse, ServerError> {
+        let info = match self.load_owned(id, user).await? {
+            Some(info) => info,
+            None => return Ok(error_response(404)),
+        };
+        let offset = tokio::fs::metadata(self.data_path(id)).await?.len();
+        let mut response = HttpResponse::new()
+            .status(200)
+            .header("Upload-Offset", &offset.to_string())
+            .header("Upload-Length", &info.length.to_string())
+            .header("Upload-Expires", &http_date(info.expires))
+            .header("Cache-Control", "no-store")
+            .build();
+        if let Some(metadata) = &info.metadata {
+            response.set_header("Upload-Metadata", metadata);
+        }
+        Ok(response)
+    }
+    /// Appends the request body at `Upload-Offset`, keeping whatever arrives
+    /// even if the connection drops partway.
+    async fn append(
+        &self,
+        request: &mut HttpRequest,
+        id: &str,
+        user: &str,
+    ) -> Result<HttpResponse, ServerError> {
+        if !request.has_content_type("application/offset+octet-stream") {
+            return Ok(error_response(415));
+        }
+        let info = match self.load_owned(id, user).await? {
+            Some(info) => info,
+            None => return Ok(error_response(404)),
+        };
+        let offset = tokio::fs::metadata(self.data_path(id)).await?.len();
+        if request.header("Upload-Offset") != Some(offset.to_string().as_str()) {
+            return Ok(error_response(409));
+        }
+        self.receive(request, id, info, offset).await
+    }
+    /// Writes the request body after the `offset` bytes already received.
+    async fn receive(
+        &self,
+        request: &mut HttpRequest,
+        id: &str,
+        mut info: UploadInfo,
+        offset: u64,
+    ) -> Result<HttpResponse, ServerError> {
+        let data_path = self.data_path(id);
+        if offset + request.body.len() > info.length {
+            return Ok(error_response(413));
+        }
+        let mut file = OpenOptions::new().append(true).open(&data_path).await?;
+        let mut buffer = vec![0; 64 * 1024];
+        let mut written = 0;
+        loop {
+            let count = match request.body.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(e) => {
+                    warn!("Upload {} interrupted: {}", id, e);
+                    break;
+                }
+            };
+            file.write_all(&buffer[..count]).await?;
+            written += count as u64;
+        }
+        file.sync_all().await?;
+        let offset = offset + written;
+        info.expires = Utc::now().timestamp() + self.tus().expiration;
+        if offset == info.length {
+            if let Some(status) = self.finish(id, &info).await? {
+                return Ok(error_response(status));
+            }
+        } else {
+            self.save_info(id, &info).await?;
+        }
+        Ok(HttpResponse::new()
+            .status(204)
+            .header("Upload-Offset", &offset.to_string())
+            .header("Upload-Expires", &http_date(info.expires))
+            .build())
+    }
+    /// Abandons an upload and deletes what has arrived so far.
+    async fn terminate(&self, id: &str, user: &str) -> Result<HttpResponse, ServerError> {
+        if self.load_owned(id, user).await?.is_none() {
+            return Ok(error_response(404));
+        }
+        self.remove(id).await;
+        Ok(HttpResponse::new().status(204).build())
+    }
+    /// Moves a complete upload into the destination directory. Returns the
+    /// status to refuse it with if the destination isn't safe to write to.
+    async fn finish(&self, id: &str, info: &UploadInfo) -> Result<Option<usize>, ServerError> {
+        let url_path = format!(
+            "{}/{}",
+            self.tus().destination.trim_end_matches('/'),
+            encode_path_segment(&info.filename)
+        );
+        let file_path = self
+            .files
+            .safe_path(&url_path)
+            .ok_or(ServerError::FileLoadError)?;
+        let dir = file_path.parent().unwrap_or(&self.config.resources);
+        if let Some(status) = self.files.prepare_dir(dir).await? {
+            warn!("Refused to move upload {} to {}", id, url_path);
+            self.remove(id).await;
+            return Ok(Some(status));
+        }
+        let data_path = self.data_path(id);
+        // Staging may be on another filesystem, where renaming fails.
+        if tokio::fs::rename(&data_path, &file_path).await.is_err() {
+            let temp_path = file_path.with_file_name(format!(".{}.{}.tmp", info.filename, id));
+            tokio::fs::copy(&data_path, &temp_path).await?;
+            tokio::fs::rename(&temp_path, &file_path).await?;
+        }
+        self.remove(id).await;
+        self.files.invalidate(&url_path, &file_path).await;
+        info!("{} uploaded {}", info.user, url_path);
+        Ok(None)
+    }
+    /// Deletes unfinished uploads that have expired.
+    async fn expire(&self) {
+        let mut entries = match tokio::fs::read_dir(&self.tus().staging).await {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let now = Utc::now().timestamp();
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("info") {
+                continue;
+            }
+            let id = path.file_stem().and_then(|id| id.to_str()).unwrap_or("");
+            if let Ok(Some(info)) = self.load_info(id).await {
+                if info.expires < now {
+                    info!("Upload {} of {} expired", id, info.filename);
+                    self.remove(id).await;
+                }
+            }
+        }
+    }
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.tus().staging.join(id)
+    }
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.tus().staging.join(format!("{}.info", id))
+    }
+    /// Reads an upload's info, or `None` if it doesn't exist or has expired.
+    async fn load_info(&self, id: &str) -> Result<Option<UploadInfo>, ServerError> {
+        let path = self.info_path(id);
+        if !Path::new(&path).is_file() {
+            return Ok(None);
+        }
+        let mut contents = vec![];
+        File::open(&path).await?.read_to_end(&mut contents).await?;
+        let info: UploadInfo =
+            serde_json::from_slice(&contents).map_err(|_| ServerError::FileLoadError)?;
+        if info.expires < Utc::now().timestamp() {
+            self.remove(id).await;
+            return Ok(None);
+        }
+        Ok(Some(info))
+    }
+    /// Reads an upload's info if `user` created it. Others are told it doesn't
+    /// exist, so ids can't be probed.
+    async fn load_owned(&self, id: &str, user: &str) -> Result<Option<UploadInfo>, ServerError> {
+        Ok(self.load_info(id).await?.filter(|info| info.user == user))
+    }
+    async fn save_info(&self, id: &str, info: &UploadInfo) -> Result<(), ServerError> {
+        let contents = serde_json::to_vec(info).map_err(|_| ServerError::FileLoadError)?;
+        File::create(self.info_path(id))
+            .await?
+            .write_all(&contents)
+            .await?;
+        Ok(())
+    }
+    async fn remove(&self, id: &str) {
+        let _ = tokio::fs::remove_file(self.data_path(id)).await;
+        let _ = tokio::fs::remove_file(self.info_path(id)).await;
+    }
+}
+
+#[async_trait]
+impl Handler for Tus {
+    async fn handle(&self, request: HttpRequest) -> HttpResponse {
+        let mut request = request;
+        match self.serve(&mut request).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!(
+                    "Could not handle upload {}: {}",
+                    request.path.path(),
+                    e.message()
+                );
+                error_response(500)
+            }
+        }
+    }
+}
+
+/// Decodes one value from an `Upload-Metadata` header, a comma-separated list
+/// of keys and base64 values.
+fn metadata_value(metadata: &str, key: &str) -> Option<String> {
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+        if parts.next()? != key {
+            return None;
+        }
+        let value = base64::decode(parts.next().unwrap_or("").trim()).ok()?;
+        String::from_utf8(value).ok()
+    })
+}
+
+fn http_date(timestamp: i64) -> String {
+    Utc.timestamp_opt(timestamp, 0)
+        .single()
+        .map(|date| date.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Location;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn upload_metadata() {
+        let metadata = "relativePath bnVsbA==,filename YnVpbGQudGFyLmd6,is_confidential";
+        assert_eq!(
+            metadata_value(metadata, "filename"),
+            Some("build.tar.gz".to_owned())
+        );
+        assert_eq!(
+            metadata_value(metadata, "is_confidential"),
+            Some(String::new())
+        );
+        assert_eq!(metadata_value(metadata, "filetype"), None);
+    }
+
+    #[tokio::test]
+    async fn upload_owner() {
+        let root = TempDir::new("tus-owner");
+        std::fs::create_dir_all(root.join("res")).unwrap();
+        let mut config = root.config();
+        config.resources = root.join("res");
+        let mut location = Location::new("/files/");
+        location.writable = true;
+        location.users.insert("ci".to_owned(), "one".to_owned());
+        location.users.insert("dev".to_owned(), "two".to_owned());
+        config.locations = vec![location];
+        let mut tus = TusConfig::from_toml(&"destination = '/files/'".parse().unwrap()).unwrap();
+        tus.staging = root.join("staging");
+        config.tus = Some(tus);
+        let tus = Tus::new(StaticFiles::load(Arc::new(config)).await.unwrap());
+        let request = |head: &str, user: &str| {
+            let head = format!(
+                "{}\r\nTus-Resumable: 1.0.0\r\nAuthorization: Basic {}\r\n\r\n",
+                head,
+                base64::encode(user)
+            );
+            HttpRequest::parse(&head).unwrap().1
+        };
+        let created = tus
+            .handle(request(
+                "POST /uploads/ HTTP/1.1\r\nUpload-Length: 4",
+                "ci:one",
+            ))
+            .await;
+        assert_eq!(created.status.value, 201);
+        let upload = created.header("Location").unwrap().to_owned();
+        let head = format!("HEAD {} HTTP/1.1", upload);
+        assert_eq!(tus.handle(request(&head, "ci:one")).await.status.value, 200);
+        assert_eq!(
+            tus.handle(request(&head, "dev:two")).await.status.value,
+            404
+        );
+        let delete = format!("DELETE {} HTTP/1.1", upload);
+        assert_eq!(
+            tus.handle(request(&delete, "dev:two")).await.status.value,
+            404
+        );
+        assert_eq!(
+            tus.handle(request(&delete, "ci:one")).await.status.value,
+            204
+        );
+    }
+}
@@ -0,0 +1,522 @@
+use crate::http::*;
+use crate::proxy;
+use crate::server::ServerError;
+use log::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use toml::Value;
+
+/// Points each backend gets on the hash ring per unit of weight, so keys
+/// spread evenly and only move off a backend that goes away.
+const RING_POINTS: u32 = 100;
+
+/// What requests are hashed on to keep them on the same backend.
+#[derive(Debug, PartialEq, Clone)]
+pub enum HashKey {
+    Header(String),
+    Cookie(String),
+    ClientIp,
+}
+impl HashKey {
+    /// Reads `ip`, `header:Name` or `cookie:name`.
+    pub fn new(value: &str) -> Option<HashKey> {
+        match value.split_once(':') {
+            None if value == "ip" => Some(HashKey::ClientIp),
+            Some(("header", name)) if !name.is_empty() => Some(HashKey::Header(name.to_owned())),
+            Some(("cookie", name)) if !name.is_empty() => Some(HashKey::Cookie(name.to_owned())),
+            _ => None,
+        }
+    }
+    fn value(&self, request: &HttpRequest) -> Option<String> {
+        match self {
+            HashKey::Header(name) => request.header(name).map(str::to_owned),
+            HashKey::Cookie(name) => request.cookie(name),
+            HashKey::ClientIp => request.remote_addr.map(|addr| addr.ip().to_string()),
+        }
+    }
+}
+
+/// How a pool picks a backend for each request.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Strategy {
+    /// In turn, in proportion to their weights.
+    RoundRobin,
+    /// The one with the fewest open requests for its weight.
+    LeastConnections,
+    /// The same backend for the same key while it is up. Requests without
+    /// the key are shared round-robin.
+    Hash(HashKey),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BackendConfig {
+    /// `host:port`.
+    pub address: String,
+    pub weight: u32,
+}
+
+/// Requests sent to each backend in the background to see if it is up.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HealthCheckConfig {
+    pub path: String,
+    /// Seconds between checks.
+    pub interval: u64,
+    /// Seconds to wait for an answer.
+    pub timeout: u64,
+    /// Failed checks in a row that take a backend out of the pool.
+    pub fails: u32,
+    /// Passed checks in a row that put it back.
+    pub passes: u32,
+}
+impl HealthCheckConfig {
+    pub fn from_toml(cfg: &Value) -> HealthCheckConfig {
+        let mut config = HealthCheckConfig {
+            path: "/".to_owned(),
+            interval: 10,
+            timeout: 2,
+            fails: 3,
+            passes: 2,
+        };
+        if let Some(Value::String(path)) = cfg.get("path") {
+            config.path = path.clone();
+        }
+        if let Some(Value::Integer(interval)) = cfg.get("interval") {
+            config.interval = (*interval).max(1) as u64;
+        }
+        if let Some(Value::Integer(timeout)) = cfg.get("timeout") {
+            config.timeout = *timeout as u64;
+        }
+        if let Some(Value::Integer(fails)) = cfg.get("fails") {
+            config.fails = (*fails).max(1) as u32;
+        }
+        if let Some(Value::Integer(passes)) = cfg.get("passes") {
+            config.passes = (*passes).max(1) as u32;
+        }
+        config
+    }
+}
+
+/// A named group of backends that proxies can forward to, from an `[[upstream]]` table.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UpstreamConfig {
+    /// Used as the host of a proxy's `upstream` URL, like `http://api/`.
+    pub name: String,
+    pub backends: Vec<BackendConfig>,
+    pub strategy: Strategy,
+    pub health_check: Option<HealthCheckConfig>,
+    /// Failed requests in a row that take a backend out of the pool; 0 never does.
+    pub max_fails: u32,
+    /// Seconds a backend stays out after `max_fails`, before it is tried again.
+    pub fail_timeout: u64,
+}
+impl UpstreamConfig {
+    pub fn from_toml(cfg: &Value) -> Result<UpstreamConfig, ServerError> {
+        let name = match cfg.get("name") {
+            Some(Value::String(name)) if !name.is_empty() => name.clone(),
+            _ => return Err(ServerError::ConfigError),
+        };
+        let mut backends = vec![];
+        if let Some(Value::Array(cfg_backends)) = cfg.get("backends") {
+            for cfg_backend in cfg_backends {
+                backends.push(match cfg_backend {
+                    Value::String(address) => BackendConfig {
+                        address: address.clone(),
+                        weight: 1,
+                    },
+                    Value::Table(table) => BackendConfig {
+                        address: match table.get("address") {
+                            Some(Value::String(address)) => address.clone(),
+                            _ => return Err(ServerError::ConfigError),
+                        },
+                        weight: match table.get("weight") {
+                            Some(Value::Integer(weight)) if *weight > 0 => *weight as u32,
+                            None => 1,
+                            _ => return Err(ServerError::ConfigError),
+                        },
+                    },
+                    _ => return Err(ServerError::ConfigError),
+                });
+            }
+        }
+        if backends.is_empty() {
+            return Err(ServerError::ConfigError);
+        }
+        let strategy = match cfg.get("strategy").and_then(|strategy| strategy.as_str()) {
+            None | Some("round_robin") => Strategy::RoundRobin,
+            Some("least_connections") => Strategy::LeastConnections,
+            Some("hash") => match cfg.get("hash_key").and_then(|key| key.as_str()) {
+                Some(key) => Strategy::Hash(HashKey::new(key).ok_or(ServerError::ConfigError)?),
+                None => return Err(ServerError::ConfigError),
+            },
+            Some(_) => return Err(ServerError::ConfigError),
+        };
+        let mut config = UpstreamConfig {
+            name,
+            backends,
+            strategy,
+            health_check: cfg.get("health_check").map(HealthCheckConfig::from_toml),
+            max_fails: 3,
+            fail_timeout: 30,
+        };
+        if let Some(Value::Integer(max_fails)) = cfg.get("max_fails") {
+            config.max_fails = *max_fails as u32;
+        }
+        if let Some(Value::Integer(fail_timeout)) = cfg.get("fail_timeout") {
+            config.fail_timeout = *fail_timeout as u64;
+        }
+        Ok(config)
+    }
+}
+
+/// How a backend is doing, as seen by its pool.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BackendStatus {
+    pub address: String,
+    pub weight: u32,
+    /// Whether it passes health checks; always true without them.
+    pub healthy: bool,
+    /// Whether it is out of the pool for failing requests.
+    pub ejected: bool,
+    /// Requests it is answering right now.
+    pub active: usize,
+    /// Requests that failed in a row.
+    pub fails: u32,
+}
+
+#[derive(Debug)]
+struct BackendState {
+    healthy: bool,
+    /// Health checks passed in a row, or failed in a row when negative.
+    streak: i64,
+    fails: u32,
+    ejected_until: Option<Instant>,
+    /// For smooth weighted round-robin.
+    current_weight: i64,
+}
+
+#[derive(Debug)]
+struct Backend {
+    config: BackendConfig,
+    active: AtomicUsize,
+    state: Mutex<BackendState>,
+}
+
+/// A pool of backends, shared by the proxies that forward to it.
+#[derive(Debug)]
+pub struct Upstream {
+    config: UpstreamConfig,
+    backends: Vec<Backend>,
+    /// Hash ring points and the backends they belong to, in order.
+    ring: Vec<(u64, usize)>,
+    /// Where least-connections starts looking, so ties take turns.
+    next: AtomicUsize,
+}
+impl Upstream {
+    pub fn new(config: UpstreamConfig) -> Upstream {
+        let mut ring = vec![];
+        for (index, backend) in config.backends.iter().enumerate() {
+            for point in 0..backend.weight * RING_POINTS {
+                ring.push((hash(&format!("{}#{}", backend.address, point)), index));
+            }
+        }
+        ring.sort_unstable();
+        Upstream {
+            backends: config
+                .backends
+                .iter()
+                .map(|backend| Backend {
+                    config: backend.clone(),
+                    active: AtomicUsize::new(0),
+                    state: Mutex::new(BackendState {
+                        healthy: true,
+                        streak: 0,
+                        fails: 0,
+                        ejected_until: None,
+                        current_weight: 0,
+                    }),
+                })
+                .collect(),
+            ring,
+            next: AtomicUsize::new(0),
+            config,
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+    /// The state of each backend, in configured order.
+    pub fn status(&self) -> Vec<BackendStatus> {
+        let now = Instant::now();
+        self.backends
+            .iter()
+            .map(|backend| {
+                let state = backend.state.lock().unwrap();
+                BackendStatus {
+                    address: backend.config.address.clone(),
+                    weight: backend.config.weight,
+                    healthy: state.healthy,
+                    ejected: state.ejected_until.is_some_and(|until| until > now),
+                    active: backend.active.load(Ordering::SeqCst),
+                    fails: state.fails,
+                }
+            })
+            .collect()
+    }
+    /// Whether a backend can take requests, putting it back in the pool if
+    /// its ejection is over.
+    fn available(&self, index: usize) -> bool {
+        let backend = &self.backends[index];
+        let mut state = backend.state.lock().unwrap();
+        if let Some(until) = state.ejected_until {
+            if until > Instant::now() {
+                return false;
+            }
+            info!(
+                "Upstream {}: trying {} again",
+                self.config.name, backend.config.address
+            );
+            state.ejected_until = None;
+            state.fails = 0;
+        }
+        state.healthy
+    }
+    /// Picks a backend for a request, other than ones already tried.
+    pub(crate) fn pick(&self, request: &HttpRequest, tried: &[usize]) -> Option<usize> {
+        let candidates: Vec<usize> = (0..self.backends.len())
+            .filter(|index| !tried.contains(index) && self.available(*index))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        match &self.config.strategy {
+            Strategy::RoundRobin => Some(self.round_robin(&candidates)),
+            Strategy::LeastConnections => {
+                let start = self.next.fetch_add(1, Ordering::SeqCst);
+                let load = |index: &usize| {
+                    let backend = &self.backends[*index];
+                    backend.active.load(Ordering::SeqCst) as f64 / backend.config.weight as f64
+                };
+                (0..candidates.len())
+                    .map(|offset| candidates[(start + offset) % candidates.len()])
+                    .min_by(|a, b| load(a).total_cmp(&load(b)))
+            }
+            Strategy::Hash(key) => match key.value(request) {
+                Some(value) => {
+                    let point = hash(&value);
+                    let start = self.ring.partition_point(|(position, _)| *position < point);
+                    (0..self.ring.len())
+                        .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+                        .find(|index| candidates.contains(index))
+                }
+                None => Some(self.round_robin(&candidates)),
+            },
+        }
+    }
+    /// Smooth weighted round-robin, which spreads a heavy backend's turns out
+    /// rather than giving them all at once.
+    fn round_robin(&self, candidates: &[usize]) -> usize {
+        let total: i64 = candidates
+            .iter()
+            .map(|index| self.backends[*index].config.weight as i64)
+            .sum();
+        let mut best: Option<(usize, i64)> = None;
+        for index in candidates {
+            let backend = &self.backends[*index];
+            let mut state = backend.state.lock().unwrap();
+            state.current_weight += backend.config.weight as i64;
+            if best.is_none_or(|(_, weight)| state.current_weight > weight) {
+                best = Some((*index, state.current_weight));
+            }
+        }
+        let (index, _) = best.unwrap_or((candidates[0], 0));
+        self.backends[index].state.lock().unwrap().current_weight -= total;
+        index
+    }
+    pub(crate) fn address(&self, index: usize) -> &str {
+        &self.backends[index].config.address
+    }
+    /// Counts a request as open on a backend until the lease is dropped.
+    pub(crate) fn lease(self: &Arc<Upstream>, index: usize) -> Lease {
+        self.backends[index].active.fetch_add(1, Ordering::SeqCst);
+        Lease {
+            upstream: self.clone(),
+            index,
+        }
+    }
+    /// Takes a backend out of the pool for a while once requests to it keep failing.
+    fn report(&self, index: usize, ok: bool) {
+        let backend = &self.backends[index];
+        let mut state = backend.state.lock().unwrap();
+        if ok {
+            state.fails = 0;
+            return;
+        }
+        state.fails += 1;
+        if self.config.max_fails > 0
+            && state.fails >= self.config.max_fails
+            && state.ejected_until.is_none()
+        {
+            warn!(
+                "Upstream {}: taking {} out for {}s after {} failed requests",
+                self.config.name, backend.config.address, self.config.fail_timeout, state.fails
+            );
+            state.ejected_until =
+                Some(Instant::now() + Duration::from_secs(self.config.fail_timeout));
+        }
+    }
+    /// Checks every backend on the configured interval, for as long as the
+    /// runtime does.
+    pub fn spawn_health_checks(self: &Arc<Upstream>) {
+        let check = match &self.config.health_check {
+            Some(check) => check.clone(),
+            None => return,
+        };
+        let upstream = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(check.interval));
+            loop {
+                interval.tick().await;
+                for (index, backend) in upstream.backends.iter().enumerate() {
+                    let passed = tokio::time::timeout(
+                        Duration::from_secs(check.timeout),
+                        probe(&backend.config.address, upstream.name(), &check.path),
+                    )
+                    .await
+                    .unwrap_or(false);
+                    upstream.record_check(index, passed, &check);
+                }
+            }
+        });
+    }
+    fn record_check(&self, index: usize, passed: bool, check: &HealthCheckConfig) {
+        let backend = &self.backends[index];
+        let mut state = backend.state.lock().unwrap();
+        state.streak = match (passed, state.streak) {
+            (true, streak) if streak > 0 => streak + 1,
+            (true, _) => 1,
+            (false, streak) if streak < 0 => streak - 1,
+            (false, _) => -1,
+        };
+        if state.healthy && -state.streak >= check.fails as i64 {
+            warn!(
+                "Upstream {}: {} failed {} health checks",
+                self.config.name, backend.config.address, check.fails
+            );
+            state.healthy = false;
+        } else if !state.healthy && state.streak >= check.passes as i64 {
+            info!(
+                "Upstream {}: {} is healthy again",
+                self.config.name, backend.config.address
+            );
+            state.healthy = true;
+        }
+    }
+}
+
+/// An open request on a backend.
+pub(crate) struct Lease {
+    upstream: Arc<Upstream>,
+    index: usize,
+}
+impl Lease {
+    pub(crate) fn address(&self) -> &str {
+        self.upstream.address(self.index)
+    }
+    /// Records whether the backend answered the request.
+    pub(crate) fn report(&self, ok: bool) {
+        self.upstream.report(self.index, ok);
+    }
+}
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.upstream.backends[self.index]
+            .active
+            .fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether a backend answers a health check with a 2xx or 3xx status.
+async fn probe(address: &str, host: &str, path: &str) -> bool {
+    let mut stream = match TcpStream::connect(address).await {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: GlassCannon health check\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    if stream.write_all(request.as_bytes()).await.is_err() {
+        return false;
+    }
+    match proxy::read_head(stream).await {
+        Ok(Some((response, _, _))) => (200..400).contains(&response.status.value),
+        _ => false,
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the standard hasher is the same in every build.
+pub(crate) fn hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(toml: &str) -> Arc<Upstream> {
+        Arc::new(Upstream::new(
+            UpstreamConfig::from_toml(&toml::from_str(toml).unwrap()).unwrap(),
+        ))
+    }
+    fn request(headers: &str) -> HttpRequest {
+        HttpRequest::parse(&format!("GET / HTTP/1.1\r\n{}\r\n", headers))
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn upstream_balancing() {
+        let pool = upstream(
+            "name = \"api\"\nbackends = [{ address = \"a:80\", weight = 2 }, \"b:80\"]\nmax_fails = 2\nfail_timeout = 60",
+        );
+        let picks: Vec<usize> = (0..6)
+            .map(|_| pool.pick(&request(""), &[]).unwrap())
+            .collect();
+        assert_eq!(picks, vec![0, 1, 0, 0, 1, 0]);
+        pool.report(0, false);
+        assert!(!pool.status()[0].ejected);
+        pool.report(0, false);
+        assert!(pool.status()[0].ejected);
+        assert_eq!(pool.pick(&request(""), &[]), Some(1));
+        assert_eq!(pool.pick(&request(""), &[1]), None);
+
+        let pool = upstream(
+            "name = \"api\"\nbackends = [\"a:80\", \"b:80\"]\nstrategy = \"least_connections\"",
+        );
+        let lease = pool.lease(0);
+        assert_eq!(pool.status()[0].active, 1);
+        assert_eq!(pool.pick(&request(""), &[]), Some(1));
+        drop(lease);
+        assert_eq!(pool.status()[0].active, 0);
+
+        let pool = upstream(
+            "name = \"api\"\nbackends = [\"a:80\", \"b:80\", \"c:80\"]\nstrategy = \"hash\"\nhash_key = \"header:X-User\"\nhealth_check = { fails = 1 }",
+        );
+        let user = request("X-User: 42\r\n");
+        let first = pool.pick(&user, &[]).unwrap();
+        assert!((0..10).all(|_| pool.pick(&user, &[]) == Some(first)));
+        let check = pool.config.health_check.clone().unwrap();
+        pool.record_check(first, false, &check);
+        assert!(!pool.status()[first].healthy);
+        let moved = pool.pick(&user, &[]).unwrap();
+        assert_ne!(moved, first);
+        pool.record_check(first, true, &check);
+        pool.record_check(first, true, &check);
+        assert_eq!(pool.pick(&user, &[]), Some(first));
+    }
+}
@@ -0,0 +1,101 @@
+use crate::server::ServerError;
+use log::*;
+use notify::{RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// Watches the resources root and reports which URL paths changed on disk.
+pub struct ResourceWatcher {
+    // Dropping the watcher stops the notifications, so it has to be kept around.
+    _watcher: notify::RecommendedWatcher,
+    events: UnboundedReceiver<PathBuf>,
+    root: PathBuf,
+    debounce: Duration,
+    pending: HashSet<String>,
+}
+impl ResourceWatcher {
+    pub fn new(root: &Path, debounce: Duration) -> Result<ResourceWatcher, ServerError> {
+        let root = root.canonicalize()?;
+        let (sender, events) = unbounded_channel();
+        let mut watcher = notify::recommended_watcher(
+            move |result: notify::Result<notify::Event>| match result {
+                Ok(event) => {
+                    if event.kind.is_access() {
+                        return;
+                    }
+                    for path in event.paths {
+                        let _ = sender.send(path);
+                    }
+                }
+                Err(e) => warn!("File watcher error: {}", e),
+            },
+        )?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+        Ok(ResourceWatcher {
+            _watcher: watcher,
+            events,
+            root,
+            debounce,
+            pending: HashSet::new(),
+        })
+    }
+    /// Waits for changes and returns the URL paths that changed once the
+    /// filesystem has been quiet for the debounce period.
+    ///
+    /// Safe to cancel: changes seen so far are kept for the next call.
+    pub async fn changed(&mut self) -> Vec<String> {
+        loop {
+            let event = if self.pending.is_empty() {
+                self.events.recv().await
+            } else {
+                match tokio::time::timeout(self.debounce, self.events.recv()).await {
+                    Ok(event) => event,
+                    Err(_) => return self.pending.drain().collect(),
+                }
+            };
+            match event {
+                Some(path) => {
+                    if let Some(url_path) = self.url_path(&path) {
+                        self.pending.insert(url_path);
+                    }
+                }
+                None if self.pending.is_empty() => std::future::pending::<()>().await,
+                None => return self.pending.drain().collect(),
+            }
+        }
+    }
+    fn url_path(&self, path: &Path) -> Option<String> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let mut url_path = String::new();
+        for component in relative.components() {
+            url_path.push('/');
+            url_path.push_str(component.as_os_str().to_str()?);
+        }
+        Some(url_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn watch_changes() {
+        let root = TempDir::new("watch");
+        std::fs::create_dir_all(root.join("css")).unwrap();
+        let mut watcher = ResourceWatcher::new(&root, Duration::from_millis(100)).unwrap();
+        root.write("index.html", "<p>");
+        root.write("css/site.css", "p {}");
+        root.write("index.html", "<p>again");
+        // A burst of writes arrives as one batch, each path once.
+        let mut changed = tokio::time::timeout(Duration::from_secs(5), watcher.changed())
+            .await
+            .unwrap();
+        changed.sort();
+        assert_eq!(changed, vec!["/css/site.css", "/index.html"]);
+        assert_eq!(watcher.url_path(Path::new("/etc/passwd")), None);
+    }
+}
@@ -0,0 +1,814 @@
+use crate::files::{error_response, StaticFiles};
+use crate::http::*;
+use crate::server::ServerError;
+use chrono::{DateTime, SecondsFormat, Utc};
+use roxmltree::{Document, Node};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Locks last this long when the client doesn't ask for a timeout.
+const DEFAULT_LOCK_TIMEOUT: u64 = 3600;
+/// The longest a lock may last without being refreshed.
+const MAX_LOCK_TIMEOUT: u64 = 24 * 3600;
+/// Properties every resource has, as reported by `allprop` and `propname`.
+const LIVE_PROPERTIES: [&str; 9] = [
+    "creationdate",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "resourcetype",
+    "supportedlock",
+    "lockdiscovery",
+];
+
+/// The WebDAV methods a location answers, on top of the plain HTTP ones.
+pub fn methods(writable: bool) -> Vec<HttpMethod> {
+    let names: &[&str] = if writable {
+        &[
+            "PROPFIND",
+            "PROPPATCH",
+            "MKCOL",
+            "COPY",
+            "MOVE",
+            "LOCK",
+            "UNLOCK",
+        ]
+    } else {
+        &["PROPFIND"]
+    };
+    names
+        .iter()
+        .map(|name| HttpMethod::Extension((*name).to_owned()))
+        .collect()
+}
+
+pub fn is_propfind(method: &HttpMethod) -> bool {
+    *method == HttpMethod::Extension("PROPFIND".to_owned())
+}
+
+#[derive(Debug, Clone)]
+struct Lock {
+    token: String,
+    /// The locked resource, as a key from `key`.
+    path: String,
+    /// The locked resource as the client named it.
+    href: String,
+    depth_infinity: bool,
+    exclusive: bool,
+    owner: Option<String>,
+    expires: Instant,
+}
+impl Lock {
+    /// Whether the lock applies to `key` itself.
+    fn covers(&self, key: &str) -> bool {
+        self.path == key || (self.depth_infinity && is_beneath(key, &self.path))
+    }
+    fn activelock(&self) -> String {
+        let remaining = self.expires.saturating_duration_since(Instant::now());
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:{}/></D:lockscope><D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+            if self.exclusive { "exclusive" } else { "shared" },
+            if self.depth_infinity { "infinity" } else { "0" },
+            match &self.owner {
+                Some(owner) => format!("<D:owner>{}</D:owner>", owner),
+                None => String::new(),
+            },
+            remaining.as_millis().div_ceil(1000),
+            self.token,
+            self.href,
+        )
+    }
+}
+
+/// Dead properties of one resource, by `(namespace, name)`.
+type Properties = HashMap<(String, String), String>;
+
+/// Locks and dead properties, shared by every clone of a `StaticFiles`.
+/// Neither survives a restart.
+#[derive(Default)]
+pub(crate) struct DavState {
+    locks: Mutex<Vec<Lock>>,
+    /// Properties set with PROPPATCH, by resource.
+    properties: Mutex<HashMap<String, Properties>>,
+}
+impl DavState {
+    fn locks(&self) -> std::sync::MutexGuard<'_, Vec<Lock>> {
+        let mut locks = self.locks.lock().unwrap();
+        let now = Instant::now();
+        locks.retain(|lock| lock.expires > now);
+        locks
+    }
+    /// Whether a write to `url_path` is blocked by a lock whose token the
+    /// request doesn't submit in its `If` header.
+    pub(crate) fn locked(&self, request: &HttpRequest, url_path: &str) -> bool {
+        let key = key(url_path);
+        let submitted = request.header("If").unwrap_or("");
+        let overlapping: Vec<Lock> = self
+            .locks()
+            .iter()
+            .filter(|lock| lock.covers(&key) || is_beneath(&lock.path, &key))
+            .cloned()
+            .collect();
+        !overlapping.is_empty()
+            && !overlapping
+                .iter()
+                .any(|lock| submitted.contains(&lock.token))
+    }
+    /// Drops the locks and properties of a resource and everything beneath it.
+    pub(crate) fn forget(&self, url_path: &str) {
+        let key = key(url_path);
+        self.locks()
+            .retain(|lock| lock.path != key && !is_beneath(&lock.path, &key));
+        self.properties
+            .lock()
+            .unwrap()
+            .retain(|path, _| *path != key && !is_beneath(path, &key));
+    }
+    /// Gives a copied or moved resource the properties of its source.
+    fn copy_properties(&self, from: &str, to: &str) {
+        let (from, to) = (key(from), key(to));
+        let mut properties = self.properties.lock().unwrap();
+        let copied: Vec<(String, Properties)> = properties
+            .iter()
+            .filter(|(path, _)| **path == from || is_beneath(path, &from))
+            .map(|(path, props)| (format!("{}{}", to, &path[from.len()..]), props.clone()))
+            .collect();
+        properties.extend(copied);
+    }
+}
+
+/// Answers a WebDAV method other than PROPFIND. The caller has already
+/// checked that `user` may write here.
+pub(crate) async fn handle(
+    files: &StaticFiles,
+    request: &mut HttpRequest,
+    user: &str,
+    url_path: &str,
+    file_path: &Path,
+) -> Result<HttpResponse, ServerError> {
+    let method = request.method.to_string();
+    let checks_lock = method != "LOCK" && method != "UNLOCK" && method != "COPY";
+    if checks_lock && files.dav.locked(request, url_path) {
+        return Ok(error_response(423));
+    }
+    match method.as_str() {
+        "PROPPATCH" => proppatch(files, request, url_path, file_path).await,
+        "MKCOL" => mkcol(files, request, url_path, file_path).await,
+        "COPY" | "MOVE" => transfer(files, request, user, url_path, file_path).await,
+        "LOCK" => lock(files, request, url_path, file_path).await,
+        "UNLOCK" => Ok(unlock(files, request, url_path)),
+        _ => Ok(error_response(405)),
+    }
+}
+
+enum PropfindKind {
+    AllProperties,
+    Names,
+    Properties(Vec<(String, String)>),
+}
+
+/// Lists the properties of a resource and, with `Depth: 1`, its children.
+pub(crate) async fn propfind(
+    files: &StaticFiles,
+    request: &mut HttpRequest,
+) -> Result<HttpResponse, ServerError> {
+    let url_path = request.path.path().to_owned();
+    let file_path = match files.safe_path(&url_path) {
+        Some(file_path) if file_path.exists() => file_path,
+        _ => return Ok(error_response(404)),
+    };
+    let depth_one = match request.header("Depth") {
+        Some("0") => false,
+        Some("1") => true,
+        _ => {
+            // Walking a whole tree for one request is too easy to abuse.
+            return Ok(HttpResponse::new()
+                .status(403)
+                .header("Content-Type", "application/xml; charset=utf-8")
+                .body(
+                    xml("<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>")
+                        .into_bytes(),
+                )
+                .build());
+        }
+    };
+    let body = match request.body.to_vec().await {
+        Ok(body) => String::from_utf8_lossy(&body).to_string(),
+        Err(e) => return Ok(error_response(e.status())),
+    };
+    let kind = match parse_propfind(&body) {
+        Some(kind) => kind,
+        None => return Ok(error_response(400)),
+    };
+    let metadata = tokio::fs::metadata(&file_path).await?;
+    let mut href = url_path.clone();
+    if metadata.is_dir() && !href.ends_with('/') {
+        href.push('/');
+    }
+    let mut entries = vec![];
+    if depth_one && metadata.is_dir() {
+        let mut children = tokio::fs::read_dir(&file_path).await?;
+        while let Some(child) = children.next_entry().await? {
+            let name = child.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+            let child_metadata = child.metadata().await?;
+            let mut child_href = format!("{}{}", href, encode_path_segment(&name));
+            if child_metadata.is_dir() {
+                child_href.push('/');
+            }
+            entries.push((child_href, child_metadata));
+        }
+    }
+    entries.insert(0, (href, metadata));
+    let responses: Vec<String> = entries
+        .iter()
+        .map(|(href, metadata)| propfind_response(files, href, metadata, &kind))
+        .collect();
+    Ok(multistatus(&responses))
+}
+
+fn parse_propfind(body: &str) -> Option<PropfindKind> {
+    if body.trim().is_empty() {
+        return Some(PropfindKind::AllProperties);
+    }
+    let document = Document::parse(body).ok()?;
+    let root = document.root_element();
+    if !is_dav(root, "propfind") {
+        return None;
+    }
+    for child in root.children().filter(Node::is_element) {
+        if is_dav(child, "allprop") {
+            return Some(PropfindKind::AllProperties);
+        } else if is_dav(child, "propname") {
+            return Some(PropfindKind::Names);
+        } else if is_dav(child, "prop") {
+            return Some(PropfindKind::Properties(
+                child
+                    .children()
+                    .filter(Node::is_element)
+                    .map(|prop| qualified_name(&prop))
+                    .collect(),
+            ));
+        }
+    }
+    None
+}
+
+fn propfind_response(
+    files: &StaticFiles,
+    href: &str,
+    metadata: &Metadata,
+    kind: &PropfindKind,
+) -> String {
+    let resource = key(href);
+    let dead = files
+        .dav
+        .properties
+        .lock()
+        .unwrap()
+        .get(&resource)
+        .cloned()
+        .unwrap_or_default();
+    let mut found = vec![];
+    let mut missing = vec![];
+    match kind {
+        PropfindKind::Names => {
+            for name in LIVE_PROPERTIES.iter() {
+                if live_property(files, href, metadata, name).is_some() {
+                    found.push(format!("<D:{}/>", name));
+                }
+            }
+            for (namespace, name) in dead.keys() {
+                found.push(property_element(namespace, name, None));
+            }
+        }
+        PropfindKind::AllProperties => {
+            for name in LIVE_PROPERTIES.iter() {
+                if let Some(value) = live_property(files, href, metadata, name) {
+                    found.push(format!("<D:{0}>{1}</D:{0}>", name, value));
+                }
+            }
+            for ((namespace, name), value) in &dead {
+                found.push(property_element(namespace, name, Some(value)));
+            }
+        }
+        PropfindKind::Properties(names) => {
+            for (namespace, name) in names {
+                let live = match namespace.as_str() {
+                    "DAV:" => live_property(files, href, metadata, name),
+                    _ => None,
+                };
+                if let Some(value) = live {
+                    found.push(format!("<D:{0}>{1}</D:{0}>", name, value));
+                } else if let Some(value) = dead.get(&(namespace.clone(), name.clone())) {
+                    found.push(property_element(namespace, name, Some(value)));
+                } else {
+                    missing.push(property_element(namespace, name, None));
+                }
+            }
+        }
+    }
+    let mut response = format!("<D:response><D:href>{}</D:href>", href);
+    response.push_str(&propstat(&found, 200));
+    if !missing.is_empty() {
+        response.push_str(&propstat(&missing, 404));
+    }
+    response.push_str("</D:response>");
+    response
+}
+
+/// The XML content of a `DAV:` property, or `None` if the resource doesn't have it.
+fn live_property(
+    files: &StaticFiles,
+    href: &str,
+    metadata: &Metadata,
+    name: &str,
+) -> Option<String> {
+    let modified: DateTime<Utc> = metadata.modified().ok()?.into();
+    Some(match name {
+        "creationdate" => {
+            let created: DateTime<Utc> = metadata.created().map(|c| c.into()).unwrap_or(modified);
+            created.to_rfc3339_opts(SecondsFormat::Secs, true)
+        }
+        "displayname" => {
+            let name = href.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+            escape_html(&percent_encoding::percent_decode_str(name).decode_utf8_lossy())
+        }
+        "getcontentlength" if metadata.is_file() => metadata.len().to_string(),
+        "getcontenttype" if metadata.is_file() => files.config.mimetype(href).to_owned(),
+        "getetag" if metadata.is_file() => {
+            format!("\"{:x}-{:x}\"", metadata.len(), modified.timestamp())
+        }
+        "getlastmodified" => modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        "resourcetype" if metadata.is_dir() => "<D:collection/>".to_owned(),
+        "resourcetype" => String::new(),
+        "supportedlock" => ["exclusive", "shared"]
+            .iter()
+            .map(|scope| {
+                format!(
+                    "<D:lockentry><D:lockscope><D:{}/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>",
+                    scope
+                )
+            })
+            .collect(),
+        "lockdiscovery" => {
+            let resource = key(href);
+            files
+                .dav
+                .locks()
+                .iter()
+                .filter(|lock| lock.covers(&resource))
+                .map(|lock| lock.activelock())
+                .collect()
+        }
+        _ => return None,
+    })
+}
+
+/// Sets and removes dead properties. Either every change applies or none do.
+async fn proppatch(
+    files: &StaticFiles,
+    request: &mut HttpRequest,
+    url_path: &str,
+    file_path: &Path,
+) -> Result<HttpResponse, ServerError> {
+    if !file_path.exists() {
+        return Ok(error_response(404));
+    }
+    let body = match request.body.to_vec().await {
+        Ok(body) => String::from_utf8_lossy(&body).to_string(),
+        Err(e) => return Ok(error_response(e.status())),
+    };
+    let document = match Document::parse(&body) {
+        Ok(document) if is_dav(document.root_element(), "propertyupdate") => document,
+        _ => return Ok(error_response(400)),
+    };
+    let mut changes = vec![];
+    for instruction in document.root_element().children().filter(Node::is_element) {
+        let set = is_dav(instruction, "set");
+        if !set && !is_dav(instruction, "remove") {
+            continue;
+        }
+        let props = instruction.children().filter(|n| is_dav(*n, "prop"));
+        for prop in props.flat_map(|prop| prop.children().filter(Node::is_element)) {
+            let value = match set {
+                true => Some(escape_html(&text(&prop))),
+                false => None,
+            };
+            changes.push((qualified_name(&prop), value));
+        }
+    }
+    // Live properties are computed from the file, so they can't be changed.
+    let protected = changes
+        .iter()
+        .any(|((namespace, _), _)| namespace == "DAV:");
+    let mut succeeded = vec![];
+    let mut forbidden = vec![];
+    let mut failed = vec![];
+    for ((namespace, name), _) in &changes {
+        let element = property_element(namespace, name, None);
+        if namespace == "DAV:" {
+            forbidden.push(element);
+        } else if protected {
+            failed.push(element);
+        } else {
+            succeeded.push(element);
+        }
+    }
+    if !protected {
+        let mut properties = files.dav.properties.lock().unwrap();
+        let resource = properties.entry(key(url_path)).or_default();
+        for (name, value) in changes {
+            match value {
+                Some(value) => resource.insert(name, value),
+                None => resource.remove(&name),
+            };
+        }
+    }
+    let mut response = format!("<D:response><D:href>{}</D:href>", url_path);
+    for (elements, status) in [(succeeded, 200), (forbidden, 403), (failed, 424)].iter() {
+        if !elements.is_empty() {
+            response.push_str(&propstat(elements, *status));
+        }
+    }
+    response.push_str("</D:response>");
+    Ok(multistatus(&[response]))
+}
+
+/// Creates a collection, whose parent must already exist.
+async fn mkcol(
+    files: &StaticFiles,
+    request: &HttpRequest,
+    url_path: &str,
+    file_path: &Path,
+) -> Result<HttpResponse, ServerError> {
+    if !request.body.is_empty() {
+        return Ok(error_response(415));
+    } else if file_path.exists() {
+        return Ok(error_response(405));
+    } else if !file_path.parent().is_some_and(|parent| parent.is_dir()) {
+        return Ok(error_response(409));
+    }
+    tokio::fs::create_dir(file_path).await?;
+    files.invalidate(url_path, file_path).await;
+    Ok(HttpResponse::new().status(201).build())
+}
+
+/// Copies or moves a resource to the `Destination` header's path.
+async fn transfer(
+    files: &StaticFiles,
+    request: &HttpRequest,
+    user: &str,
+    url_path: &str,
+    file_path: &Path,
+) -> Result<HttpResponse, ServerError> {
+    let moving = request.method.to_string() == "MOVE";
+    if !file_path.exists() {
+        return Ok(error_response(404));
+    }
+    let destination = match request.header("Destination").map(Url::parse) {
+        Some(Ok(url)) => {
+            let host = match url.port() {
+                Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+                None => url.host_str().unwrap_or("").to_owned(),
+            };
+            if let Some(requested) = request.header("Host") {
+                if !requested.eq_ignore_ascii_case(&host) {
+                    return Ok(error_response(502));
+                }
+            }
+            url.path().to_owned()
+        }
+        Some(Err(_)) => match request.header("Destination") {
+            Some(path) if path.starts_with('/') => path.to_owned(),
+            _ => return Ok(error_response(400)),
+        },
+        None => return Ok(error_response(400)),
+    };
+    // The destination needs the same write access as the source.
+    let writable = match files.config.location(&destination) {
+        Some(location) => location.webdav && location.writable && location.users.contains_key(user),
+        None => false,
+    };
+    let destination_path = match files.safe_path(&destination) {
+        Some(destination_path) if writable => destination_path,
+        _ => return Ok(error_response(403)),
+    };
+    let (source_key, destination_key) = (key(url_path), key(&destination));
+    if source_key == destination_key || is_beneath(&destination_key, &source_key) {
+        return Ok(error_response(403));
+    }
+    if files.dav.locked(request, &destination) {
+        return Ok(error_response(423));
+    }
+    let existed = destination_path.exists();
+    if existed && request.header("Overwrite") == Some("F") {
+        return Ok(error_response(412));
+    } else if !destination_path
+        .parent()
+        .is_some_and(|parent| parent.is_dir())
+    {
+        return Ok(error_response(409));
+    }
+    if existed {
+        remove(&destination_path).await?;
+        files.dav.forget(&destination);
+    }
+    if moving {
+        tokio::fs::rename(file_path, &destination_path).await?;
+        files.dav.copy_properties(url_path, &destination);
+        files.dav.forget(url_path);
+        files.invalidate(url_path, file_path).await;
+    } else {
+        let shallow = request.header("Depth") == Some("0");
+        copy(file_path.to_owned(), destination_path.clone(), shallow).await?;
+        files.dav.copy_properties(url_path, &destination);
+    }
+    files.invalidate(&destination, &destination_path).await;
+    Ok(HttpResponse::new()
+        .status(if existed { 204 } else { 201 })
+        .build())
+}
+
+/// Creates a lock, or refreshes one when the request has no body.
+async fn lock(
+    files: &StaticFiles,
+    request: &mut HttpRequest,
+    url_path: &str,
+    file_path: &Path,
+) -> Result<HttpResponse, ServerError> {
+    let timeout = request
+        .header("Timeout")
+        .and_then(|timeout| timeout.split(',').next())
+        .map(|timeout| match timeout.trim() {
+            "Infinite" => MAX_LOCK_TIMEOUT,
+            timeout => timeout
+                .strip_prefix("Second-")
+                .and_then(|seconds| seconds.parse().ok())
+                .unwrap_or(DEFAULT_LOCK_TIMEOUT),
+        })
+        .unwrap_or(DEFAULT_LOCK_TIMEOUT)
+        .min(MAX_LOCK_TIMEOUT);
+    let expires = Instant::now() + Duration::from_secs(timeout);
+    let resource = key(url_path);
+    let body = match request.body.to_vec().await {
+        Ok(body) => String::from_utf8_lossy(&body).to_string(),
+        Err(e) => return Ok(error_response(e.status())),
+    };
+    if body.trim().is_empty() {
+        let submitted = request.header("If").unwrap_or("");
+        let mut locks = files.dav.locks();
+        let refreshed = locks
+            .iter_mut()
+            .find(|lock| lock.covers(&resource) && submitted.contains(&lock.token));
+        return Ok(match refreshed {
+            Some(lock) => {
+                lock.expires = expires;
+                lock_response(200, lock)
+            }
+            None => error_response(412),
+        });
+    }
+    let document = match Document::parse(&body) {
+        Ok(document) if is_dav(document.root_element(), "lockinfo") => document,
+        _ => return Ok(error_response(400)),
+    };
+    let info = document.root_element();
+    let exclusive = info.descendants().any(|node| is_dav(node, "exclusive"));
+    let owner = info
+        .children()
+        .find(|node| is_dav(*node, "owner"))
+        .map(
+            |owner| match owner.children().find(|node| is_dav(*node, "href")) {
+                Some(href) => format!("<D:href>{}</D:href>", escape_html(&text(&href))),
+                None => escape_html(&text(&owner)),
+            },
+        );
+    let new_lock = Lock {
+        token: format!("opaquelocktoken:{}", uuid::Uuid::new_v4()),
+        path: resource.clone(),
+        href: url_path.to_owned(),
+        depth_infinity: request.header("Depth") != Some("0"),
+        exclusive,
+        owner,
+        expires,
+    };
+    let conflict = files.dav.locks().iter().any(|lock| {
+        let overlaps = lock.covers(&resource)
+            || (new_lock.depth_infinity && is_beneath(&lock.path, &resource));
+        overlaps && (lock.exclusive || exclusive)
+    });
+    if conflict {
+        return Ok(error_response(423));
+    }
+    // Locking an unmapped URL reserves it with an empty file.
+    let status = if file_path.exists() {
+        200
+    } else if file_path.parent().is_some_and(|parent| parent.is_dir()) {
+        tokio::fs::File::create(file_path).await?;
+        files.invalidate(url_path, file_path).await;
+        201
+    } else {
+        return Ok(error_response(409));
+    };
+    let response = lock_response(status, &new_lock);
+    files.dav.locks().push(new_lock);
+    Ok(response)
+}
+
+fn lock_response(status: usize, lock: &Lock) -> HttpResponse {
+    HttpResponse::new()
+        .status(status)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .header("Lock-Token", &format!("<{}>", lock.token))
+        .body(
+            xml(&format!(
+                "<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>",
+                lock.activelock()
+            ))
+            .into_bytes(),
+        )
+        .build()
+}
+
+fn unlock(files: &StaticFiles, request: &HttpRequest, url_path: &str) -> HttpResponse {
+    let token = request
+        .header("Lock-Token")
+        .unwrap_or("")
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    let resource = key(url_path);
+    let mut locks = files.dav.locks();
+    let count = locks.len();
+    locks.retain(|lock| !(lock.token == token && lock.covers(&resource)));
+    if locks.len() < count {
+        HttpResponse::new().status(204).build()
+    } else {
+        error_response(409)
+    }
+}
+
+/// Removes a file, or a directory and everything in it.
+async fn remove(path: &Path) -> Result<(), ServerError> {
+    if path.is_dir() {
+        tokio::fs::remove_dir_all(path).await?;
+    } else {
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+/// Copies a file, or a directory and (unless `shallow`) everything in it.
+#[async_recursion::async_recursion]
+async fn copy(from: PathBuf, to: PathBuf, shallow: bool) -> Result<(), ServerError> {
+    if from.is_dir() {
+        tokio::fs::create_dir(&to).await?;
+        if !shallow {
+            let mut entries = tokio::fs::read_dir(&from).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                copy(entry.path(), to.join(entry.file_name()), false).await?;
+            }
+        }
+    } else {
+        tokio::fs::copy(&from, &to).await?;
+    }
+    Ok(())
+}
+
+fn multistatus(responses: &[String]) -> HttpResponse {
+    HttpResponse::new()
+        .status(207)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(
+            xml(&format!(
+                "<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+                responses.concat()
+            ))
+            .into_bytes(),
+        )
+        .build()
+}
+
+fn propstat(properties: &[String], status: usize) -> String {
+    let reason = match status {
+        200 => "OK",
+        403 => "Forbidden",
+        404 => "Not Found",
+        _ => "Failed Dependency",
+    };
+    format!(
+        "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {} {}</D:status></D:propstat>",
+        properties.concat(),
+        status,
+        reason
+    )
+}
+
+/// A property element in its own namespace, empty or holding `value`.
+fn property_element(namespace: &str, name: &str, value: Option<&String>) -> String {
+    if namespace == "DAV:" {
+        return match value {
+            Some(value) => format!("<D:{0}>{1}</D:{0}>", name, value),
+            None => format!("<D:{}/>", name),
+        };
+    }
+    let namespace = escape_html(namespace);
+    match value {
+        Some(value) => format!("<P:{0} xmlns:P=\"{1}\">{2}</P:{0}>", name, namespace, value),
+        None => format!("<P:{} xmlns:P=\"{}\"/>", name, namespace),
+    }
+}
+
+fn xml(body: &str) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n{}", body)
+}
+
+fn is_dav(node: Node, name: &str) -> bool {
+    node.is_element()
+        && node.tag_name().namespace() == Some("DAV:")
+        && node.tag_name().name() == name
+}
+
+fn qualified_name(node: &Node) -> (String, String) {
+    let name = node.tag_name();
+    (
+        name.namespace().unwrap_or("").to_owned(),
+        name.name().to_owned(),
+    )
+}
+
+fn text(node: &Node) -> String {
+    node.descendants()
+        .filter(Node::is_text)
+        .filter_map(|node| node.text())
+        .collect()
+}
+
+/// Identifies a resource however its URL path is encoded or terminated.
+fn key(url_path: &str) -> String {
+    let decoded = percent_encoding::percent_decode_str(url_path).decode_utf8_lossy();
+    match decoded.trim_end_matches('/') {
+        "" => "/".to_owned(),
+        trimmed => trimmed.to_owned(),
+    }
+}
+
+/// Whether the resource `key` is inside the collection `parent`.
+fn is_beneath(key: &str, parent: &str) -> bool {
+    key.len() > parent.len()
+        && key.starts_with(parent)
+        && (parent == "/" || key.as_bytes()[parent.len()] == b'/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn propfind_bodies() {
+        assert!(matches!(
+            parse_propfind(""),
+            Some(PropfindKind::AllProperties)
+        ));
+        let names = "<?xml version=\"1.0\"?><propfind xmlns=\"DAV:\"><propname/></propfind>";
+        assert!(matches!(parse_propfind(names), Some(PropfindKind::Names)));
+        let props = "<D:propfind xmlns:D=\"DAV:\" xmlns:Z=\"urn:z\"><D:prop><D:getetag/><Z:color/></D:prop></D:propfind>";
+        match parse_propfind(props) {
+            Some(PropfindKind::Properties(names)) => assert_eq!(
+                names,
+                vec![
+                    ("DAV:".to_owned(), "getetag".to_owned()),
+                    ("urn:z".to_owned(), "color".to_owned())
+                ]
+            ),
+            _ => panic!("expected a property list"),
+        }
+        assert!(parse_propfind("<propfind/>").is_none());
+    }
+
+    #[test]
+    fn lock_scope() {
+        let lock = Lock {
+            token: "opaquelocktoken:1".to_owned(),
+            path: key("/dav/docs/"),
+            href: "/dav/docs/".to_owned(),
+            depth_infinity: true,
+            exclusive: true,
+            owner: None,
+            expires: Instant::now(),
+        };
+        assert!(lock.covers(&key("/dav/docs")));
+        assert!(lock.covers(&key("/dav/docs/a%20b.txt")));
+        assert!(!lock.covers(&key("/dav/docsets")));
+        assert!(is_beneath("/a", "/"));
+    }
+}
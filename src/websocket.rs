@@ -0,0 +1,439 @@
+use crate::files::error_response;
+use crate::http::*;
+use log::*;
+use sha1::{Digest, Sha1};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// Appended to the client's key to make `Sec-WebSocket-Accept` (RFC 6455 section 4.2.2).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Messages bigger than this, in bytes, close the connection with 1009.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Close codes for what went wrong, from RFC 6455 section 7.4.1.
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const TOO_BIG: u16 = 1009;
+/// Never sent: the connection ended without a close frame.
+const ABNORMAL: u16 = 1006;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Answered with a pong before it is returned.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// A status code and reason, if the other side gave them.
+    Close(Option<(u16, String)>),
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    base64::encode(Sha1::digest(format!("{}{}", key.trim(), GUID).as_bytes()))
+}
+
+/// Whether a request asks to switch to the WebSocket protocol.
+pub fn is_upgrade(request: &HttpRequest) -> bool {
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    request.method == HttpMethod::Get
+        && has_token("Upgrade", "websocket")
+        && has_token("Connection", "upgrade")
+}
+
+/// The subprotocols the client offers in `Sec-WebSocket-Protocol`, most preferred
+/// first. To pick one, set the header on the response from `upgrade`.
+pub fn protocols(request: &HttpRequest) -> Vec<String> {
+    request
+        .header("Sec-WebSocket-Protocol")
+        .unwrap_or("")
+        .split(',')
+        .map(|protocol| protocol.trim().to_owned())
+        .filter(|protocol| !protocol.is_empty())
+        .collect()
+}
+
+/// Answers a WebSocket handshake, then runs `on_open` with the connection.
+/// Requests that aren't a valid handshake get an error response instead.
+pub fn upgrade<F, Fut>(request: &HttpRequest, on_open: F) -> HttpResponse
+where
+    F: FnOnce(WebSocket) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    if !is_upgrade(request) {
+        let mut response = error_response(426);
+        response.set_header("Connection", "Upgrade");
+        response.set_header("Upgrade", "websocket");
+        return response;
+    } else if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        let mut response = error_response(426);
+        response.set_header("Sec-WebSocket-Version", "13");
+        return response;
+    }
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if base64::decode(key.trim()).is_ok_and(|key| key.len() == 16) => key,
+        _ => return error_response(400),
+    };
+    HttpResponse::new()
+        .header("Sec-WebSocket-Accept", &accept_key(key))
+        .upgrade("websocket", move |socket, buffered| async move {
+            on_open(WebSocket::from_socket(socket, buffered)).await
+        })
+        .build()
+}
+
+#[derive(Debug, PartialEq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    masked: bool,
+    payload: Vec<u8>,
+}
+
+/// Encodes a frame. Clients mask what they send; servers don't.
+fn encode(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = vec![(fin as u8) << 7 | opcode];
+    let mask_bit = (mask.is_some() as u8) << 7;
+    match payload.len() {
+        length @ 0..=125 => frame.push(mask_bit | length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(
+                payload
+                    .iter()
+                    .enumerate()
+                    .map(|(index, byte)| byte ^ mask[index % 4]),
+            );
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
+
+/// Reads and unmasks a frame, or returns the close code for why it can't.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_size: usize) -> Result<Frame, u16> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).await.map_err(|_| ABNORMAL)?;
+    // The reserved bits are for extensions, and none are supported.
+    if head[0] & 0x70 != 0 {
+        return Err(PROTOCOL_ERROR);
+    }
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    let masked = head[1] & 0x80 != 0;
+    let length = match head[1] & 0x7F {
+        126 => reader.read_u16().await.map_err(|_| ABNORMAL)? as u64,
+        127 => reader.read_u64().await.map_err(|_| ABNORMAL)?,
+        length => length as u64,
+    };
+    // Control frames are short and can't be fragmented.
+    if opcode >= CLOSE && (!fin || length > 125) {
+        return Err(PROTOCOL_ERROR);
+    } else if length > max_size as u64 {
+        return Err(TOO_BIG);
+    }
+    let mut mask = [0; 4];
+    if masked {
+        reader.read_exact(&mut mask).await.map_err(|_| ABNORMAL)?;
+    }
+    let mut payload = vec![0; length as usize];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(|_| ABNORMAL)?;
+    for (index, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        masked,
+        payload,
+    })
+}
+
+/// Sends messages on a WebSocket; it can be cloned to send from other tasks
+/// while one waits in `recv`.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    writer: Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>,
+    /// Set once a close frame has been sent, after which nothing else may be.
+    closing: Arc<AtomicBool>,
+}
+impl WebSocketSender {
+    pub async fn send(&self, message: Message) -> std::io::Result<()> {
+        let (opcode, payload) = match message {
+            Message::Text(text) => (TEXT, text.into_bytes()),
+            Message::Binary(data) => (BINARY, data),
+            Message::Ping(data) => (PING, data),
+            Message::Pong(data) => (PONG, data),
+            Message::Close(close) => (
+                CLOSE,
+                close
+                    .map(|(code, reason)| [&code.to_be_bytes()[..], reason.as_bytes()].concat())
+                    .unwrap_or_default(),
+            ),
+        };
+        let mut writer = self.writer.lock().await;
+        if self.closing.load(Ordering::SeqCst) {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        self.closing.store(opcode == CLOSE, Ordering::SeqCst);
+        writer
+            .write_all(&encode(true, opcode, &payload, None))
+            .await?;
+        writer.flush().await
+    }
+    /// Starts the closing handshake; `recv` returns the other side's close.
+    pub async fn close(&self, code: u16, reason: &str) -> std::io::Result<()> {
+        self.send(Message::Close(Some((code, reason.to_owned()))))
+            .await
+    }
+    fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+    async fn shutdown(&self) {
+        let _ = self.writer.lock().await.shutdown().await;
+    }
+}
+
+/// A connection that has switched to the WebSocket protocol (RFC 6455).
+pub struct WebSocket {
+    reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+    sender: WebSocketSender,
+    max_message_size: usize,
+    /// The opcode and data so far of a message sent in several frames.
+    fragments: Option<(u8, Vec<u8>)>,
+    /// Set once the other side has closed, or broken, the connection.
+    closed: bool,
+}
+impl WebSocket {
+    /// Takes over a connection after the handshake, given any bytes that were
+    /// read past the request.
+    pub fn from_socket(socket: TcpStream, buffered: Vec<u8>) -> WebSocket {
+        let (reader, writer) = socket.into_split();
+        WebSocket::new(
+            Box::new(std::io::Cursor::new(buffered).chain(reader)),
+            Box::new(writer),
+        )
+    }
+    fn new(
+        reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+        writer: Box<dyn AsyncWrite + Send + Unpin>,
+    ) -> WebSocket {
+        WebSocket {
+            reader,
+            sender: WebSocketSender {
+                writer: Arc::new(Mutex::new(writer)),
+                closing: Arc::new(AtomicBool::new(false)),
+            },
+            max_message_size: MAX_MESSAGE_SIZE,
+            fragments: None,
+            closed: false,
+        }
+    }
+    pub fn max_message_size(mut self, max_message_size: usize) -> WebSocket {
+        self.max_message_size = max_message_size;
+        self
+    }
+    pub fn sender(&self) -> WebSocketSender {
+        self.sender.clone()
+    }
+    pub async fn send(&self, message: Message) -> std::io::Result<()> {
+        self.sender.send(message).await
+    }
+    pub async fn close(&self, code: u16, reason: &str) -> std::io::Result<()> {
+        self.sender.close(code, reason).await
+    }
+    /// Waits for the next message. Returns `None` once the connection is closed,
+    /// after the other side's `Message::Close` if it sent one.
+    pub async fn recv(&mut self) -> Option<Message> {
+        if self.closed {
+            return None;
+        }
+        match self.read_message().await {
+            Ok(Message::Ping(data)) => {
+                let _ = self.sender.send(Message::Pong(data.clone())).await;
+                Some(Message::Ping(data))
+            }
+            Ok(Message::Close(close)) => {
+                self.closed = true;
+                // Echo the close, unless this one answers ours.
+                if !self.sender.is_closing() {
+                    let echo = close.as_ref().map(|(code, _)| (*code, String::new()));
+                    let _ = self.sender.send(Message::Close(echo)).await;
+                }
+                self.sender.shutdown().await;
+                Some(Message::Close(close))
+            }
+            Ok(message) => Some(message),
+            Err(code) => {
+                debug!("Closing WebSocket: {}", code);
+                self.closed = true;
+                if code != ABNORMAL {
+                    let _ = self.sender.close(code, "").await;
+                }
+                self.sender.shutdown().await;
+                None
+            }
+        }
+    }
+    async fn read_message(&mut self) -> Result<Message, u16> {
+        loop {
+            let frame = read_frame(&mut self.reader, self.max_message_size).await?;
+            if !frame.masked {
+                return Err(PROTOCOL_ERROR);
+            }
+            let (opcode, data) = match (frame.opcode, &mut self.fragments) {
+                (PING, _) => return Ok(Message::Ping(frame.payload)),
+                (PONG, _) => return Ok(Message::Pong(frame.payload)),
+                (CLOSE, _) => return close_message(frame.payload),
+                (TEXT | BINARY, None) => (frame.opcode, frame.payload),
+                (CONTINUATION, Some((opcode, data))) => {
+                    if data.len() + frame.payload.len() > self.max_message_size {
+                        return Err(TOO_BIG);
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    (*opcode, std::mem::take(data))
+                }
+                _ => return Err(PROTOCOL_ERROR),
+            };
+            if !frame.fin {
+                self.fragments = Some((opcode, data));
+                continue;
+            }
+            self.fragments = None;
+            return match opcode {
+                TEXT => String::from_utf8(data)
+                    .map(Message::Text)
+                    .map_err(|_| INVALID_DATA),
+                _ => Ok(Message::Binary(data)),
+            };
+        }
+    }
+}
+
+fn close_message(payload: Vec<u8>) -> Result<Message, u16> {
+    if payload.is_empty() {
+        return Ok(Message::Close(None));
+    } else if payload.len() == 1 {
+        return Err(PROTOCOL_ERROR);
+    }
+    let code = u16::from_be_bytes([payload[0], payload[1]]);
+    // Codes that may be sent, rather than ones reserved for reporting locally.
+    if !matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999) {
+        return Err(PROTOCOL_ERROR);
+    }
+    let reason = String::from_utf8(payload[2..].to_vec()).map_err(|_| INVALID_DATA)?;
+    Ok(Message::Close(Some((code, reason))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn websocket_handshake() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let (_, request) = HttpRequest::parse(
+            "GET /chat HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: chat, superchat\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(protocols(&request), vec!["chat", "superchat"]);
+        let response = upgrade(&request, |_| async {});
+        assert_eq!(response.status.value, 101);
+        assert_eq!(
+            response.header("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        assert_eq!(response.header("Content-Length"), None);
+        assert!(response.upgrade.is_some());
+        let (_, plain) = HttpRequest::parse("GET /chat HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(upgrade(&plain, |_| async {}).status.value, 426);
+    }
+
+    #[tokio::test]
+    async fn websocket_messages() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let mut socket = WebSocket::new(Box::new(reader), Box::new(writer));
+        let mask = Some([1, 2, 3, 4]);
+        let mut frames = encode(false, TEXT, b"Hel", mask);
+        frames.extend(encode(true, PING, b"?", mask));
+        frames.extend(encode(true, CONTINUATION, "lo ✓".as_bytes(), mask));
+        frames.extend(encode(true, BINARY, &[7; 300], mask));
+        frames.extend(encode(true, CLOSE, &[0x03, 0xE8, b'b', b'y', b'e'], mask));
+        client.write_all(&frames).await.unwrap();
+        assert_eq!(socket.recv().await, Some(Message::Ping(b"?".to_vec())));
+        assert_eq!(
+            socket.recv().await,
+            Some(Message::Text("Hello ✓".to_owned()))
+        );
+        socket.send(Message::Text("hi".to_owned())).await.unwrap();
+        assert_eq!(socket.recv().await, Some(Message::Binary(vec![7; 300])));
+        assert_eq!(
+            socket.recv().await,
+            Some(Message::Close(Some((1000, "bye".to_owned()))))
+        );
+        assert_eq!(socket.recv().await, None);
+        let mut sent = vec![];
+        client.read_to_end(&mut sent).await.unwrap();
+        let mut sent = &sent[..];
+        let pong = read_frame(&mut sent, MAX_MESSAGE_SIZE).await.unwrap();
+        assert_eq!(
+            (pong.opcode, pong.masked, pong.payload),
+            (PONG, false, b"?".to_vec())
+        );
+        let text = read_frame(&mut sent, MAX_MESSAGE_SIZE).await.unwrap();
+        assert_eq!((text.opcode, text.payload), (TEXT, b"hi".to_vec()));
+        let close = read_frame(&mut sent, MAX_MESSAGE_SIZE).await.unwrap();
+        assert_eq!((close.opcode, close.payload), (CLOSE, vec![0x03, 0xE8]));
+        assert!(sent.is_empty());
+
+        // Clients have to mask their frames.
+        let (mut client, server) = tokio::io::duplex(1024);
+        let (reader, writer) = tokio::io::split(server);
+        let mut socket = WebSocket::new(Box::new(reader), Box::new(writer));
+        client
+            .write_all(&encode(true, TEXT, b"x", None))
+            .await
+            .unwrap();
+        assert_eq!(socket.recv().await, None);
+        let mut sent = vec![];
+        client.read_to_end(&mut sent).await.unwrap();
+        assert_eq!(
+            sent,
+            encode(true, CLOSE, &PROTOCOL_ERROR.to_be_bytes(), None)
+        );
+    }
+}